@@ -0,0 +1,118 @@
+//! A typed view over the [`eyre::Report`]s returned throughout the crate.
+//!
+//! Internally, failures are raised as reports carrying one of the crate's typed errors
+//! ([`ScsiError`], [`TransportError`], [`CswError`], ...), recovered with `downcast_ref`
+//! where a caller needs to act on them. [`Error`] does that classification once, for
+//! applications that want to `match` on what went wrong without depending on eyre themselves.
+
+use eyre::Report;
+
+use crate::scsi::{Cancelled, ScsiError};
+use crate::usb::TransportError;
+use crate::usb::cbw::CswError;
+
+/// An error returned by the crate, classified by the layer it came from.
+///
+/// Each variant keeps the original report, so no context is lost when displaying it.
+#[derive(Debug)]
+pub enum Error {
+    /// The host couldn't use the device, e.g it couldn't be opened or its interface claimed.
+    Usb(Report),
+    /// The device rejected a command, the report contains a [`ScsiError`] with the sense data.
+    Scsi(Report),
+    /// The bulk-only transport failed, the report contains a [`TransportError`] or a
+    /// [`CswError`]. Commands aborted through a [`crate::scsi::CancellationToken`] are also
+    /// reported here.
+    Transport(Report),
+    /// Anything else, typically a response from the device, or data read from it, that failed
+    /// validation.
+    Parse(Report),
+}
+
+impl Error {
+    /// The original report, with any context that was added to it.
+    pub fn report(&self) -> &Report {
+        match self {
+            Self::Usb(report)
+            | Self::Scsi(report)
+            | Self::Transport(report)
+            | Self::Parse(report) => report,
+        }
+    }
+
+    /// The sense data of the failed command, if the device rejected one.
+    pub fn scsi_error(&self) -> Option<&ScsiError> {
+        // Searched the same way as when classifying, see `From<Report>`
+        self.report().chain().find_map(|e| e.downcast_ref())
+    }
+
+    /// The transport failure, if the USB transfers themselves failed.
+    pub fn transport_error(&self) -> Option<&TransportError> {
+        self.report().chain().find_map(|e| e.downcast_ref())
+    }
+}
+
+impl From<Report> for Error {
+    fn from(report: Report) -> Self {
+        // Typed errors may be wrapped in context, or in an io::Error, so the whole chain is
+        // searched. Errors from the device take priority over how they were transported.
+        let found = |is: fn(&(dyn std::error::Error + 'static)) -> bool| report.chain().any(is);
+        if found(|e| e.is::<ScsiError>()) {
+            Self::Scsi(report)
+        } else if found(|e| e.is::<TransportError>() || e.is::<CswError>() || e.is::<Cancelled>()) {
+            Self::Transport(report)
+        } else if found(|e| e.is::<nusb::Error>() || e.is::<nusb::transfer::TransferError>()) {
+            Self::Usb(report)
+        } else {
+            Self::Parse(report)
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // The alternate form includes the context chain, as with eyre
+        write!(f, "{:#}", self.report())
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.report().source()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use eyre::eyre;
+
+    use crate::error::Error;
+    use crate::scsi::ScsiError;
+    use crate::scsi::response::SenseKey;
+    use crate::usb::TransportError;
+
+    #[test]
+    fn reports_are_classified_through_context() {
+        let scsi = ScsiError::CheckCondition {
+            sense_key: SenseKey::MediumError,
+            asc: 0x11,
+            ascq: 0x00,
+        };
+        let error = Error::from(eyre!(scsi).wrap_err("read failed"));
+        assert!(matches!(error, Error::Scsi(_)));
+        assert_eq!(error.scsi_error(), Some(&scsi));
+
+        let error = Error::from(eyre!(TransportError::Timeout));
+        assert!(matches!(error, Error::Transport(_)));
+        assert_eq!(error.transport_error(), Some(&TransportError::Timeout));
+
+        // As returned by `ScsiBlockReader`, through `std::io::Read`
+        let read_error = eyre!(scsi).wrap_err("attempting to issue READ (10)");
+        let error = Error::from(eyre!(std::io::Error::other(read_error)));
+        assert!(matches!(error, Error::Scsi(_)));
+        assert_eq!(error.scsi_error(), Some(&scsi));
+
+        let error = Error::from(eyre!("INQUIRY data is truncated"));
+        assert!(matches!(error, Error::Parse(_)));
+    }
+}
@@ -0,0 +1,134 @@
+//! Filesystem detection from the boot sector (first sector) of a partition.
+
+use std::fmt;
+
+/// A filesystem recognized by [`detect_filesystem`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FsKind {
+    Fat12,
+    Fat16,
+    Fat32,
+    ExFat,
+    Ntfs,
+}
+
+impl fmt::Display for FsKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Fat12 => "FAT12",
+            Self::Fat16 => "FAT16",
+            Self::Fat32 => "FAT32",
+            Self::ExFat => "exFAT",
+            Self::Ntfs => "NTFS",
+        })
+    }
+}
+
+/// Identifies the filesystem in a partition from its first sector, returning `None`
+/// if it isn't recognized.
+pub fn detect_filesystem(boot_sector: &[u8]) -> Option<FsKind> {
+    if boot_sector.len() < 512 {
+        return None;
+    }
+    // exFAT and NTFS both have an OEM name that identifies them
+    match &boot_sector[3..11] {
+        b"EXFAT   " => return Some(FsKind::ExFat),
+        b"NTFS    " => return Some(FsKind::Ntfs),
+        _ => {}
+    }
+    detect_fat(boot_sector)
+}
+
+/// FAT has no magic value, so the BIOS Parameter Block is sanity checked instead, and the
+/// FAT type is determined from the count of clusters, as described in the Microsoft
+/// "FAT: General Overview of On-Disk Format" specification.
+fn detect_fat(boot_sector: &[u8]) -> Option<FsKind> {
+    let le_u16 = |offset: usize| u16::from_le_bytes([boot_sector[offset], boot_sector[offset + 1]]);
+    let le_u32 =
+        |offset: usize| u32::from_le_bytes(boot_sector[offset..offset + 4].try_into().unwrap());
+
+    let jump_is_valid =
+        boot_sector[0] == 0xE9 || (boot_sector[0] == 0xEB && boot_sector[2] == 0x90);
+    let bytes_per_sector = u32::from(le_u16(11));
+    let sectors_per_cluster = u32::from(boot_sector[13]);
+    let reserved_sectors = u32::from(le_u16(14));
+    let fat_count = u32::from(boot_sector[16]);
+    if !jump_is_valid
+        || boot_sector[510..512] != [0x55, 0xAA]
+        || !(512..=4096).contains(&bytes_per_sector)
+        || !bytes_per_sector.is_power_of_two()
+        || !sectors_per_cluster.is_power_of_two()
+        || reserved_sectors == 0
+        || fat_count == 0
+    {
+        return None;
+    }
+
+    let root_entry_count = u32::from(le_u16(17));
+    let root_dir_sectors = (root_entry_count * 32).div_ceil(bytes_per_sector);
+    let fat_size = match le_u16(22) {
+        0 => le_u32(36),
+        size => u32::from(size),
+    };
+    let total_sectors = match le_u16(19) {
+        0 => le_u32(32),
+        count => u32::from(count),
+    };
+    let data_sectors = total_sectors.checked_sub(
+        reserved_sectors
+            .checked_add(fat_count.checked_mul(fat_size)?)?
+            .checked_add(root_dir_sectors)?,
+    )?;
+
+    Some(match data_sectors / sectors_per_cluster {
+        ..4085 => FsKind::Fat12,
+        4085..65525 => FsKind::Fat16,
+        _ => FsKind::Fat32,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::format::filesystem::{FsKind, detect_filesystem};
+
+    /// A boot sector with a minimal BPB, as written by `mkfs.fat` for a 32GB partition
+    fn fat32_boot_sector() -> [u8; 512] {
+        let mut sector = [0u8; 512];
+        sector[0..3].copy_from_slice(&[0xEB, 0x58, 0x90]);
+        sector[3..11].copy_from_slice(b"mkfs.fat");
+        sector[11..13].copy_from_slice(&512_u16.to_le_bytes());
+        sector[13] = 64;
+        sector[14..16].copy_from_slice(&32_u16.to_le_bytes());
+        sector[16] = 2;
+        sector[32..36].copy_from_slice(&62_521_344_u32.to_le_bytes());
+        sector[36..40].copy_from_slice(&7_628_u32.to_le_bytes());
+        sector[510..].copy_from_slice(&[0x55, 0xAA]);
+        sector
+    }
+
+    #[test]
+    fn detect_fat32() {
+        assert_eq!(detect_filesystem(&fat32_boot_sector()), Some(FsKind::Fat32));
+    }
+
+    #[test]
+    fn detect_fat16() {
+        let mut sector = fat32_boot_sector();
+        // 256MiB with 4KiB clusters and a 512 entry root directory
+        sector[17..19].copy_from_slice(&512_u16.to_le_bytes());
+        sector[13] = 8;
+        sector[22..24].copy_from_slice(&256_u16.to_le_bytes());
+        sector[32..36].copy_from_slice(&524_288_u32.to_le_bytes());
+        assert_eq!(detect_filesystem(&sector), Some(FsKind::Fat16));
+    }
+
+    #[test]
+    fn detect_by_oem_name() {
+        let mut sector = [0u8; 512];
+        sector[3..11].copy_from_slice(b"NTFS    ");
+        assert_eq!(detect_filesystem(&sector), Some(FsKind::Ntfs));
+        sector[3..11].copy_from_slice(b"EXFAT   ");
+        assert_eq!(detect_filesystem(&sector), Some(FsKind::ExFat));
+        assert_eq!(detect_filesystem(&[0u8; 512]), None);
+    }
+}
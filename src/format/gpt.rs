@@ -0,0 +1,349 @@
+//! GUID Partition Table parsing.
+//!
+//! The primary GPT header lives at LBA 1, and points at an array of partition entries
+//! (normally starting at LBA 2). Both are protected by a CRC32.
+//! See UEFI 2.10 section 5.3, "GUID Partition Table (GPT) Disk Layout".
+
+use std::fmt;
+
+use eyre::{Result, ensure};
+
+/// The signature found in the first 8 bytes of a GPT header
+const SIGNATURE: &[u8; 8] = b"EFI PART";
+/// The smallest valid GPT header, in *bytes*
+const MIN_HEADER_SIZE: usize = 92;
+/// The smallest valid partition entry, in *bytes*
+const MIN_ENTRY_SIZE: usize = 128;
+/// The offset of the header CRC32 within the header
+const HEADER_CRC_OFFSET: usize = 16;
+/// The largest partition entry array accepted, in *bytes*. The usual array is 16KiB, anything
+/// much larger is a corrupt header rather than a real table.
+const MAX_ENTRY_ARRAY_LEN: u64 = 1024 * 1024;
+
+/// A GUID as stored on disk, where the first three fields are little endian
+/// and the last two are big endian.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Default)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    /// The all zero GUID, used as the type of unused partition entries
+    pub const NIL: Self = Self([0; 16]);
+    /// `C12A7328-F81F-11D2-BA4B-00A0C93EC93B`
+    pub const EFI_SYSTEM_PARTITION: Self = Self([
+        0x28, 0x73, 0x2A, 0xC1, 0x1F, 0xF8, 0xD2, 0x11, 0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E, 0xC9,
+        0x3B,
+    ]);
+    /// `EBD0A0A2-B9E5-4433-87C0-68B6B72699C7`, used for FAT, exFAT and NTFS
+    pub const BASIC_DATA_PARTITION: Self = Self([
+        0xA2, 0xA0, 0xD0, 0xEB, 0xE5, 0xB9, 0x33, 0x44, 0x87, 0xC0, 0x68, 0xB6, 0xB7, 0x26, 0x99,
+        0xC7,
+    ]);
+
+    fn from_slice(bytes: &[u8]) -> Self {
+        Self(bytes[..16].try_into().unwrap())
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let b = &self.0;
+        write!(
+            f,
+            "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-",
+            u32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+            u16::from_le_bytes([b[4], b[5]]),
+            u16::from_le_bytes([b[6], b[7]]),
+            b[8],
+            b[9]
+        )?;
+        b[10..].iter().try_for_each(|byte| write!(f, "{byte:02X}"))
+    }
+}
+
+/// The fields of a GPT header needed to locate and validate the partition entries.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct GptHeader {
+    /// Identifies the disk
+    pub disk_guid: Guid,
+    /// The LBA of the backup header, normally the last block of the device
+    pub backup_lba: u64,
+    /// The first block that may be used by a partition
+    pub first_usable_lba: u64,
+    /// The last block that may be used by a partition
+    pub last_usable_lba: u64,
+    /// The first block of the partition entry array
+    pub partition_entry_lba: u64,
+    /// The number of entries in the partition entry array
+    pub partition_entry_count: u32,
+    /// The size of each partition entry in *bytes*
+    pub partition_entry_size: u32,
+    /// The CRC32 of the whole partition entry array
+    pub partition_entry_array_crc32: u32,
+}
+
+impl GptHeader {
+    /// The size of the partition entry array in *bytes*.
+    pub fn partition_entry_array_len(&self) -> usize {
+        self.partition_entry_count as usize * self.partition_entry_size as usize
+    }
+}
+
+/// A used entry of the partition entry array.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GptPartition {
+    /// The position of the entry in the partition entry array, starting at 1
+    pub number: u32,
+    /// What the partition contains, e.g [`Guid::BASIC_DATA_PARTITION`]
+    pub type_guid: Guid,
+    /// Identifies this specific partition
+    pub unique_guid: Guid,
+    /// The address of the first block of the partition
+    pub first_lba: u64,
+    /// The address of the last block of the partition (inclusive)
+    pub last_lba: u64,
+    /// Attribute flags, see UEFI 2.10 table 5.8
+    pub attributes: u64,
+    /// The human readable name of the partition
+    pub name: String,
+}
+
+impl GptPartition {
+    /// The length of the partition in *blocks*, `None` if the entry ends before it starts or
+    /// spans more blocks than a `u64` can count.
+    pub fn block_count(&self) -> Option<u64> {
+        self.last_lba.checked_sub(self.first_lba)?.checked_add(1)
+    }
+}
+
+/// A parsed GUID partition table.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GptTable {
+    pub header: GptHeader,
+    /// The partitions in use, unused entries are skipped
+    pub partitions: Vec<GptPartition>,
+}
+
+/// Parses and validates a GPT header, which is needed to know where the
+/// partition entry array is and how large it is.
+pub fn parse_gpt_header(header_lba1: &[u8]) -> Result<GptHeader> {
+    ensure!(
+        header_lba1.len() >= MIN_HEADER_SIZE,
+        "GPT header should be at least {MIN_HEADER_SIZE} bytes, was {}",
+        header_lba1.len()
+    );
+    ensure!(
+        &header_lba1[0..8] == SIGNATURE,
+        "invalid GPT signature: {:02X?}",
+        &header_lba1[0..8]
+    );
+    let header_size = le_u32(header_lba1, 12) as usize;
+    ensure!(
+        (MIN_HEADER_SIZE..=header_lba1.len()).contains(&header_size),
+        "invalid GPT header size: {header_size}"
+    );
+    // The CRC is calculated with the CRC field itself zeroed
+    let mut header = header_lba1[..header_size].to_vec();
+    header[HEADER_CRC_OFFSET..HEADER_CRC_OFFSET + 4].fill(0);
+    let expected_crc = le_u32(header_lba1, HEADER_CRC_OFFSET);
+    let actual_crc = crc32(&header);
+    ensure!(
+        expected_crc == actual_crc,
+        "GPT header CRC mismatch, header says 0x{expected_crc:08X}, calculated 0x{actual_crc:08X}"
+    );
+
+    let header = GptHeader {
+        backup_lba: le_u64(header_lba1, 32),
+        first_usable_lba: le_u64(header_lba1, 40),
+        last_usable_lba: le_u64(header_lba1, 48),
+        disk_guid: Guid::from_slice(&header_lba1[56..72]),
+        partition_entry_lba: le_u64(header_lba1, 72),
+        partition_entry_count: le_u32(header_lba1, 80),
+        partition_entry_size: le_u32(header_lba1, 84),
+        partition_entry_array_crc32: le_u32(header_lba1, 88),
+    };
+    ensure!(
+        header.partition_entry_size as usize >= MIN_ENTRY_SIZE
+            && header.partition_entry_size.is_power_of_two(),
+        "invalid GPT partition entry size: {}",
+        header.partition_entry_size
+    );
+    let array_len =
+        u64::from(header.partition_entry_count) * u64::from(header.partition_entry_size);
+    ensure!(
+        array_len <= MAX_ENTRY_ARRAY_LEN,
+        "GPT partition entry array is implausibly large, {} entries of {} bytes",
+        header.partition_entry_count,
+        header.partition_entry_size
+    );
+
+    Ok(header)
+}
+
+/// Parses a GUID partition table from the already read header (LBA 1) and the
+/// partition entry array it points to.
+///
+/// `entries` must contain at least [`GptHeader::partition_entry_array_len`] bytes, use
+/// [`parse_gpt_header`] to find out how much to read.
+pub fn parse_gpt(header_lba1: &[u8], entries: &[u8]) -> Result<GptTable> {
+    let header = parse_gpt_header(header_lba1)?;
+    let array_len = header.partition_entry_array_len();
+    ensure!(
+        entries.len() >= array_len,
+        "GPT partition entry array should be {array_len} bytes, was {}",
+        entries.len()
+    );
+    let entries = &entries[..array_len];
+    let actual_crc = crc32(entries);
+    ensure!(
+        header.partition_entry_array_crc32 == actual_crc,
+        "GPT partition entry array CRC mismatch, header says 0x{:08X}, calculated 0x{actual_crc:08X}",
+        header.partition_entry_array_crc32
+    );
+
+    let partitions = entries
+        .chunks_exact(header.partition_entry_size as usize)
+        .zip(1..)
+        .filter(|(entry, _)| Guid::from_slice(&entry[0..16]) != Guid::NIL)
+        .map(|(entry, number)| {
+            let name: Vec<u16> = entry[56..128]
+                .chunks_exact(2)
+                .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+                .take_while(|&unit| unit != 0)
+                .collect();
+            let (first_lba, last_lba) = (le_u64(entry, 32), le_u64(entry, 40));
+            ensure!(
+                first_lba <= last_lba,
+                "GPT partition {number} ends at LBA {last_lba}, before it starts at LBA {first_lba}"
+            );
+            Ok(GptPartition {
+                number,
+                type_guid: Guid::from_slice(&entry[0..16]),
+                unique_guid: Guid::from_slice(&entry[16..32]),
+                first_lba,
+                last_lba,
+                attributes: le_u64(entry, 48),
+                name: String::from_utf16_lossy(&name),
+            })
+        })
+        .collect::<Result<_>>()?;
+
+    Ok(GptTable { header, partitions })
+}
+
+fn le_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn le_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+/// The CRC32 used by GPT (the same one as zlib/Ethernet, reflected polynomial `0xEDB88320`).
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0_u32, |crc, &byte| {
+        (0..8).fold(crc ^ u32::from(byte), |crc, _| {
+            (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg())
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::format::gpt::{GptPartition, Guid, crc32, parse_gpt, parse_gpt_header};
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn guid_display() {
+        assert_eq!(
+            Guid::EFI_SYSTEM_PARTITION.to_string(),
+            "C12A7328-F81F-11D2-BA4B-00A0C93EC93B"
+        );
+    }
+
+    /// Builds a valid GPT header for a 4MiB device, pointing at `entries`.
+    fn header_for(entries: &[u8]) -> Vec<u8> {
+        let mut header = vec![0u8; 512];
+        header[0..8].copy_from_slice(b"EFI PART");
+        header[8..12].copy_from_slice(&0x0001_0000_u32.to_le_bytes());
+        header[12..16].copy_from_slice(&92_u32.to_le_bytes());
+        header[24..32].copy_from_slice(&1_u64.to_le_bytes());
+        header[32..40].copy_from_slice(&8191_u64.to_le_bytes());
+        header[40..48].copy_from_slice(&34_u64.to_le_bytes());
+        header[48..56].copy_from_slice(&8158_u64.to_le_bytes());
+        header[72..80].copy_from_slice(&2_u64.to_le_bytes());
+        header[80..84].copy_from_slice(&4_u32.to_le_bytes());
+        header[84..88].copy_from_slice(&128_u32.to_le_bytes());
+        header[88..92].copy_from_slice(&crc32(entries).to_le_bytes());
+        let header_crc = crc32(&header[..92]);
+        header[16..20].copy_from_slice(&header_crc.to_le_bytes());
+        header
+    }
+
+    #[test]
+    fn decode_partition_table() {
+        let mut entries = vec![0u8; 4 * 128];
+        let entry = &mut entries[128..256];
+        entry[0..16].copy_from_slice(&Guid::BASIC_DATA_PARTITION.0);
+        entry[16..32].fill(0x11);
+        entry[32..40].copy_from_slice(&2048_u64.to_le_bytes());
+        entry[40..48].copy_from_slice(&4095_u64.to_le_bytes());
+        for (unit, c) in entry[56..].chunks_exact_mut(2).zip("USB".encode_utf16()) {
+            unit.copy_from_slice(&c.to_le_bytes());
+        }
+        let mut header = header_for(&entries);
+
+        let table = parse_gpt(&header, &entries).unwrap();
+        assert_eq!(table.header.partition_entry_lba, 2);
+        assert_eq!(table.partitions.len(), 1);
+        let partition = &table.partitions[0];
+        assert_eq!(partition.number, 2);
+        assert_eq!(partition.type_guid, Guid::BASIC_DATA_PARTITION);
+        assert_eq!(partition.block_count(), Some(2048));
+        assert_eq!(partition.name, "USB");
+
+        // Any corruption of the header should be caught
+        header[40] ^= 1;
+        assert!(parse_gpt(&header, &entries).is_err());
+    }
+
+    #[test]
+    fn oversized_entry_arrays_are_rejected() {
+        let entries = vec![0u8; 4 * 128];
+        let mut header = header_for(&entries);
+        // 2^32 - 1 entries of 128 bytes, which would be 512GiB to read
+        header[80..84].copy_from_slice(&u32::MAX.to_le_bytes());
+        header[16..20].fill(0);
+        let header_crc = crc32(&header[..92]);
+        header[16..20].copy_from_slice(&header_crc.to_le_bytes());
+        assert!(parse_gpt_header(&header).is_err());
+    }
+
+    #[test]
+    fn whole_disk_partitions_do_not_overflow() {
+        let partition = GptPartition {
+            number: 1,
+            type_guid: Guid::BASIC_DATA_PARTITION,
+            unique_guid: Guid::NIL,
+            first_lba: 0,
+            last_lba: u64::MAX,
+            attributes: 0,
+            name: String::new(),
+        };
+        assert_eq!(partition.block_count(), None);
+    }
+
+    #[test]
+    fn backwards_partitions_are_rejected() {
+        let mut entries = vec![0u8; 4 * 128];
+        let entry = &mut entries[..128];
+        entry[0..16].copy_from_slice(&Guid::BASIC_DATA_PARTITION.0);
+        entry[32..40].copy_from_slice(&4095_u64.to_le_bytes());
+        entry[40..48].copy_from_slice(&2048_u64.to_le_bytes());
+        let header = header_for(&entries);
+        assert!(parse_gpt(&header, &entries).is_err());
+    }
+}
@@ -0,0 +1,110 @@
+//! Master Boot Record partition table parsing.
+//!
+//! The MBR occupies the first 512 bytes of the device (LBA 0), and describes up to four
+//! primary partitions. See <https://en.wikipedia.org/wiki/Master_boot_record>.
+
+use eyre::{Result, ensure};
+
+/// The size of the MBR in *bytes*
+pub const MBR_SIZE: usize = 512;
+/// The offset of the partition table within the MBR
+const PARTITION_TABLE_OFFSET: usize = 446;
+/// The size of each partition table entry in *bytes*
+const PARTITION_ENTRY_SIZE: usize = 16;
+/// The boot signature found in the last two bytes of a valid MBR
+const BOOT_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+/// The partition type of the single partition in a protective MBR, which indicates
+/// that the device is actually partitioned with GPT
+pub const PROTECTIVE_PARTITION_TYPE: u8 = 0xEE;
+
+/// A primary partition described by an MBR partition table entry.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct MbrPartition {
+    /// The position of the entry in the partition table, from 1 to 4
+    pub number: u8,
+    /// Set if the partition is marked as active (bootable)
+    pub bootable: bool,
+    /// The partition type, e.g `0x0C` for FAT32 with LBA addressing, or `0x07` for NTFS/exFAT
+    pub partition_type: u8,
+    /// The address of the first block of the partition
+    pub start_lba: u32,
+    /// The length of the partition in *blocks*
+    pub sector_count: u32,
+}
+
+impl MbrPartition {
+    /// Returns true if this is the protective partition of a GPT partitioned device.
+    pub fn is_protective(&self) -> bool {
+        self.partition_type == PROTECTIVE_PARTITION_TYPE
+    }
+}
+
+/// Parses the partition table out of the first sector of a device.
+///
+/// Only entries that describe a partition are returned, unused entries are skipped.
+pub fn parse_mbr(sector0: &[u8]) -> Result<Vec<MbrPartition>> {
+    ensure!(
+        sector0.len() >= MBR_SIZE,
+        "MBR should be at least {MBR_SIZE} bytes, was {}",
+        sector0.len()
+    );
+    ensure!(
+        sector0[510..512] == BOOT_SIGNATURE,
+        "invalid MBR boot signature, should be 0x55AA, is 0x{:02X}{:02X}",
+        sector0[510],
+        sector0[511]
+    );
+    let entries =
+        &sector0[PARTITION_TABLE_OFFSET..PARTITION_TABLE_OFFSET + 4 * PARTITION_ENTRY_SIZE];
+    let partitions = entries
+        .chunks_exact(PARTITION_ENTRY_SIZE)
+        .zip(1..)
+        .map(|(entry, number)| MbrPartition {
+            number,
+            bootable: entry[0] & 0x80 != 0,
+            // Bytes 1-3 and 5-7 are CHS addresses, which are superseded by the LBA fields
+            partition_type: entry[4],
+            start_lba: u32::from_le_bytes([entry[8], entry[9], entry[10], entry[11]]),
+            sector_count: u32::from_le_bytes([entry[12], entry[13], entry[14], entry[15]]),
+        })
+        .filter(|partition| partition.partition_type != 0 && partition.sector_count != 0)
+        .collect();
+
+    Ok(partitions)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::format::mbr::{MbrPartition, parse_mbr};
+
+    #[test]
+    fn decode_partition_table() {
+        let mut sector = [0u8; 512];
+        // A bootable FAT32 partition at 1MiB, in the second entry
+        let entry = &mut sector[462..478];
+        entry[0] = 0x80;
+        entry[4] = 0x0C;
+        entry[8..12].copy_from_slice(&2048_u32.to_le_bytes());
+        entry[12..16].copy_from_slice(&60_000_000_u32.to_le_bytes());
+        sector[510..].copy_from_slice(&[0x55, 0xAA]);
+        assert_eq!(
+            parse_mbr(&sector).unwrap(),
+            [MbrPartition {
+                number: 2,
+                bootable: true,
+                partition_type: 0x0C,
+                start_lba: 2048,
+                sector_count: 60_000_000,
+            }]
+        );
+    }
+
+    #[test]
+    fn catch_invalid_signature() {
+        let sector = [0u8; 512];
+        assert!(
+            parse_mbr(&sector).is_err(),
+            "should catch a missing boot signature"
+        );
+    }
+}
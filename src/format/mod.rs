@@ -0,0 +1,9 @@
+//! Parsers for on-disk structures found on storage devices, like partition tables
+//! and filesystem boot sectors.
+//!
+//! Everything here operates on sectors that have already been read from the device, so it can
+//! be used (and tested) independently of the USB and SCSI layers.
+
+pub mod filesystem;
+pub mod gpt;
+pub mod mbr;
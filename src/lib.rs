@@ -0,0 +1,28 @@
+//! A userspace USB mass storage driver, talking SCSI over the USB Bulk-Only Transport.
+//!
+//! Devices are found with [`enumerate_usb_storage_devices`], opened as a [`USBDrive`], and
+//! then initialized as a [`SCSIDevice`], which commands are issued through:
+//!
+//! ```no_run
+//! # async fn example() -> eyre::Result<()> {
+//! use eyre::OptionExt;
+//! use floatglass::{SCSIDevice, USBDrive, enumerate_usb_storage_devices};
+//!
+//! let device = enumerate_usb_storage_devices()
+//!     .await?
+//!     .next()
+//!     .ok_or_eyre("no USB storage device is connected")?;
+//! let mut device = SCSIDevice::new(USBDrive::new(&device).await?, 0).await?;
+//! let first_block = device.read_blocks(0, 1).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod error;
+pub mod format;
+pub mod scsi;
+pub mod usb;
+
+pub use error::Error;
+pub use scsi::{Capacity, SCSIDevice, ScsiError};
+pub use usb::{USBDrive, UsbTransport, enumerate_usb_storage_devices};
@@ -1,45 +1,387 @@
-pub mod scsi;
-pub mod usb;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
 
-use crate::scsi::command;
-use color_eyre::{Result, eyre::ContextCompat};
-use std::fmt::Write;
+use clap::{Arg, ArgAction, ArgMatches, Command, value_parser};
+use color_eyre::{
+    Result,
+    eyre::{ContextCompat, WrapErr, ensure},
+};
+use floatglass::{scsi, usb, usb::enumerate_usb_storage_devices};
+use nusb::DeviceInfo;
 use tracing::{info, level_filters::LevelFilter};
-use usb::enumerate_usb_storage_devices;
+use tracing_subscriber::{filter::Targets, prelude::*};
+
+fn cli() -> Command {
+    Command::new("floatglass")
+        .about("Talks to USB mass storage devices from userspace")
+        .subcommand_required(true)
+        .subcommand(
+            Command::new("info")
+                .about(
+                    "Lists connected USB storage devices, or describes the one matching the \
+                     selector",
+                )
+                .args(device_selector())
+                .arg(
+                    Arg::new("json")
+                        .long("json")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with_all(["vid", "pid", "serial"])
+                        .help(
+                            "List the devices as a JSON array of {vid, pid, serial, bus, \
+                             address, vendor, product} objects",
+                        ),
+                ),
+        )
+        .subcommand(
+            Command::new("read")
+                .about("Reads a range of blocks from the selected device into a file")
+                .args(device_selector())
+                .arg(
+                    Arg::new("start")
+                        .long("start")
+                        .value_name("LBA")
+                        .value_parser(value_parser!(u64))
+                        .default_value("0")
+                        .help("Logical block address of the first block to read"),
+                )
+                .arg(
+                    Arg::new("count")
+                        .long("count")
+                        .value_name("N")
+                        .value_parser(value_parser!(u64))
+                        .required(true)
+                        .help("Number of blocks to read"),
+                )
+                .arg(
+                    Arg::new("out")
+                        .long("out")
+                        .value_name("FILE")
+                        .value_parser(value_parser!(PathBuf))
+                        .required(true)
+                        .help("File to write the blocks to, or - for stdout"),
+                ),
+        )
+        .subcommand(
+            Command::new("write")
+                .about("Writes an image to the selected device, overwriting its contents")
+                .args(device_selector())
+                .arg(
+                    Arg::new("in")
+                        .long("in")
+                        .value_name("IMAGE")
+                        .value_parser(value_parser!(PathBuf))
+                        .required(true)
+                        .help("Image to write, starting at the first block of the device"),
+                )
+                .arg(
+                    Arg::new("yes")
+                        .long("yes")
+                        .action(ArgAction::SetTrue)
+                        .help("Confirm that the contents of the device should be overwritten"),
+                ),
+        )
+}
+
+/// Arguments choosing a single device out of those connected, see [`select_device`].
+fn device_selector() -> [Arg; 3] {
+    [
+        Arg::new("vid")
+            .long("vid")
+            .value_name("VID")
+            .value_parser(parse_hex_id)
+            .help("Vendor ID of the device, in hex"),
+        Arg::new("pid")
+            .long("pid")
+            .value_name("PID")
+            .value_parser(parse_hex_id)
+            .help("Product ID of the device, in hex"),
+        Arg::new("serial")
+            .long("serial")
+            .value_name("SERIAL")
+            .help("Serial number of the device"),
+    ]
+}
+
+/// Parses a USB vendor or product ID, written in hex like `lsusb` prints them (`0951`), with or
+/// without a `0x` prefix.
+fn parse_hex_id(id: &str) -> Result<u16, String> {
+    let digits = id.strip_prefix("0x").unwrap_or(id);
+    u16::from_str_radix(digits, 16).map_err(|e| format!("invalid hex ID {id:?}: {e}"))
+}
+
+/// Returns true if any selector arguments were given.
+fn has_selector(matches: &ArgMatches) -> bool {
+    ["vid", "pid", "serial"]
+        .iter()
+        .any(|id| matches.contains_id(id))
+}
+
+/// Returns the first connected storage device matching every given selector argument.
+async fn select_device(matches: &ArgMatches) -> Result<DeviceInfo> {
+    let vid = matches.get_one::<u16>("vid").copied();
+    let pid = matches.get_one::<u16>("pid").copied();
+    let serial = matches.get_one::<String>("serial");
+    enumerate_usb_storage_devices()
+        .await?
+        .find(|dev| {
+            vid.is_none_or(|vid| dev.vendor_id() == vid)
+                && pid.is_none_or(|pid| dev.product_id() == pid)
+                && serial.is_none_or(|serial| dev.serial_number() == Some(serial.as_str()))
+        })
+        .wrap_err("no connected USB storage device matches the selector")
+}
+
+/// Opens and initializes logical unit 0 of the selected device.
+async fn open_device(matches: &ArgMatches) -> Result<scsi::SCSIDevice> {
+    let device = select_device(matches).await?;
+    let drive = usb::USBDrive::new(&device).await?;
+    let scsi_device = scsi::SCSIDevice::new(drive, 0).await?;
+    info!("opened {scsi_device}");
+    Ok(scsi_device)
+}
+
+/// Releases a device opened with [`open_device`] once a command is done with it, whether or not
+/// the command succeeded, so that the medium isn't left locked. An error from the command takes
+/// priority over one from closing the device.
+async fn close_device<T>(device: scsi::SCSIDevice, result: Result<T>) -> Result<T> {
+    let closed = device.close().await;
+    let value = result?;
+    closed?;
+    Ok(value)
+}
+
+/// `info`: without a selector, lists every storage device. Otherwise describes the selected one.
+async fn info_command(matches: &ArgMatches) -> Result<()> {
+    if matches.get_flag("json") {
+        let devices: Vec<_> = enumerate_usb_storage_devices()
+            .await?
+            .map(|dev| device_json(&dev))
+            .collect();
+        println!("[{}]", devices.join(","));
+        return Ok(());
+    }
+    if !has_selector(matches) {
+        for dev in enumerate_usb_storage_devices().await? {
+            println!(
+                "bus {} device {}: {:04x}:{:04x} {} {} (serial {})",
+                dev.bus_id(),
+                dev.device_address(),
+                dev.vendor_id(),
+                dev.product_id(),
+                dev.manufacturer_string().unwrap_or("unknown"),
+                dev.product_string().unwrap_or("unknown"),
+                dev.serial_number().unwrap_or("unknown"),
+            );
+        }
+        return Ok(());
+    }
+
+    let mut device = open_device(matches).await?;
+    let result = async {
+        let inquiry = device.inquiry().await?;
+        let capacity = device.capacity().await?;
+        println!("vendor:     {}", inquiry.vendor());
+        println!("product:    {}", inquiry.product());
+        println!("revision:   {}", inquiry.revision());
+        println!("capacity:   {}", capacity.describe());
+        println!("block size: {} bytes", capacity.block_size);
+        println!(
+            "removable:  {}",
+            if inquiry.is_removable() { "yes" } else { "no" }
+        );
+        Ok(())
+    }
+    .await;
+    close_device(device, result).await
+}
+
+/// Describes a device as a JSON object for `info --json`. IDs are hex strings, as accepted by
+/// `--vid` and `--pid`, and strings the device doesn't report are `null`.
+fn device_json(dev: &DeviceInfo) -> String {
+    let string = |s: Option<&str>| s.map_or_else(|| "null".to_string(), json_string);
+    format!(
+        r#"{{"vid":"{:04x}","pid":"{:04x}","serial":{},"bus":{},"address":{},"vendor":{},"product":{}}}"#,
+        dev.vendor_id(),
+        dev.product_id(),
+        string(dev.serial_number()),
+        json_string(dev.bus_id()),
+        dev.device_address(),
+        string(dev.manufacturer_string()),
+        string(dev.product_string()),
+    )
+}
+
+/// Quotes `s` as a JSON string, escaping it as described in RFC 8259 section 7.
+fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// The number of blocks `read` requests from the device at a time, to bound memory use.
+const READ_CHUNK_BLOCKS: u64 = 2048;
+
+/// `read`: copies `--count` blocks starting at `--start` into `--out`.
+async fn read_command(matches: &ArgMatches) -> Result<()> {
+    let start = *matches.get_one::<u64>("start").unwrap();
+    let count = *matches.get_one::<u64>("count").unwrap();
+    let end = start
+        .checked_add(count)
+        .wrap_err("--start and --count overflow")?;
+
+    let mut device = open_device(matches).await?;
+    let result = async {
+        // Only created once the device is open, so a mistyped selector doesn't truncate the file
+        let out = matches.get_one::<PathBuf>("out").unwrap();
+        let mut out: Box<dyn Write> = if out.as_os_str() == "-" {
+            Box::new(std::io::stdout().lock())
+        } else {
+            let file = File::create(out)
+                .wrap_err_with(|| format!("failed to create {}", out.display()))?;
+            Box::new(BufWriter::new(file))
+        };
+        let mut lba = start;
+        while lba < end {
+            let len = (end - lba).min(READ_CHUNK_BLOCKS);
+            let data = device.read_blocks(lba, len).await?;
+            out.write_all(&data)
+                .wrap_err("failed to write the output")?;
+            lba += len;
+        }
+        out.flush().wrap_err("failed to write the output")
+    }
+    .await;
+    close_device(device, result).await?;
+    info!("read {count} blocks starting at LBA {start}");
+    Ok(())
+}
+
+/// `write`: streams `--in` onto the device, once the target has been confirmed with `--yes`.
+async fn write_command(matches: &ArgMatches) -> Result<()> {
+    // Unlike reads, writes never fall back to whichever device happens to be first
+    ensure!(
+        has_selector(matches),
+        "select the device to overwrite with --vid, --pid or --serial"
+    );
+    let path = matches.get_one::<PathBuf>("in").unwrap();
+    let image = tokio::fs::File::open(path)
+        .await
+        .wrap_err_with(|| format!("failed to open {}", path.display()))?;
+    let image_len = image.metadata().await?.len();
+
+    let mut device = open_device(matches).await?;
+    let result = async {
+        let inquiry = device.inquiry().await?;
+        let capacity = device.capacity().await?;
+        // The target is always shown, so that the wrong device can be caught before confirming
+        println!(
+            "target: {} {}, {}",
+            inquiry.vendor(),
+            inquiry.product(),
+            capacity.describe()
+        );
+        println!(
+            "image:  {}, {}",
+            path.display(),
+            scsi::human_size(image_len)
+        );
+        ensure!(
+            image_len <= capacity.total_bytes,
+            "the image is larger than the device"
+        );
+        ensure!(
+            matches.get_flag("yes"),
+            "refusing to overwrite the device without --yes"
+        );
+
+        device
+            .write_image(image, |written| draw_progress(written, image_len))
+            .await?;
+        eprintln!();
+        Ok(())
+    }
+    .await;
+    close_device(device, result).await?;
+    info!("wrote {} to the device", scsi::human_size(image_len));
+    Ok(())
+}
+
+/// The width of the progress bar drawn by [`draw_progress`], in characters
+const PROGRESS_BAR_WIDTH: u64 = 40;
+
+/// Redraws a progress bar on the current line of stderr, e.g
+/// `[################........................]  40% 1.1 GiB / 2.8 GiB`.
+fn draw_progress(done: u64, total: u64) {
+    // The last block may be padded past the end of the image
+    let done = done.min(total);
+    let filled = (done * PROGRESS_BAR_WIDTH).checked_div(total).unwrap_or(0);
+    let percent = (done * 100).checked_div(total).unwrap_or(100);
+    eprint!(
+        "\r[{}{}] {percent:>3}% {} / {}",
+        "#".repeat(filled as usize),
+        ".".repeat((PROGRESS_BAR_WIDTH - filled) as usize),
+        scsi::human_size(done),
+        scsi::human_size(total)
+    );
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
     // Initialize error handling
     color_eyre::install()?;
-    // Initialize logging
+    let matches = cli().get_matches();
+    // Initialize logging, at INFO unless overridden with `RUST_LOG`, using the same
+    // `target=level` directives as `EnvFilter`, e.g `RUST_LOG=info,floatglass::usb=trace`
+    let filter = match std::env::var("RUST_LOG") {
+        Ok(directives) => directives
+            .parse::<Targets>()
+            .wrap_err("RUST_LOG is not a valid filter")?,
+        Err(_) => Targets::new().with_default(LevelFilter::INFO),
+    };
     tracing_subscriber::fmt()
-        .with_max_level(LevelFilter::DEBUG)
         .without_time()
+        // Logs are kept off stdout, which `read` may be writing data to
+        .with_writer(std::io::stderr)
+        .finish()
+        .with(filter)
         .init();
     info!("starting");
-    let mut devices = enumerate_usb_storage_devices().await?;
-    let device = devices
-        .next()
-        .wrap_err("at least one usb drive should be connected")?;
-    let drive = usb::USBDrive::new(device).await?;
-    let mut scsi_device = scsi::SCSIDevice::new(drive).await?;
-
-    let first_block = scsi_device
-        .issue_command(command::read(1, 1, scsi_device.block_size))
-        .await?;
-    let mut hex_repr = String::with_capacity(512);
-    let mut ascii_repr = String::with_capacity(512);
-    for byte in first_block.raw().iter() {
-        write!(hex_repr, "{byte:X} ")?;
-        if byte.is_ascii() {
-            ascii_repr.push(*byte as char);
-        } else {
-            ascii_repr.push('.');
-        }
+    match matches.subcommand() {
+        Some(("info", matches)) => info_command(matches).await,
+        Some(("read", matches)) => read_command(matches).await,
+        Some(("write", matches)) => write_command(matches).await,
+        _ => unreachable!("clap requires a subcommand"),
     }
+}
 
-    println!(
-        "HEX REPRESENTATION:\n----------\n{hex_repr}\n-----------\nACII REPR:\n-------------\n{ascii_repr}\n-------------"
-    );
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use crate::{json_string, parse_hex_id};
+
+    #[test]
+    fn strings_are_escaped_for_json() {
+        assert_eq!(json_string("DataTraveler 3.0"), r#""DataTraveler 3.0""#);
+        assert_eq!(json_string("a \"b\"\\\n\u{1}"), r#""a \"b\"\\\n\u0001""#);
+    }
+
+    #[test]
+    fn usb_ids_are_hex() {
+        assert_eq!(parse_hex_id("0951"), Ok(0x0951));
+        assert_eq!(parse_hex_id("0x1666"), Ok(0x1666));
+        assert!(parse_hex_id("10000").is_err());
+    }
 }
@@ -0,0 +1,247 @@
+//! ATA commands tunnelled to the drive behind a USB-SATA bridge with `ATA PASS-THROUGH`, as
+//! described in:
+//! - SCSI / ATA Translation - 2 (SAT-2), which defines how ATA commands are carried by SCSI
+//! - ATA/ATAPI Command Set (ACS), which defines the ATA commands themselves
+//!
+//! Plenty of bridges don't implement pass-through at all, and fail these commands with
+//! `ILLEGAL REQUEST`.
+
+use eyre::{OptionExt, Result, ensure};
+
+/// The size of an ATA sector, the unit of every PIO data transfer.
+pub const ATA_SECTOR_SIZE: u32 = 512;
+
+/// The largest logical sector size accepted from `IDENTIFY DEVICE`, anything larger is assumed
+/// to be garbage rather than a real drive.
+const MAX_LOGICAL_SECTOR_SIZE: u32 = 64 * 1024;
+
+/// The ATA protocol used by a command, which determines whether and how data is transferred.
+///
+/// Only the protocols needed to read data from the drive are supported.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AtaProtocol {
+    /// No data is transferred
+    NonData,
+    /// Sectors are transferred from the drive with programmed I/O
+    PioDataIn,
+}
+
+impl AtaProtocol {
+    /// The value of the `PROTOCOL` field of `ATA PASS-THROUGH`
+    pub fn code(self) -> u8 {
+        match self {
+            Self::NonData => 3,
+            Self::PioDataIn => 4,
+        }
+    }
+}
+
+/// The registers of an ATA command, see [`command::ata_passthrough16`](super::command::ata_passthrough16).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AtaCommand {
+    /// The command code, e.g [`AtaCommand::IDENTIFY_DEVICE`]
+    pub command: u8,
+    pub features: u16,
+    /// For PIO Data-In commands, the number of sectors transferred
+    pub sector_count: u16,
+    /// The 48-bit logical block address, or any other value carried by the LBA registers
+    pub lba: u64,
+    pub device: u8,
+    pub protocol: AtaProtocol,
+    /// Set for 48-bit commands, so the upper byte of every register is sent too
+    pub extend: bool,
+}
+
+impl AtaCommand {
+    /// `IDENTIFY DEVICE`, returning a sector of information about the drive
+    pub const IDENTIFY_DEVICE: u8 = 0xEC;
+    /// `SMART`, with the subcommand selected by the features register
+    pub const SMART: u8 = 0xB0;
+
+    /// Builds `IDENTIFY DEVICE`, which returns 512 bytes describing the drive, including its
+    /// model, serial number, and supported features.
+    pub fn identify_device() -> Self {
+        Self {
+            command: Self::IDENTIFY_DEVICE,
+            features: 0,
+            sector_count: 1,
+            lba: 0,
+            device: 0,
+            protocol: AtaProtocol::PioDataIn,
+            extend: false,
+        }
+    }
+
+    /// Builds `SMART READ DATA`, which returns 512 bytes of device SMART data.
+    pub fn smart_read_data() -> Self {
+        Self {
+            command: Self::SMART,
+            // SMART READ DATA
+            features: 0xD0,
+            sector_count: 1,
+            // Every SMART subcommand requires the LBA Mid and LBA High registers to be set to
+            // 4Fh and C2h
+            lba: 0xC2_4F00,
+            device: 0,
+            protocol: AtaProtocol::PioDataIn,
+            extend: false,
+        }
+    }
+
+    /// Returns the number of bytes the command transfers from the drive.
+    pub fn data_in_len(&self) -> u32 {
+        match self.protocol {
+            AtaProtocol::NonData => 0,
+            AtaProtocol::PioDataIn => u32::from(self.sector_count) * ATA_SECTOR_SIZE,
+        }
+    }
+}
+
+/// The data returned by `IDENTIFY DEVICE`, describing the drive behind the bridge.
+///
+/// Unlike the bridge's INQUIRY data, which is often generic (e.g `USB 3.0 Device`), this is the
+/// identity of the drive itself.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IdentifyDevice {
+    /// `MODEL NUMBER`, words 27-46
+    pub model: String,
+    /// `SERIAL NUMBER`, words 10-19
+    pub serial: String,
+    /// `FIRMWARE REVISION`, words 23-26
+    pub firmware: String,
+    /// True if the 48-bit Address feature set is supported (word 83 bit 10)
+    pub lba48: bool,
+    /// The number of user addressable logical sectors
+    pub sector_count: u64,
+    /// The size of each logical sector in *bytes*
+    pub logical_sector_size: u32,
+    /// The size of each physical sector in *bytes*, larger than the logical sector size on
+    /// Advanced Format drives
+    pub physical_sector_size: u32,
+}
+
+impl IdentifyDevice {
+    /// Parses the 512 bytes returned by `IDENTIFY DEVICE`, which are 256 little endian words.
+    pub fn parse(buf: &[u8]) -> Result<Self> {
+        ensure!(
+            buf.len() >= ATA_SECTOR_SIZE as usize,
+            "IDENTIFY DEVICE data should be {ATA_SECTOR_SIZE} bytes, was {}",
+            buf.len()
+        );
+        let word = |index: usize| u16::from_le_bytes([buf[index * 2], buf[index * 2 + 1]]);
+        let lba48 = word(83) & (1 << 10) != 0;
+        let sector_count = if lba48 {
+            (100..104)
+                .rev()
+                .fold(0, |count, index| count << 16 | u64::from(word(index)))
+        } else {
+            u64::from(word(61)) << 16 | u64::from(word(60))
+        };
+
+        // Word 106 is only valid if bit 14 is set and bit 15 is clear
+        let sector_sizes = word(106);
+        let mut logical_sector_size = ATA_SECTOR_SIZE;
+        let mut physical_sector_size = ATA_SECTOR_SIZE;
+        if sector_sizes & 0xC000 == 0x4000 {
+            // Logical sectors are longer than 256 words, with the size in words 117-118
+            if sector_sizes & (1 << 12) != 0 {
+                let words = u32::from(word(118)) << 16 | u32::from(word(117));
+                logical_sector_size = words
+                    .checked_mul(2)
+                    .filter(|size| (ATA_SECTOR_SIZE..=MAX_LOGICAL_SECTOR_SIZE).contains(size))
+                    .ok_or_eyre(format!(
+                        "IDENTIFY DEVICE reports an implausible logical sector size of {words} words"
+                    ))?;
+            }
+            // Bits 3:0 are the log2 of the number of logical sectors per physical sector
+            if sector_sizes & (1 << 13) != 0 {
+                // Can't overflow, as the logical sector size is at most 64KiB
+                physical_sector_size = logical_sector_size << (sector_sizes & 0x0F);
+            } else {
+                physical_sector_size = logical_sector_size;
+            }
+        }
+
+        Ok(Self {
+            model: ata_string(&buf[54..94]),
+            serial: ata_string(&buf[20..40]),
+            firmware: ata_string(&buf[46..54]),
+            lba48,
+            sector_count,
+            logical_sector_size,
+            physical_sector_size,
+        })
+    }
+
+    /// The total size of the drive in *bytes*
+    pub fn total_bytes(&self) -> u64 {
+        self.sector_count * u64::from(self.logical_sector_size)
+    }
+}
+
+/// Decodes an ATA string, which holds two ASCII characters per word, with the first character
+/// in the upper byte, padded with spaces.
+fn ata_string(field: &[u8]) -> String {
+    field
+        .chunks_exact(2)
+        .flat_map(|pair| [pair[1], pair[0]])
+        .map(char::from)
+        .collect::<String>()
+        .trim()
+        .to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::scsi::ata::IdentifyDevice;
+
+    /// Writes `text` into `buf` as an ATA string starting at word `word`.
+    fn put_string(buf: &mut [u8], word: usize, text: &[u8]) {
+        for (i, pair) in text.chunks(2).enumerate() {
+            buf[(word + i) * 2] = pair[1];
+            buf[(word + i) * 2 + 1] = pair[0];
+        }
+    }
+
+    #[test]
+    fn decode_identify_device() {
+        let mut buf = [0; 512];
+        put_string(&mut buf, 10, b"     WD-WX12A3456789");
+        put_string(&mut buf, 23, b"01.01A01");
+        put_string(&mut buf, 27, b"WDC WD40EZRZ-00GXCB0                    ");
+        // 48-bit addressing
+        buf[83 * 2 + 1] = 0b0000_0100;
+        // 7814037168 sectors
+        buf[200..208].copy_from_slice(&7_814_037_168_u64.to_le_bytes());
+        // 8 logical sectors per physical sector
+        buf[106 * 2..106 * 2 + 2].copy_from_slice(&0x6003_u16.to_le_bytes());
+
+        let identify = IdentifyDevice::parse(&buf).unwrap();
+        assert_eq!(identify.model, "WDC WD40EZRZ-00GXCB0");
+        assert_eq!(identify.serial, "WD-WX12A3456789");
+        assert_eq!(identify.firmware, "01.01A01");
+        assert!(identify.lba48);
+        assert_eq!(identify.sector_count, 7_814_037_168);
+        assert_eq!(identify.logical_sector_size, 512);
+        assert_eq!(identify.physical_sector_size, 4096);
+        assert_eq!(identify.total_bytes(), 7_814_037_168 * 512);
+        assert!(IdentifyDevice::parse(&buf[..256]).is_err());
+    }
+
+    #[test]
+    fn implausible_sector_sizes_are_rejected() {
+        let mut buf = [0; 512];
+        // Logical sectors longer than 256 words, with the size in words 117-118
+        buf[106 * 2..106 * 2 + 2].copy_from_slice(&0x5000_u16.to_le_bytes());
+        buf[117 * 2..117 * 2 + 4].copy_from_slice(&2048_u32.to_le_bytes());
+        assert_eq!(
+            IdentifyDevice::parse(&buf).unwrap().logical_sector_size,
+            4096
+        );
+        // Would overflow when converted to bytes
+        buf[117 * 2..117 * 2 + 4].copy_from_slice(&0xFFFF_FFFF_u32.to_le_bytes());
+        assert!(IdentifyDevice::parse(&buf).is_err());
+        buf[117 * 2..117 * 2 + 4].copy_from_slice(&0_u32.to_le_bytes());
+        assert!(IdentifyDevice::parse(&buf).is_err());
+    }
+}
@@ -0,0 +1,63 @@
+//! Cancellation of commands issued by a [`SCSIDevice`](super::SCSIDevice), so that long running
+//! operations like imaging a drive can be stopped part way through.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use tokio::sync::Notify;
+
+/// A handle for aborting the commands of a [`SCSIDevice`](super::SCSIDevice) from another task,
+/// see [`SCSIDevice::set_cancellation_token`](super::SCSIDevice::set_cancellation_token).
+///
+/// Clones share the same state, so one clone can be attached to the device while another is
+/// handed to e.g a GUI's stop button. Once cancelled, a token stays cancelled.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken {
+    state: Arc<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    cancelled: AtomicBool,
+    notify: Notify,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancels the token, waking every task waiting in [`CancellationToken::cancelled`].
+    pub fn cancel(&self) {
+        self.state.cancelled.store(true, Ordering::Release);
+        self.state.notify.notify_waiters();
+    }
+
+    /// Returns true once the token has been cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.state.cancelled.load(Ordering::Acquire)
+    }
+
+    /// Waits until the token is cancelled.
+    pub async fn cancelled(&self) {
+        // `notify_waiters` wakes every future created before it's called, so creating this
+        // before checking the flag means a cancellation in between can't be missed
+        let notified = self.state.notify.notified();
+        if self.is_cancelled() {
+            return;
+        }
+        notified.await;
+    }
+}
+
+/// The error returned by commands aborted with a [`CancellationToken`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("the command was cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
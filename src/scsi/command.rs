@@ -7,35 +7,90 @@
 //! Commands are exposed as a function that returns a [`CommandBlock`]. These functions wrap
 //! the more granular [`ShortCommandDescriptor`] and [`LongCommandDescriptor`] structs.
 
+use eyre::{OptionExt, Result, ensure};
+
 use super::command_descriptor::*;
-use crate::{scsi::response, usb::cbw::CBWDirection};
+use crate::{
+    scsi::{
+        ata::{AtaCommand, AtaProtocol},
+        response,
+    },
+    usb::cbw::CBWDirection,
+};
 
 /// A serialized command block ready to be submitted
-pub struct CommandBlock {
+pub struct CommandBlock<'a> {
     command: Box<dyn CommandDescriptor>,
     pub direction: CBWDirection,
     pub data_transfer_len: u32,
+    /// The data sent to the device during the Data-Out phase.
+    ///
+    /// Only present for commands with a [`CBWDirection::DataOut`] direction,
+    /// in which case its length matches `data_transfer_len`.
+    pub data_out: Option<&'a [u8]>,
     pub response_parser: response::ResponseParser,
 }
 
-impl CommandBlock {
+impl Clone for CommandBlock<'_> {
+    /// The copy has the same CDB bytes, but is backed by a raw descriptor.
+    fn clone(&self) -> Self {
+        CommandBlock {
+            command: Box::new(RawCommandDescriptor(self.command.as_slice().to_vec())),
+            direction: self.direction,
+            data_transfer_len: self.data_transfer_len,
+            data_out: self.data_out,
+            response_parser: self.response_parser,
+        }
+    }
+}
+
+impl<'a> CommandBlock<'a> {
+    /// Builds a command from raw CDB bytes, for commands this crate doesn't model
+    /// (e.g vendor specific commands).
+    ///
+    /// `transfer_len` is the number of bytes transferred in `direction`. For a
+    /// [`CBWDirection::DataOut`] command, [`CommandBlock::data_out`] must be set to exactly that
+    /// many bytes before the command is submitted. Any Data-In response is passed to `parser`,
+    /// [`response::raw`] can be used to receive the bytes as is.
+    pub fn raw(
+        cdb: &[u8],
+        direction: CBWDirection,
+        transfer_len: u32,
+        parser: response::ResponseParser,
+    ) -> Result<Self> {
+        // `bCBWCBLength`: "The only legal values are 1 through 16"
+        ensure!(
+            (1..=16).contains(&cdb.len()),
+            "command block must be 1 to 16 bytes long, was {}",
+            cdb.len()
+        );
+        Ok(CommandBlock {
+            command: Box::new(RawCommandDescriptor(cdb.to_vec())),
+            direction,
+            data_transfer_len: transfer_len,
+            data_out: None,
+            response_parser: parser,
+        })
+    }
+
     /// Returns the length of the underlying command block.
     ///
-    /// Will always be less than 16 bytes.
-    pub fn size_of(&self) -> usize {
-        std::mem::size_of_val(&*self.command)
+    /// Valid command blocks are 1 to 16 bytes long.
+    pub fn len(&self) -> usize {
+        self.command.as_slice().len()
+    }
+
+    /// Returns true if the underlying command block has no bytes, which is never valid.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
     }
 
     /// Returns a valid command block, prepared as described by USB Mass
     /// Storage Class - Bulk Only Transport section 5.1 (CBWCB).
     pub fn get(&self) -> [u8; 16] {
         let mut output_buf: [u8; 16] = [0; 16];
-        let (subslice, _) = output_buf.split_at_mut(self.size_of());
-        let slice = unsafe {
-            let ptr = &*self.command as *const dyn CommandDescriptor as *const u8;
-            std::slice::from_raw_parts(ptr, self.size_of())
-        };
-        subslice.copy_from_slice(slice);
+        let (subslice, _) = output_buf.split_at_mut(self.len());
+        subslice.copy_from_slice(self.command.as_slice());
         output_buf
     }
 }
@@ -45,7 +100,11 @@ impl CommandBlock {
 /// "The READ (10) command request that the device server transfer data to the application client."
 ///
 /// SBC-2 5.1.7
-pub fn read(logical_block_address: u32, transfer_len: u16, block_size: u32) -> CommandBlock {
+pub fn read(
+    logical_block_address: u32,
+    transfer_len: u16,
+    block_size: u32,
+) -> CommandBlock<'static> {
     CommandBlock {
         command: Box::new(X10CommandDescriptor {
             operation_code: OpCode::Read,
@@ -59,19 +118,21 @@ pub fn read(logical_block_address: u32, transfer_len: u16, block_size: u32) -> C
         }),
         direction: CBWDirection::DataIn,
         data_transfer_len: u32::from(transfer_len) * block_size,
+        data_out: None,
         response_parser: response::no_response,
     }
 }
 
 /// Write `transfer_len` contiguous blocks to the device, starting at `logical_block_address`.
 ///
-/// After this command is submitted, the data is written to the USB bulk-out endpoint
+/// After this command is submitted, `data` is written to the USB bulk-out endpoint, so it
+/// must be exactly `transfer_len` blocks long.
 ///
 ///"The WRITE (10) command requests that the device server write the data transferred by the
 /// application client to the medium."
 ///
 /// SBC-2 5.1.29
-pub fn write(transfer_len: u16, logical_block_address: u32, block_size: u32) -> CommandBlock {
+pub fn write(logical_block_address: u32, transfer_len: u16, data: &[u8]) -> CommandBlock<'_> {
     CommandBlock {
         command: Box::new(X10CommandDescriptor {
             operation_code: OpCode::Write,
@@ -86,12 +147,305 @@ pub fn write(transfer_len: u16, logical_block_address: u32, block_size: u32) ->
             control: 0,
         }),
         direction: CBWDirection::DataOut,
-        data_transfer_len: u32::from(transfer_len * (block_size as u16)),
+        data_transfer_len: data.len() as u32,
+        data_out: Some(data),
+        response_parser: response::no_response,
+    }
+}
+
+/// "The WRITE SAME (10) command requests that the device server write the single block of data
+/// transferred by the application client to the medium multiple times to consecutive multiple
+/// logical blocks."
+///
+/// `pattern` must be exactly one block long, and is written to the `block_count` blocks starting
+/// at `logical_block_address`. "A NUMBER OF BLOCKS field set to zero specifies that the device
+/// server write all the logical blocks starting with the one specified in the LOGICAL BLOCK
+/// ADDRESS field to the last logical block on the medium."
+///
+/// Support is optional, devices that don't implement it reject it as an ILLEGAL REQUEST.
+///
+/// SBC-2, `WRITE SAME (10)`
+pub fn write_same10(
+    logical_block_address: u32,
+    block_count: u16,
+    pattern: &[u8],
+) -> CommandBlock<'_> {
+    CommandBlock {
+        command: Box::new(X10CommandDescriptor {
+            operation_code: OpCode::WriteSame10,
+            // WRPROTECT, PBDATA, and LBDATA are left unset, so the block is written as is
+            service_action: 0,
+            logical_block_address: logical_block_address.to_be_bytes(),
+            // GROUP NUMBER
+            _reserved: 0,
+            // NUMBER OF BLOCKS
+            misc_len: block_count.to_be_bytes(),
+            control: 0,
+        }),
+        direction: CBWDirection::DataOut,
+        data_transfer_len: pattern.len() as u32,
+        data_out: Some(pattern),
+        response_parser: response::no_response,
+    }
+}
+
+/// Read `transfer_len` contiguous blocks from the device, starting at `logical_block_address`.
+///
+/// Identical to [`read`], but with a 32 bit transfer length. Some older devices only
+/// implement the 12 byte variant.
+///
+/// Fails if `transfer_len` blocks of `block_size` bytes don't fit in a single transfer.
+///
+/// SBC-2 5.1.8
+pub fn read_12(
+    logical_block_address: u32,
+    transfer_len: u32,
+    block_size: u32,
+) -> Result<CommandBlock<'static>> {
+    let data_transfer_len = transfer_len
+        .checked_mul(block_size)
+        .ok_or_eyre("READ (12) transfer length exceeds 4GiB")?;
+    Ok(CommandBlock {
+        command: Box::new(X12CommandDescriptor {
+            operation_code: OpCode::Read12,
+            // DPO, FUA, and RELADR are left unset, like `READ (10)`
+            service_action: 0,
+            logical_block_address: logical_block_address.to_be_bytes(),
+            misc_len: transfer_len.to_be_bytes(),
+            _reserved: 0,
+            control: 0,
+        }),
+        direction: CBWDirection::DataIn,
+        data_transfer_len,
+        data_out: None,
+        response_parser: response::no_response,
+    })
+}
+
+/// Write `transfer_len` contiguous blocks to the device, starting at `logical_block_address`.
+///
+/// Identical to [`write()`], but with a 32 bit transfer length.
+///
+/// Fails unless `data` is exactly `transfer_len` blocks of `block_size` bytes, and fits in a
+/// single transfer.
+///
+/// SBC-2 5.1.30
+pub fn write_12(
+    logical_block_address: u32,
+    transfer_len: u32,
+    block_size: u32,
+    data: &[u8],
+) -> Result<CommandBlock<'_>> {
+    let data_transfer_len = data_out_len("WRITE (12)", transfer_len, block_size, data)?;
+    Ok(CommandBlock {
+        command: Box::new(X12CommandDescriptor {
+            operation_code: OpCode::Write12,
+            // DPO, FUA, and RELADR are left unset, like `WRITE (10)`
+            service_action: 0,
+            logical_block_address: logical_block_address.to_be_bytes(),
+            misc_len: transfer_len.to_be_bytes(),
+            _reserved: 0,
+            control: 0,
+        }),
+        direction: CBWDirection::DataOut,
+        data_transfer_len,
+        data_out: Some(data),
+        response_parser: response::no_response,
+    })
+}
+
+/// Read `transfer_len` contiguous blocks from the device, starting at `logical_block_address`.
+///
+/// Identical to [`read`], but with a 64 bit logical block address, needed to address
+/// anything past the first 2TiB of a device with 512 byte blocks.
+///
+/// Fails if `transfer_len` blocks of `block_size` bytes don't fit in a single transfer.
+///
+/// SBC-2 5.1.9
+pub fn read_16(
+    logical_block_address: u64,
+    transfer_len: u32,
+    block_size: u32,
+) -> Result<CommandBlock<'static>> {
+    let data_transfer_len = transfer_len
+        .checked_mul(block_size)
+        .ok_or_eyre("READ (16) transfer length exceeds 4GiB")?;
+    Ok(CommandBlock {
+        command: Box::new(X16CommandDescriptor {
+            operation_code: OpCode::Read16,
+            // DPO, FUA, and FUA_NV are left unset
+            misc_info: 0,
+            logical_block_address: logical_block_address.to_be_bytes(),
+            param: transfer_len.to_be_bytes(),
+            // GROUP NUMBER
+            _reserved: 0,
+            control: 0,
+        }),
+        direction: CBWDirection::DataIn,
+        data_transfer_len,
+        data_out: None,
+        response_parser: response::no_response,
+    })
+}
+
+/// Write `transfer_len` contiguous blocks to the device, starting at `logical_block_address`.
+///
+/// Identical to [`write()`], but with a 64 bit logical block address.
+///
+/// Fails unless `data` is exactly `transfer_len` blocks of `block_size` bytes, and fits in a
+/// single transfer.
+///
+/// SBC-2 5.1.31
+pub fn write_16(
+    logical_block_address: u64,
+    transfer_len: u32,
+    block_size: u32,
+    data: &[u8],
+) -> Result<CommandBlock<'_>> {
+    let data_transfer_len = data_out_len("WRITE (16)", transfer_len, block_size, data)?;
+    Ok(CommandBlock {
+        command: Box::new(X16CommandDescriptor {
+            operation_code: OpCode::Write16,
+            // DPO, FUA, and FUA_NV are left unset
+            misc_info: 0,
+            logical_block_address: logical_block_address.to_be_bytes(),
+            param: transfer_len.to_be_bytes(),
+            // GROUP NUMBER
+            _reserved: 0,
+            control: 0,
+        }),
+        direction: CBWDirection::DataOut,
+        data_transfer_len,
+        data_out: Some(data),
+        response_parser: response::no_response,
+    })
+}
+
+/// Returns the data transfer length of a write of `transfer_len` blocks of `block_size` bytes,
+/// checking that `data` is exactly that long.
+fn data_out_len(command: &str, transfer_len: u32, block_size: u32, data: &[u8]) -> Result<u32> {
+    let data_transfer_len = transfer_len
+        .checked_mul(block_size)
+        .ok_or_eyre(format!("{command} transfer length exceeds 4GiB"))?;
+    ensure!(
+        data.len() == data_transfer_len as usize,
+        "{command} of {transfer_len} blocks of {block_size}B needs {data_transfer_len} bytes of data, was {}",
+        data.len()
+    );
+    Ok(data_transfer_len)
+}
+
+/// The `SEEK(10)` command requests that the device seek to `lba`.
+///
+/// No data is transferred, the command only positions the heads of spinning drives, so it can be
+/// used to measure seek latency separately from reads. The command was defined by SCSI-2 and the
+/// first SBC, but SBC-2 made it obsolete, so newer devices may reject it with
+/// `ILLEGAL REQUEST`, see [`pre_fetch`].
+pub fn seek10(lba: u32) -> CommandBlock<'static> {
+    CommandBlock {
+        command: Box::new(X10CommandDescriptor {
+            operation_code: OpCode::Seek10,
+            service_action: 0,
+            logical_block_address: lba.to_be_bytes(),
+            _reserved: 0,
+            misc_len: [0, 0],
+            control: 0,
+        }),
+        direction: CBWDirection::NonDirectional,
+        data_transfer_len: 0,
+        data_out: None,
+        response_parser: response::no_response,
+    }
+}
+
+/// "The PRE-FETCH (10) command requests that the device server transfer the specified logical
+/// blocks to the cache."
+///
+/// A `block_count` of zero pre-fetches every block from `logical_block_address` to the end of
+/// the medium. No data is transferred to the host. A device that can't cache every block
+/// still completes the command, with a `CONDITION MET` status only reported if they all fit.
+///
+/// SBC-2 5.1.3
+pub fn pre_fetch(logical_block_address: u32, block_count: u16) -> CommandBlock<'static> {
+    CommandBlock {
+        command: Box::new(X10CommandDescriptor {
+            operation_code: OpCode::PreFetch,
+            // IMMED is left unset, so the status isn't returned until the blocks are cached
+            service_action: 0,
+            logical_block_address: logical_block_address.to_be_bytes(),
+            _reserved: 0,
+            // PREFETCH LENGTH
+            misc_len: block_count.to_be_bytes(),
+            control: 0,
+        }),
+        direction: CBWDirection::NonDirectional,
+        data_transfer_len: 0,
+        data_out: None,
+        response_parser: response::no_response,
+    }
+}
+
+/// "The SYNCHRONIZE CACHE (10) command requests that the device server ensure that the specified
+/// logical blocks have their most recent data values recorded on the medium."
+///
+/// A `block_count` of zero synchronizes every block from `logical_block_address` to the end of
+/// the medium.
+///
+/// SBC-2 5.1.18
+pub fn synchronize_cache(logical_block_address: u32, block_count: u16) -> CommandBlock<'static> {
+    CommandBlock {
+        command: Box::new(X10CommandDescriptor {
+            operation_code: OpCode::SynchronizeCache,
+            // IMMED is left unset, so the status isn't returned until the cache has been flushed
+            service_action: 0,
+            logical_block_address: logical_block_address.to_be_bytes(),
+            _reserved: 0,
+            // NUMBER OF BLOCKS
+            misc_len: block_count.to_be_bytes(),
+            control: 0,
+        }),
+        direction: CBWDirection::NonDirectional,
+        data_transfer_len: 0,
+        data_out: None,
         response_parser: response::no_response,
     }
 }
 
-// TODO: implement SBC-2 5.1.25 VERIFY (10), or WRITE AND VERIFY
+/// "The VERIFY (10) command requests that the device server verify the specified logical
+/// block(s) on the medium."
+///
+/// Without `compare`, the device only checks that the blocks can be read back from the medium.
+/// With `compare` (`BYTCHK`), it's sent to the device during the Data-Out phase and compared
+/// byte for byte against the medium, so it must be exactly `block_count` blocks long. A mismatch
+/// is reported as a CHECK CONDITION with a sense key of MISCOMPARE.
+///
+/// SBC-2 5.1.25
+pub fn verify(
+    logical_block_address: u32,
+    block_count: u16,
+    compare: Option<&[u8]>,
+) -> CommandBlock<'_> {
+    let byte_check = u8::from(compare.is_some());
+    CommandBlock {
+        command: Box::new(X10CommandDescriptor {
+            operation_code: OpCode::Verify,
+            // VRPROTECT and DPO are left unset
+            service_action: byte_check << 1,
+            logical_block_address: logical_block_address.to_be_bytes(),
+            _reserved: 0,
+            // VERIFICATION LENGTH
+            misc_len: block_count.to_be_bytes(),
+            control: 0,
+        }),
+        direction: match compare {
+            Some(_) => CBWDirection::DataOut,
+            None => CBWDirection::NonDirectional,
+        },
+        data_transfer_len: compare.map_or(0, |data| data.len() as u32),
+        data_out: compare,
+        response_parser: response::no_response,
+    }
+}
 
 /// "The TEST UNIT READY command provides a means to check if the logical unit is ready.
 ///
@@ -102,7 +456,7 @@ pub fn write(transfer_len: u16, logical_block_address: u32, block_size: u32) ->
 /// CHECK CONDITION status with a sense key of NOT READY."
 ///
 /// Defined in SPC2 7.25
-pub fn test_unit_ready() -> CommandBlock {
+pub fn test_unit_ready() -> CommandBlock<'static> {
     CommandBlock {
         command: Box::new(X6CommandDescriptor {
             operation_code: OpCode::TestUnitReady,
@@ -112,49 +466,245 @@ pub fn test_unit_ready() -> CommandBlock {
         }),
         direction: CBWDirection::NonDirectional,
         data_transfer_len: 0,
+        data_out: None,
         response_parser: response::no_response,
     }
 }
 
+/// "The REQUEST SENSE command requests that the device server transfer sense data
+/// to the application client."
+///
+/// This is how the cause of a CHECK CONDITION is retrieved, the sense data
+/// describes the *last* command that failed.
+///
+/// Defined in SPC-2 7.20
+pub fn request_sense() -> CommandBlock<'static> {
+    CommandBlock {
+        command: Box::new(X6CommandDescriptor {
+            operation_code: OpCode::RequestSense,
+            logical_block_address: [0, 0, 0],
+            // ALLOCATION LENGTH, 18 bytes is enough to contain the fixed format
+            // sense data (table 102), up to and including the SENSE KEY SPECIFIC field.
+            misc_len: 18,
+            control: 0,
+        }),
+        direction: CBWDirection::DataIn,
+        data_transfer_len: 18,
+        data_out: None,
+        response_parser: response::request_sense,
+    }
+}
+
 /// "The INQUIRY command requests that information regarding parameters
 /// of the target and a component logical unit be sent to the application client.
 /// Options allow the client to request additional information."
 ///
+/// Only the standard 36 bytes are requested, see [`inquiry_with_length`] to request more.
+///
 /// Defined in SPC2 7.3.1 table 45
-pub fn inquiry() -> CommandBlock {
+pub fn inquiry() -> CommandBlock<'static> {
+    // "The standard INQUIRY data shall contain at least 36 bytes" (table 46)
+    inquiry_with_length(response::STANDARD_INQUIRY_LEN as u8)
+}
+
+/// Requests up to `allocation_len` bytes of standard INQUIRY data, which includes
+/// vendor specific data and version descriptors past the first 36 bytes.
+///
+/// The length of the INQUIRY data is given by its ADDITIONAL LENGTH field (byte 4), which can be
+/// read from a response to [`inquiry`].
+pub fn inquiry_with_length(allocation_len: u8) -> CommandBlock<'static> {
     CommandBlock {
         command: Box::new(X6CommandDescriptor {
             operation_code: OpCode::Inquiry,
             logical_block_address: [0, 0, 0],
-            // For inquiry, is ALLOCATION LENGTH,
-            // "The standard INQUIRY data shall contain at least 36 bytes"
-            // (table 46)
-            misc_len: 36,
+            // For inquiry, is ALLOCATION LENGTH
+            misc_len: allocation_len,
             control: 0,
         }),
         direction: CBWDirection::DataIn,
-        data_transfer_len: 36,
+        data_transfer_len: u32::from(allocation_len),
+        data_out: None,
         response_parser: response::inquiry,
     }
 }
 
+/// Requests a vital product data page with `INQUIRY`, by setting the EVPD bit.
+///
+/// "The application client can request vital product data information by setting
+/// the EVPD bit to one and specifying the page code of the desired vital product data."
+///
+/// See [`response::UNIT_SERIAL_NUMBER_VPD_PAGE`] for a commonly supported page.
+///
+/// Defined in SPC2 7.3.1, 8.4
+pub fn inquiry_vpd(page_code: u8) -> CommandBlock<'static> {
+    // 0 - false. 1 - true
+    let enable_vital_product_data: u8 = 1;
+    CommandBlock {
+        command: Box::new(X6CommandDescriptor {
+            operation_code: OpCode::Inquiry,
+            logical_block_address: [enable_vital_product_data, page_code, 0],
+            // ALLOCATION LENGTH, the largest that fits in a byte, as the length of VPD pages
+            // varies
+            misc_len: u8::MAX,
+            control: 0,
+        }),
+        direction: CBWDirection::DataIn,
+        data_transfer_len: u32::from(u8::MAX),
+        data_out: None,
+        response_parser: response::vital_product_data,
+    }
+}
+
+/// The largest number of LUNs a Bulk-Only device can have, see [`crate::usb::USBDrive::max_lun`]
+const MAX_BULK_ONLY_LUNS: u32 = 16;
+
+/// "The REPORT LUNS command requests that the peripheral device logical unit numbers of known
+/// logical units in the target be sent to the application client."
+///
+/// Unlike GET MAX LUN, only logical units that are present are reported.
+///
+/// SPC-2 7.19
+pub fn report_luns() -> CommandBlock<'static> {
+    // The 8 byte header, then 8 bytes per LUN
+    let allocation_len = 8 + MAX_BULK_ONLY_LUNS * 8;
+    CommandBlock {
+        command: Box::new(X12CommandDescriptor {
+            operation_code: OpCode::ReportLuns,
+            service_action: 0,
+            // SELECT REPORT (byte 2) is left as 0, reporting every logical unit
+            logical_block_address: [0, 0, 0, 0],
+            // ALLOCATION LENGTH
+            misc_len: allocation_len.to_be_bytes(),
+            _reserved: 0,
+            control: 0,
+        }),
+        direction: CBWDirection::DataIn,
+        data_transfer_len: allocation_len,
+        data_out: None,
+        response_parser: response::report_luns,
+    }
+}
+
+/// The service action of `MAINTENANCE IN` that selects `REPORT SUPPORTED OPERATION CODES`
+const REPORT_SUPPORTED_OPERATION_CODES: u8 = 0x0C;
+
+/// "The REPORT SUPPORTED OPERATION CODES command requests information on commands the
+/// addressed logical unit supports."
+///
+/// Every supported command is listed. Many devices don't implement this command, and
+/// reject it with `ILLEGAL REQUEST`.
+///
+/// SPC-3 6.23
+pub fn report_supported_opcodes() -> CommandBlock<'static> {
+    // The 4 byte header, then 8 bytes per command. Large enough for every command a USB
+    // storage device would plausibly support.
+    let allocation_len: u32 = 4 + 256 * 8;
+    CommandBlock {
+        command: Box::new(X12CommandDescriptor {
+            operation_code: OpCode::MaintenanceIn,
+            service_action: REPORT_SUPPORTED_OPERATION_CODES,
+            // REPORTING OPTIONS (byte 2) is left as 0 to list all commands, which leaves
+            // REQUESTED OPERATION CODE and REQUESTED SERVICE ACTION (bytes 3-5) unused
+            logical_block_address: [0, 0, 0, 0],
+            // ALLOCATION LENGTH
+            misc_len: allocation_len.to_be_bytes(),
+            _reserved: 0,
+            control: 0,
+        }),
+        direction: CBWDirection::DataIn,
+        data_transfer_len: allocation_len,
+        data_out: None,
+        response_parser: response::report_supported_opcodes,
+    }
+}
+
 /// "The PREVENT ALLOW MEDIUM REMOVAL" command (see table 77) requests that
 /// the target enable or disable the removal of the medium in the logical unit.
 /// The logical unit shall not allow medium removal if any initiator current
 /// has medium removal prevented."
 ///
+/// With `prevent` set, all forms of medium removal are prohibited, otherwise the medium may
+/// be removed again.
+///
 /// SPC-2 7.12
-pub fn prevent_allow_medium_removal() -> CommandBlock {
+pub fn prevent_allow_medium_removal(prevent: bool) -> CommandBlock<'static> {
     CommandBlock {
         command: Box::new(X6CommandDescriptor {
             operation_code: OpCode::PreventAllowMediumRemoval,
             logical_block_address: [0, 0, 0],
-            // See table 78, prohibits all form of medium removal
-            misc_len: 0b0000_0011,
+            // See table 78, 0b11 prohibits all forms of medium removal, 0b00 allows it
+            misc_len: if prevent { 0b0000_0011 } else { 0b0000_0000 },
+            control: 0,
+        }),
+        direction: CBWDirection::NonDirectional,
+        data_transfer_len: 0,
+        data_out: None,
+        response_parser: response::no_response,
+    }
+}
+
+/// Shorthand for [`prevent_allow_medium_removal`], allowing the medium to be removed again.
+///
+/// SPC-2 7.12
+pub fn allow_medium_removal() -> CommandBlock<'static> {
+    prevent_allow_medium_removal(false)
+}
+
+/// "The START STOP UNIT command requests that the device server change the power condition of
+/// the logical unit or load or eject the medium."
+///
+/// | `start` | `load_eject` | effect                               |
+/// |---------|--------------|--------------------------------------|
+/// | false   | false        | stop the medium (e.g spin down)      |
+/// | true    | false        | make the medium ready for access     |
+/// | false   | true         | eject the medium, if removable       |
+/// | true    | true         | load the medium, if removable        |
+///
+/// IMMED is set, so the status is returned as soon as the command is accepted, rather than once
+/// the medium has spun up, which can take longer than a command is allowed to. Follow it with
+/// [`SCSIDevice::wait_ready`](super::SCSIDevice::wait_ready) to wait for the unit to become
+/// ready.
+///
+/// SBC-2 5.1.20
+pub fn start_stop_unit(start: bool, load_eject: bool) -> CommandBlock<'static> {
+    CommandBlock {
+        command: Box::new(X6CommandDescriptor {
+            operation_code: OpCode::StartStopUnit,
+            // IMMED (byte 1, bit 0)
+            logical_block_address: [0x01, 0, 0],
+            // POWER CONDITION (bits 7-4) is left as 0, so START and LOEJ are processed
+            misc_len: (u8::from(load_eject) << 1) | u8::from(start),
             control: 0,
         }),
         direction: CBWDirection::NonDirectional,
         data_transfer_len: 0,
+        data_out: None,
+        response_parser: response::no_response,
+    }
+}
+
+/// "The SEND DIAGNOSTIC command requests the device server to perform diagnostic operations
+/// on the SCSI target device, on the logical unit, or on both."
+///
+/// With `self_test` set, the device performs its default self-test, and only returns a
+/// `GOOD` status if it passed. Otherwise the command returns `CHECK CONDITION`, with the sense
+/// key set to `HARDWARE ERROR`.
+///
+/// SPC-2 7.23
+pub fn send_diagnostic(self_test: bool) -> CommandBlock<'static> {
+    CommandBlock {
+        command: Box::new(X6CommandDescriptor {
+            operation_code: OpCode::SendDiagnostic,
+            // SELF-TEST CODE (bits 7-5) is left as 0, as required when SELFTEST (bit 2) is set,
+            // DEVOFFL and UNITOFFL are left unset so the test can't affect other logical units
+            logical_block_address: [u8::from(self_test) << 2, 0, 0],
+            // The PARAMETER LIST LENGTH is split between bytes 3-4, no parameters are sent
+            misc_len: 0,
+            control: 0,
+        }),
+        direction: CBWDirection::NonDirectional,
+        data_transfer_len: 0,
+        data_out: None,
         response_parser: response::no_response,
     }
 }
@@ -163,7 +713,7 @@ pub fn prevent_allow_medium_removal() -> CommandBlock {
 /// to request information regarding the capacity of the block device."
 ///
 /// SBC-2 5.1.10
-pub fn read_capacity() -> CommandBlock {
+pub fn read_capacity() -> CommandBlock<'static> {
     CommandBlock {
         command: Box::new(X10CommandDescriptor {
             operation_code: OpCode::ReadCapacity,
@@ -175,22 +725,155 @@ pub fn read_capacity() -> CommandBlock {
         }),
         direction: CBWDirection::DataIn,
         data_transfer_len: 8,
+        data_out: None,
         response_parser: response::read_capacity,
     }
 }
 
+/// The largest capacity list `READ FORMAT CAPACITIES` is asked for, a header followed by 31
+/// descriptors. This is also the allocation length used by Windows.
+const FORMAT_CAPACITIES_ALLOCATION_LEN: u16 = 0xFC;
+
+/// "The READ FORMAT CAPACITIES command allows the Host to request a list of the possible format
+/// capacities for an installed random-writable media."
+///
+/// Unlike `READ CAPACITY`, this succeeds without media, which it reports as such, making it
+/// useful to check card reader slots.
+///
+/// MMC-5 6.24
+pub fn read_format_capacities() -> CommandBlock<'static> {
+    CommandBlock {
+        command: Box::new(X10CommandDescriptor {
+            operation_code: OpCode::ReadFormatCapacities,
+            service_action: 0,
+            logical_block_address: [0, 0, 0, 0],
+            _reserved: 0,
+            // ALLOCATION LENGTH
+            misc_len: FORMAT_CAPACITIES_ALLOCATION_LEN.to_be_bytes(),
+            control: 0,
+        }),
+        direction: CBWDirection::DataIn,
+        data_transfer_len: u32::from(FORMAT_CAPACITIES_ALLOCATION_LEN),
+        data_out: None,
+        response_parser: response::read_format_capacities,
+    }
+}
+
+/// The largest log page `LOG SENSE` is asked for. Log pages are typically a few dozen bytes, and
+/// some USB bridges reject allocation lengths close to the 64KiB maximum.
+const LOG_SENSE_ALLOCATION_LEN: u16 = 1024;
+
+/// "The LOG SENSE command provides a means for the application client to retrieve statistical
+/// or other operational information maintained by the device about the device or its logical
+/// units. It is a complementary command to the LOG SELECT command."
+///
+/// `page_code` selects which log page is returned, e.g.
+/// [`response::INFORMATIONAL_EXCEPTIONS_LOG_PAGE`]. The cumulative values of each parameter
+/// are requested.
+///
+/// SPC-2 7.5
+pub fn log_sense(page_code: u8) -> CommandBlock<'static> {
+    // PC (bits 7-6) of 01b requests the current cumulative values
+    let page_control: u8 = 0b01;
+    CommandBlock {
+        command: Box::new(X10CommandDescriptor {
+            operation_code: OpCode::LogSense,
+            // PPC and SP are left unset
+            service_action: 0,
+            // PAGE CODE, then the PARAMETER POINTER, which is zero to start from the first
+            // parameter
+            logical_block_address: [page_control << 6 | (page_code & 0x3F), 0, 0, 0],
+            _reserved: 0,
+            // ALLOCATION LENGTH
+            misc_len: LOG_SENSE_ALLOCATION_LEN.to_be_bytes(),
+            control: 0,
+        }),
+        direction: CBWDirection::DataIn,
+        data_transfer_len: u32::from(LOG_SENSE_ALLOCATION_LEN),
+        data_out: None,
+        response_parser: response::log_sense,
+    }
+}
+
+/// "The ATA PASS-THROUGH (16) command provides a method for an application client to transmit
+/// an ATA command to an ATA device."
+///
+/// This reaches the drive behind a USB-SATA bridge directly, e.g to read its real identity with
+/// [`AtaCommand::identify_device`]. The response is returned as is.
+///
+/// SAT-2, `ATA PASS-THROUGH (16)`
+pub fn ata_passthrough16(ata_cmd: AtaCommand) -> CommandBlock<'static> {
+    // BYTE_BLOCK (bit 2) is set and T_TYPE (bit 4) is left unset, so the transfer length is
+    // in 512 byte sectors. T_LENGTH (bits 1-0) is 2, so the length is in the SECTOR_COUNT field.
+    let (flags, direction) = match ata_cmd.protocol {
+        AtaProtocol::NonData => (0, CBWDirection::NonDirectional),
+        // T_DIR (bit 3) is set for transfers from the drive
+        AtaProtocol::PioDataIn => (0b0000_1110, CBWDirection::DataIn),
+    };
+    // The 48-bit LBA is spread across three registers, each holding a byte of the upper and
+    // lower 24 bits
+    let lba = ata_cmd.lba.to_le_bytes();
+    CommandBlock {
+        command: Box::new(AtaPassThrough16CommandDescriptor {
+            operation_code: OpCode::AtaPassThrough16,
+            protocol: ata_cmd.protocol.code() << 1 | u8::from(ata_cmd.extend),
+            flags,
+            features: ata_cmd.features.to_be_bytes(),
+            sector_count: ata_cmd.sector_count.to_be_bytes(),
+            lba_low: [lba[3], lba[0]],
+            lba_mid: [lba[4], lba[1]],
+            lba_high: [lba[5], lba[2]],
+            device: ata_cmd.device,
+            command: ata_cmd.command,
+            control: 0,
+        }),
+        direction,
+        data_transfer_len: ata_cmd.data_in_len(),
+        data_out: None,
+        response_parser: response::raw,
+    }
+}
+
+/// "The `READ CAPACITY (16)` command provides a means for the application client
+/// to request information regarding the capacity of the block device."
+///
+/// Needed for devices with more than `u32::MAX` blocks, which `READ CAPACITY (10)` can't
+/// describe.
+///
+/// SBC-2 5.1.11
+pub fn read_capacity_16() -> CommandBlock<'static> {
+    CommandBlock {
+        command: Box::new(X16CommandDescriptor {
+            operation_code: OpCode::ServiceActionIn16,
+            // SERVICE ACTION, READ CAPACITY (16)
+            misc_info: 0x10,
+            logical_block_address: [0; 8],
+            // ALLOCATION LENGTH
+            param: (response::READ_CAPACITY_16_LEN as u32).to_be_bytes(),
+            // PMI is left unset
+            _reserved: 0,
+            control: 0,
+        }),
+        direction: CBWDirection::DataIn,
+        data_transfer_len: response::READ_CAPACITY_16_LEN as u32,
+        data_out: None,
+        response_parser: response::read_capacity_16,
+    }
+}
+
 /// "The `MODE SENSE(6)` command provides a means for the device server to report parameters
 /// to an application client. It is a complementary command to the MODE SELECT (6) command.
 /// Device servers that implement the MODE SENSE (6) command shall also implement the MODE
 /// SELECT(6) command"
 ///
+/// `page_code` selects which mode page is returned, `0x3F` returns all of them.
+///
 /// SPC-2 7.8
-pub fn mode_sense() -> CommandBlock {
-    let logical_block_address: [u8; 3] = const {
-        // 0 - false. 1 - true
-        let disable_block_descriptors: u8 = 1;
-        [disable_block_descriptors << 3, 0x3F, 0]
-    };
+pub fn mode_sense(page_code: u8) -> CommandBlock<'static> {
+    // 0 - false. 1 - true
+    let disable_block_descriptors: u8 = 1;
+    // PC (bits 7-6) is left as 0, requesting the current values
+    let logical_block_address: [u8; 3] = [disable_block_descriptors << 3, page_code & 0x3F, 0];
     CommandBlock {
         command: Box::new(X6CommandDescriptor {
             operation_code: OpCode::ModeSense,
@@ -200,6 +883,207 @@ pub fn mode_sense() -> CommandBlock {
         }),
         direction: CBWDirection::DataIn,
         data_transfer_len: 192,
+        data_out: None,
         response_parser: response::mode_sense,
     }
 }
+
+/// "The MODE SELECT(6) command provides a means for the application client to specify medium,
+/// logical unit, or peripheral device parameters to the device server."
+///
+/// `parameter_list` is sent during the Data-Out phase, and must start with a mode parameter
+/// header (SPC-2 8.3 table 240), followed by the mode pages to change. Its length must fit
+/// in a byte.
+///
+/// SPC-2 7.6
+pub fn mode_select(parameter_list: &[u8]) -> CommandBlock<'_> {
+    // PF (bit 4) is set, as pages are in the format described by SPC-2, rather than
+    // a vendor specific one. SP (bit 0) is left unset, so changes aren't saved across
+    // power cycles.
+    let page_format: u8 = 1;
+    CommandBlock {
+        command: Box::new(X6CommandDescriptor {
+            operation_code: OpCode::ModeSelect,
+            logical_block_address: [page_format << 4, 0, 0],
+            // PARAMETER LIST LENGTH
+            misc_len: parameter_list.len() as u8,
+            control: 0,
+        }),
+        direction: CBWDirection::DataOut,
+        data_transfer_len: parameter_list.len() as u32,
+        data_out: Some(parameter_list),
+        response_parser: response::no_response,
+    }
+}
+
+/// "The FORMAT UNIT command requests that the device server format the medium into application
+/// client accessible logical blocks as specified in the number of blocks and block length values
+/// received in the last mode parameter block descriptor in a MODE SELECT command."
+///
+/// **Every block on the medium is erased.**
+///
+/// Without a `parameter_list`, the device formats the medium with its default options.
+/// Otherwise `parameter_list` is sent during the Data-Out phase, and must start with a parameter
+/// list header (SBC-2 5.1.2.2 table 11), optionally followed by a defect list in the format
+/// given by the header. If `complete_list` is set, the defect list replaces the device's
+/// existing list of grown defects, rather than adding to it.
+///
+/// SBC-2 5.1.2
+pub fn format_unit(complete_list: bool, parameter_list: Option<&[u8]>) -> CommandBlock<'_> {
+    let parameter_list_len = parameter_list.map_or(0, <[u8]>::len) as u32;
+    // FMTDATA (bit 4) is set if a parameter list is sent, CMPLST (bit 3) only has a meaning
+    // when it is. DEFECT LIST FORMAT (bits 2-0) is left as 0, short block format.
+    let format_data = u8::from(parameter_list.is_some());
+    let complete_list = u8::from(complete_list) & format_data;
+    CommandBlock {
+        command: Box::new(X6CommandDescriptor {
+            operation_code: OpCode::FormatUnit,
+            logical_block_address: [(format_data << 4) | (complete_list << 3), 0, 0],
+            // Bytes 3-4 are obsolete
+            misc_len: 0,
+            control: 0,
+        }),
+        direction: if parameter_list.is_some() {
+            CBWDirection::DataOut
+        } else {
+            CBWDirection::NonDirectional
+        },
+        data_transfer_len: parameter_list_len,
+        data_out: parameter_list,
+        response_parser: response::no_response,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CommandBlock;
+    use crate::scsi::ata::{AtaCommand, AtaProtocol};
+    use crate::scsi::command_descriptor::{OpCode, X16CommandDescriptor};
+    use crate::scsi::response;
+    use crate::usb::cbw::CBWDirection;
+
+    #[test]
+    fn x12_fields_are_big_endian() {
+        let block = super::read_12(0x0102_0304, 0x0A0B_0C0D, 1).unwrap();
+        assert_eq!(
+            block.get()[..12],
+            [0xA8, 0, 1, 2, 3, 4, 0x0A, 0x0B, 0x0C, 0x0D, 0, 0]
+        );
+        // 0x0A0B_0C0D blocks of 512 bytes don't fit in a single transfer
+        assert!(super::read_12(0x0102_0304, 0x0A0B_0C0D, 512).is_err());
+        let data = [0; 512];
+        let block = super::write_12(0x0102_0304, 1, 512, &data).unwrap();
+        assert_eq!(block.get()[..10], [0xAA, 0, 1, 2, 3, 4, 0, 0, 0, 1]);
+        assert!(super::write_12(0x0102_0304, 2, 512, &data).is_err());
+    }
+
+    #[test]
+    fn raw_command_blocks() {
+        // A vendor specific command, as 12 bytes
+        let cdb = [0xC6, 0x01, 0, 0, 0, 0, 0, 0, 0, 0, 0x10, 0];
+        let block = CommandBlock::raw(&cdb, CBWDirection::DataIn, 16, response::raw).unwrap();
+        assert_eq!(block.len(), 12);
+        assert_eq!(block.get()[..12], cdb);
+        assert!(CommandBlock::raw(&[0; 17], CBWDirection::DataIn, 0, response::raw).is_err());
+        assert!(CommandBlock::raw(&[], CBWDirection::DataIn, 0, response::raw).is_err());
+    }
+
+    #[test]
+    fn ata_registers_are_encoded() {
+        let block = super::ata_passthrough16(AtaCommand::smart_read_data());
+        assert_eq!(
+            block.get(),
+            [
+                0x85, 0x08, 0x0E, 0, 0xD0, 0, 1, 0, 0, 0, 0x4F, 0, 0xC2, 0, 0xB0, 0
+            ]
+        );
+        assert_eq!(block.direction, CBWDirection::DataIn);
+        assert_eq!(block.data_transfer_len, 512);
+        // A 48-bit command, with every byte of the LBA set
+        let block = super::ata_passthrough16(AtaCommand {
+            command: 0x24,
+            features: 0,
+            sector_count: 0,
+            lba: 0x0605_0403_0201,
+            device: 0x40,
+            protocol: AtaProtocol::NonData,
+            extend: true,
+        });
+        assert_eq!(
+            block.get(),
+            [0x85, 0x07, 0, 0, 0, 0, 0, 4, 1, 5, 2, 6, 3, 0x40, 0x24, 0]
+        );
+        assert_eq!(block.data_transfer_len, 0);
+    }
+
+    #[test]
+    fn seek_is_non_data() {
+        let block = super::seek10(0x0102_0304);
+        assert_eq!(
+            block.get()[..block.len()],
+            [0x2B, 0, 1, 2, 3, 4, 0, 0, 0, 0]
+        );
+        assert_eq!(block.direction, CBWDirection::NonDirectional);
+        assert_eq!(block.data_transfer_len, 0);
+    }
+
+    #[test]
+    fn read_16_rejects_oversized_transfers() {
+        let block = super::read_16(1 << 32, 0x10, 512).unwrap();
+        assert_eq!(block.data_transfer_len, 0x10 * 512);
+        assert!(super::read_16(1 << 32, 0x0080_0000, 512).is_err());
+    }
+
+    #[test]
+    fn write_16_checks_the_data_length() {
+        let data = [0; 2 * 512];
+        let block = super::write_16(1 << 32, 2, 512, &data).unwrap();
+        assert_eq!(block.data_transfer_len, 2 * 512);
+        assert!(super::write_16(1 << 32, 3, 512, &data).is_err());
+        assert!(super::write_16(1 << 32, 0x0080_0000, 512, &data).is_err());
+    }
+
+    #[test]
+    fn start_stop_unit_returns_immediately() {
+        let block = super::start_stop_unit(true, false);
+        assert_eq!(block.get()[..6], [0x1B, 0x01, 0, 0, 0x01, 0]);
+        assert!(matches!(block.direction, CBWDirection::NonDirectional));
+    }
+
+    #[test]
+    fn write_same_sends_a_single_block() {
+        let pattern = [0xA5; 512];
+        let block = super::write_same10(0x0102_0304, 0x0800, &pattern);
+        assert_eq!(
+            block.get()[..block.len()],
+            [0x41, 0, 1, 2, 3, 4, 0, 0x08, 0x00, 0]
+        );
+        assert_eq!(block.direction, CBWDirection::DataOut);
+        assert_eq!(block.data_transfer_len, 512);
+    }
+
+    #[test]
+    fn x16_fields_are_big_endian() {
+        // The opcode is irrelevant here, only the field layout is being checked
+        let block = CommandBlock {
+            command: Box::new(X16CommandDescriptor {
+                operation_code: OpCode::Read,
+                misc_info: 0,
+                logical_block_address: 0x0102_0304_0506_0708_u64.to_be_bytes(),
+                param: 0x0A0B_0C0D_u32.to_be_bytes(),
+                _reserved: 0,
+                control: 0,
+            }),
+            direction: CBWDirection::DataIn,
+            data_transfer_len: 0,
+            data_out: None,
+            response_parser: response::no_response,
+        };
+        assert_eq!(
+            block.get(),
+            [
+                0x28, 0, 1, 2, 3, 4, 5, 6, 7, 8, 0x0A, 0x0B, 0x0C, 0x0D, 0, 0
+            ]
+        );
+    }
+}
@@ -7,22 +7,100 @@
 /// as needed
 #[repr(u8)]
 #[non_exhaustive]
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum OpCode {
     /// SPC-2 7.25
     TestUnitReady = 0x0,
+    /// SPC-2 7.20
+    RequestSense = 0x03,
+    /// SBC-2 5.1.2
+    FormatUnit = 0x04,
     /// SPC-2 7.3
     Inquiry = 0x12,
     /// SPC-2 7.12
-    PreventAllowMediumRemoval = 0x13,
+    PreventAllowMediumRemoval = 0x1E,
+    /// SPC-2 7.6
+    ModeSelect = 0x15,
     /// SPC-2 7.8.1
     ModeSense = 0x1A,
+    /// SBC-2 5.1.20
+    StartStopUnit = 0x1B,
+    /// SPC-2 7.23
+    SendDiagnostic = 0x1D,
+    /// MMC-5 6.24
+    ReadFormatCapacities = 0x23,
     /// SBC-2 5.1.10, table 27
     ReadCapacity = 0x25,
     /// SBC-2 5.1.7
     Read = 0x28,
     /// SBC-2 5.1.29
     Write = 0x2A,
+    /// Obsolete in SBC-2, see [`seek10`](super::command::seek10)
+    Seek10 = 0x2B,
+    /// SBC-2 5.1.25
+    Verify = 0x2F,
+    /// SBC-2 5.1.3
+    PreFetch = 0x34,
+    /// SBC-2 5.1.18
+    SynchronizeCache = 0x35,
+    /// SBC-2, see [`write_same10`](super::command::write_same10)
+    WriteSame10 = 0x41,
+    /// SPC-2 7.5
+    LogSense = 0x4D,
+    /// SAT-2, `ATA PASS-THROUGH (16)`
+    AtaPassThrough16 = 0x85,
+    /// SBC-2 5.1.9
+    Read16 = 0x88,
+    /// SBC-2 5.1.31
+    Write16 = 0x8A,
+    /// `SERVICE ACTION IN (16)`, the command is selected by the service action,
+    /// see SBC-2 table 13
+    ServiceActionIn16 = 0x9E,
+    /// SPC-2 7.19
+    ReportLuns = 0xA0,
+    /// `MAINTENANCE IN`, the command is selected by the service action,
+    /// see SPC-3 table D.3
+    MaintenanceIn = 0xA3,
+    /// SBC-2 5.1.8
+    Read12 = 0xA8,
+    /// SBC-2 5.1.30
+    Write12 = 0xAA,
+}
+
+impl OpCode {
+    /// Decodes an operation code, returning `None` for operations that aren't modelled.
+    pub fn from_u8(byte: u8) -> Option<Self> {
+        Some(match byte {
+            0x00 => Self::TestUnitReady,
+            0x03 => Self::RequestSense,
+            0x04 => Self::FormatUnit,
+            0x12 => Self::Inquiry,
+            0x1E => Self::PreventAllowMediumRemoval,
+            0x15 => Self::ModeSelect,
+            0x1A => Self::ModeSense,
+            0x1B => Self::StartStopUnit,
+            0x1D => Self::SendDiagnostic,
+            0x23 => Self::ReadFormatCapacities,
+            0x25 => Self::ReadCapacity,
+            0x28 => Self::Read,
+            0x2A => Self::Write,
+            0x2B => Self::Seek10,
+            0x2F => Self::Verify,
+            0x34 => Self::PreFetch,
+            0x35 => Self::SynchronizeCache,
+            0x41 => Self::WriteSame10,
+            0x4D => Self::LogSense,
+            0x85 => Self::AtaPassThrough16,
+            0x88 => Self::Read16,
+            0x8A => Self::Write16,
+            0x9E => Self::ServiceActionIn16,
+            0xA0 => Self::ReportLuns,
+            0xA3 => Self::MaintenanceIn,
+            0xA8 => Self::Read12,
+            0xAA => Self::Write12,
+            _ => return None,
+        })
+    }
 }
 
 /// As described in SPC-2 4.3.2 table 1, a typical CDB for 6 byte commands.
@@ -57,7 +135,18 @@ pub struct X6CommandDescriptor {
     pub control: u8,
 }
 
-impl CommandDescriptor for X6CommandDescriptor {}
+// Each descriptor is copied byte for byte into the CBW, so a stray field or padding would
+// silently produce a malformed CDB
+const _: () = assert!(
+    std::mem::size_of::<X6CommandDescriptor>() == 6,
+    "X6CommandDescriptor not 6 bytes in size"
+);
+
+impl CommandDescriptor for X6CommandDescriptor {
+    fn as_slice(&self) -> &[u8] {
+        packed_bytes(self)
+    }
+}
 
 /// As described in SPC-2 4.3.2 table 2, a typical CDB for 10 byte commands.
 #[repr(C, packed)]
@@ -92,7 +181,60 @@ pub struct X10CommandDescriptor {
     pub control: u8,
 }
 
-impl CommandDescriptor for X10CommandDescriptor {}
+const _: () = assert!(
+    std::mem::size_of::<X10CommandDescriptor>() == 10,
+    "X10CommandDescriptor not 10 bytes in size"
+);
+
+impl CommandDescriptor for X10CommandDescriptor {
+    fn as_slice(&self) -> &[u8] {
+        packed_bytes(self)
+    }
+}
+
+/// As described in SPC-2 4.3.2 table 3, a typical CDB for 12 byte commands.
+#[repr(C, packed)]
+pub struct X12CommandDescriptor {
+    ///"The `OPERATION CODE` field contains the code value identifying the operation
+    /// being requested by the CDB. SAM-2 defines the general structure of the operation
+    /// code value. The `OPERATION CODE` field has a consistently defined meaning across
+    /// all commands. This standard specifies the operation code values used by the commands
+    /// defined herein."
+    ///
+    /// This field specifies what command is being issued by the host
+    /// to the drive.
+    pub operation_code: OpCode,
+    /// (if required) the lower 5 bits identify a function to be performed under the
+    /// more general command specified in the `OPERATION CODE` field
+    pub service_action: u8,
+    /// The use of this field varies from command to command.
+    pub logical_block_address: [u8; 4],
+    /// Depending on the opcode, this field is one of `TRANSFER LENGTH` (amount of
+    /// data to be transferred, usually in blocks),
+    /// `PARAMETER LIST LENGTH` (number of bytes sent from the Data-Out buffer),
+    /// or `ALLOCATION LENGTH` (The maximum number of bytes a client has allocated for returned
+    /// data).
+    ///
+    ///More info can be found in SCSI SPC2 4.3
+    pub misc_len: [u8; 4],
+    pub _reserved: u8,
+    /// "The contents of the `CONTROL` field are defined in SAM-2. The `CONTROL` field
+    /// has a consistently defined meaning across all commands."
+    ///
+    /// As far as I can tell, this value is set to zero by most modern implementations.
+    pub control: u8,
+}
+
+const _: () = assert!(
+    std::mem::size_of::<X12CommandDescriptor>() == 12,
+    "X12CommandDescriptor not 12 bytes in size"
+);
+
+impl CommandDescriptor for X12CommandDescriptor {
+    fn as_slice(&self) -> &[u8] {
+        packed_bytes(self)
+    }
+}
 
 /// "A command is communicated by sending a command descriptor block
 /// to the device ...."
@@ -103,10 +245,6 @@ impl CommandDescriptor for X10CommandDescriptor {}
 /// "SCSI Primary Commands - 2 (SPC-2)" 4.3.2 The fixed length CDB formats
 /// Table 4 -- Typical CDB for 16-byte commands
 #[repr(C, packed)]
-#[expect(
-    unused,
-    reason = "we currently do not use any x16 commands but will likely do so in the future"
-)]
 pub struct X16CommandDescriptor {
     ///"The `OPERATION CODE` field contains the code value identifying the operation
     /// being requested by the CDB. SAM-2 defines the general structure of the operation
@@ -122,7 +260,9 @@ pub struct X16CommandDescriptor {
     /// "The logical block addresses on a logical unit or within a volume partition
     /// shall begin with block zero and be contiguous up to the last logical
     /// block of that logical unit or within that partition."
-    pub logical_block_address: u64,
+    ///
+    /// Like every other multi-byte field in a CDB, this is big endian.
+    pub logical_block_address: [u8; 8],
     /// `TRANSFER_LENGTH` or `PARAMETER_LIST_LENGTH`
     /// or `ALLOCATION LENGTH`
     ///
@@ -168,7 +308,7 @@ pub struct X16CommandDescriptor {
     /// specified in the `ALLOCATION LENGTH` field the device server shall transfer no data
     /// and return a `CHECK CONDITION` status; the sense key shall be set to `ILLEGAL REQUEST`
     /// and the additional sense code shall be set to `INVALID FIELD IN CDB`
-    pub param: u32,
+    pub param: [u8; 4],
     pub _reserved: u8,
     /// "The contents of the `CONTROL` field are defined in SAM-2. The `CONTROL` field
     /// has a consistently defined meaning across all commands."
@@ -177,6 +317,95 @@ pub struct X16CommandDescriptor {
     pub control: u8,
 }
 
-impl CommandDescriptor for X16CommandDescriptor {}
+const _: () = assert!(
+    std::mem::size_of::<X16CommandDescriptor>() == 16,
+    "X16CommandDescriptor not 16 bytes in size"
+);
+
+impl CommandDescriptor for X16CommandDescriptor {
+    fn as_slice(&self) -> &[u8] {
+        packed_bytes(self)
+    }
+}
+
+/// The CDB of `ATA PASS-THROUGH (16)`, which carries the registers of an ATA command, as
+/// described in SCSI / ATA Translation - 2 (SAT-2).
+///
+/// Every ATA register is 16 bits wide, to fit 48-bit commands. For 28-bit commands, only the
+/// lower byte of each register is used.
+#[repr(C, packed)]
+pub struct AtaPassThrough16CommandDescriptor {
+    pub operation_code: OpCode,
+    /// `MULTIPLE_COUNT` (bits 7-5), `PROTOCOL` (bits 4-1) and `EXTEND` (bit 0)
+    pub protocol: u8,
+    /// `OFF_LINE` (bits 7-6), `CK_COND` (bit 5), `T_TYPE` (bit 4), `T_DIR` (bit 3),
+    /// `BYTE_BLOCK` (bit 2) and `T_LENGTH` (bits 1-0)
+    pub flags: u8,
+    pub features: [u8; 2],
+    pub sector_count: [u8; 2],
+    /// `LBA (31:24)` and `LBA (7:0)`
+    pub lba_low: [u8; 2],
+    /// `LBA (39:32)` and `LBA (15:8)`
+    pub lba_mid: [u8; 2],
+    /// `LBA (47:40)` and `LBA (23:16)`
+    pub lba_high: [u8; 2],
+    pub device: u8,
+    /// The ATA command, e.g `ECh` for `IDENTIFY DEVICE`
+    pub command: u8,
+    pub control: u8,
+}
+
+const _: () = assert!(
+    std::mem::size_of::<AtaPassThrough16CommandDescriptor>() == 16,
+    "AtaPassThrough16CommandDescriptor not 16 bytes in size"
+);
+
+impl CommandDescriptor for AtaPassThrough16CommandDescriptor {
+    fn as_slice(&self) -> &[u8] {
+        packed_bytes(self)
+    }
+}
+
+/// A CDB that can be copied into a CBW.
+pub trait CommandDescriptor {
+    /// Returns the CDB exactly as it's sent to the device.
+    fn as_slice(&self) -> &[u8];
+}
 
-pub trait CommandDescriptor {}
+/// Views a fixed length descriptor as the bytes it's made of.
+fn packed_bytes<T: CommandDescriptor>(descriptor: &T) -> &[u8] {
+    // SAFETY: every descriptor is `repr(C, packed)`, and made of only `u8` sized fields, so
+    // there is no padding and every byte is initialized
+    unsafe {
+        std::slice::from_raw_parts(
+            descriptor as *const T as *const u8,
+            std::mem::size_of::<T>(),
+        )
+    }
+}
+
+/// A CDB of any length, for commands that aren't otherwise modelled, see
+/// [`CommandBlock::raw`](super::command::CommandBlock::raw).
+pub struct RawCommandDescriptor(pub Vec<u8>);
+
+impl CommandDescriptor for RawCommandDescriptor {
+    fn as_slice(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::OpCode;
+
+    #[test]
+    fn opcode_round_trip() {
+        for byte in 0..=u8::MAX {
+            if let Some(opcode) = OpCode::from_u8(byte) {
+                assert_eq!(opcode as u8, byte);
+            }
+        }
+        assert_eq!(OpCode::from_u8(0x28), Some(OpCode::Read));
+        assert_eq!(OpCode::from_u8(0xFF), None);
+    }
+}
@@ -0,0 +1,390 @@
+//! Streaming hashes computed over images as they're read, so that an acquisition can be
+//! verified without reading the drive a second time.
+//!
+//! - SHA-256 is described in FIPS 180-4: <https://nvlpubs.nist.gov/nistpubs/FIPS/NIST.FIPS.180-4.pdf>
+//! - MD5 is described in RFC 1321: <https://www.rfc-editor.org/rfc/rfc1321>
+//!
+//! MD5 is broken for adversarial use, and only provided because many chain of custody
+//! workflows still record it next to SHA-256.
+
+/// Both hashes process their input in blocks of this many *bytes*
+const BLOCK_SIZE: usize = 64;
+
+/// Splits a stream of input into blocks, and pads the final block with the message length as
+/// shared by SHA-256 and MD5.
+#[derive(Clone)]
+struct BlockBuffer {
+    buf: [u8; BLOCK_SIZE],
+    /// The number of bytes in `buf` waiting for a full block
+    len: usize,
+    /// The total number of bytes hashed so far
+    total: u64,
+}
+
+impl BlockBuffer {
+    fn new() -> Self {
+        Self {
+            buf: [0; BLOCK_SIZE],
+            len: 0,
+            total: 0,
+        }
+    }
+
+    /// Appends `data`, calling `compress` with each block as it fills up.
+    fn update(&mut self, mut data: &[u8], mut compress: impl FnMut(&[u8; BLOCK_SIZE])) {
+        self.total += data.len() as u64;
+        if self.len > 0 {
+            let take = (BLOCK_SIZE - self.len).min(data.len());
+            self.buf[self.len..self.len + take].copy_from_slice(&data[..take]);
+            self.len += take;
+            data = &data[take..];
+            if self.len < BLOCK_SIZE {
+                return;
+            }
+            compress(&self.buf);
+            self.len = 0;
+        }
+        let mut blocks = data.chunks_exact(BLOCK_SIZE);
+        for block in &mut blocks {
+            compress(block.try_into().unwrap());
+        }
+        let rest = blocks.remainder();
+        self.buf[..rest.len()].copy_from_slice(rest);
+        self.len = rest.len();
+    }
+
+    /// Pads the message with a `1` bit, zeroes, and finally the length of the message in bits,
+    /// encoded by `encode_len`, so that it ends on a block boundary.
+    fn finish(
+        &mut self,
+        encode_len: fn(u64) -> [u8; 8],
+        mut compress: impl FnMut(&[u8; BLOCK_SIZE]),
+    ) {
+        let bit_len = encode_len(self.total.wrapping_mul(8));
+        // The length takes up the last 8 bytes of the final block
+        let zeroes = (BLOCK_SIZE - 8 - 1).wrapping_sub(self.len) % BLOCK_SIZE;
+        let mut padding = [0; BLOCK_SIZE + 8];
+        padding[0] = 0x80;
+        let padding_len = 1 + zeroes;
+        padding[padding_len..padding_len + 8].copy_from_slice(&bit_len);
+        self.update(&padding[..padding_len + 8], &mut compress);
+        debug_assert_eq!(self.len, 0);
+    }
+}
+
+/// The SHA-256 round constants, the fractional parts of the cube roots of the first 64 primes
+const SHA256_K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// A streaming SHA-256 hasher, FIPS 180-4 section 6.2.
+#[derive(Clone)]
+pub struct Sha256 {
+    state: [u32; 8],
+    blocks: BlockBuffer,
+}
+
+impl Default for Sha256 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sha256 {
+    pub fn new() -> Self {
+        Self {
+            // The fractional parts of the square roots of the first 8 primes (section 5.3.3)
+            state: [
+                0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab,
+                0x5be0cd19,
+            ],
+            blocks: BlockBuffer::new(),
+        }
+    }
+
+    /// Hashes `data`, following any data already hashed.
+    pub fn update(&mut self, data: &[u8]) {
+        self.blocks
+            .update(data, |block| sha256_compress(&mut self.state, block));
+    }
+
+    /// Returns the digest of all of the data hashed so far.
+    pub fn finalize(mut self) -> [u8; 32] {
+        self.blocks.finish(u64::to_be_bytes, |block| {
+            sha256_compress(&mut self.state, block)
+        });
+        let mut digest = [0; 32];
+        for (bytes, word) in digest.chunks_exact_mut(4).zip(self.state) {
+            bytes.copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+}
+
+fn sha256_compress(state: &mut [u32; 8], block: &[u8; BLOCK_SIZE]) {
+    let mut w = [0_u32; 64];
+    for (word, bytes) in w.iter_mut().zip(block.chunks_exact(4)) {
+        *word = u32::from_be_bytes(bytes.try_into().unwrap());
+    }
+    for t in 16..64 {
+        let s0 = w[t - 15].rotate_right(7) ^ w[t - 15].rotate_right(18) ^ (w[t - 15] >> 3);
+        let s1 = w[t - 2].rotate_right(17) ^ w[t - 2].rotate_right(19) ^ (w[t - 2] >> 10);
+        w[t] = w[t - 16]
+            .wrapping_add(s0)
+            .wrapping_add(w[t - 7])
+            .wrapping_add(s1);
+    }
+
+    let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = *state;
+    for t in 0..64 {
+        let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+        let ch = (e & f) ^ (!e & g);
+        let t1 = h
+            .wrapping_add(s1)
+            .wrapping_add(ch)
+            .wrapping_add(SHA256_K[t])
+            .wrapping_add(w[t]);
+        let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+        let maj = (a & b) ^ (a & c) ^ (b & c);
+        let t2 = s0.wrapping_add(maj);
+        h = g;
+        g = f;
+        f = e;
+        e = d.wrapping_add(t1);
+        d = c;
+        c = b;
+        b = a;
+        a = t1.wrapping_add(t2);
+    }
+    for (word, value) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+        *word = word.wrapping_add(value);
+    }
+}
+
+/// The MD5 round constants, the integer parts of `abs(sin(i + 1)) * 2^32` (RFC 1321 3.4)
+const MD5_K: [u32; 64] = [
+    0xd76aa478, 0xe8c7b756, 0x242070db, 0xc1bdceee, 0xf57c0faf, 0x4787c62a, 0xa8304613, 0xfd469501,
+    0x698098d8, 0x8b44f7af, 0xffff5bb1, 0x895cd7be, 0x6b901122, 0xfd987193, 0xa679438e, 0x49b40821,
+    0xf61e2562, 0xc040b340, 0x265e5a51, 0xe9b6c7aa, 0xd62f105d, 0x02441453, 0xd8a1e681, 0xe7d3fbc8,
+    0x21e1cde6, 0xc33707d6, 0xf4d50d87, 0x455a14ed, 0xa9e3e905, 0xfcefa3f8, 0x676f02d9, 0x8d2a4c8a,
+    0xfffa3942, 0x8771f681, 0x6d9d6122, 0xfde5380c, 0xa4beea44, 0x4bdecfa9, 0xf6bb4b60, 0xbebfbc70,
+    0x289b7ec6, 0xeaa127fa, 0xd4ef3085, 0x04881d05, 0xd9d4d039, 0xe6db99e5, 0x1fa27cf8, 0xc4ac5665,
+    0xf4292244, 0x432aff97, 0xab9423a7, 0xfc93a039, 0x655b59c3, 0x8f0ccc92, 0xffeff47d, 0x85845dd1,
+    0x6fa87e4f, 0xfe2ce6e0, 0xa3014314, 0x4e0811a1, 0xf7537e82, 0xbd3af235, 0x2ad7d2bb, 0xeb86d391,
+];
+
+/// The left rotation applied in each step of every MD5 round
+const MD5_SHIFTS: [[u32; 4]; 4] = [
+    [7, 12, 17, 22],
+    [5, 9, 14, 20],
+    [4, 11, 16, 23],
+    [6, 10, 15, 21],
+];
+
+/// A streaming MD5 hasher, RFC 1321 section 3.
+#[derive(Clone)]
+pub struct Md5 {
+    state: [u32; 4],
+    blocks: BlockBuffer,
+}
+
+impl Default for Md5 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Md5 {
+    pub fn new() -> Self {
+        Self {
+            // RFC 1321 3.3
+            state: [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476],
+            blocks: BlockBuffer::new(),
+        }
+    }
+
+    /// Hashes `data`, following any data already hashed.
+    pub fn update(&mut self, data: &[u8]) {
+        self.blocks
+            .update(data, |block| md5_compress(&mut self.state, block));
+    }
+
+    /// Returns the digest of all of the data hashed so far.
+    pub fn finalize(mut self) -> [u8; 16] {
+        self.blocks.finish(u64::to_le_bytes, |block| {
+            md5_compress(&mut self.state, block)
+        });
+        let mut digest = [0; 16];
+        for (bytes, word) in digest.chunks_exact_mut(4).zip(self.state) {
+            bytes.copy_from_slice(&word.to_le_bytes());
+        }
+        digest
+    }
+}
+
+fn md5_compress(state: &mut [u32; 4], block: &[u8; BLOCK_SIZE]) {
+    let mut m = [0_u32; 16];
+    for (word, bytes) in m.iter_mut().zip(block.chunks_exact(4)) {
+        *word = u32::from_le_bytes(bytes.try_into().unwrap());
+    }
+
+    let [mut a, mut b, mut c, mut d] = *state;
+    for i in 0..64 {
+        let round = i / 16;
+        let (f, g) = match round {
+            0 => ((b & c) | (!b & d), i),
+            1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+            2 => (b ^ c ^ d, (3 * i + 5) % 16),
+            _ => (c ^ (b | !d), (7 * i) % 16),
+        };
+        let rotated = a
+            .wrapping_add(f)
+            .wrapping_add(MD5_K[i])
+            .wrapping_add(m[g])
+            .rotate_left(MD5_SHIFTS[round][i % 4]);
+        a = d;
+        d = c;
+        c = b;
+        b = b.wrapping_add(rotated);
+    }
+    for (word, value) in state.iter_mut().zip([a, b, c, d]) {
+        *word = word.wrapping_add(value);
+    }
+}
+
+/// Which hashes [`crate::scsi::SCSIDevice::read_image`] computes over an image as it's read.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ImageHashes {
+    pub sha256: bool,
+    pub md5: bool,
+}
+
+impl ImageHashes {
+    /// Computes nothing, for when the image doesn't need to be verified.
+    pub const NONE: Self = Self {
+        sha256: false,
+        md5: false,
+    };
+    /// Computes a SHA-256 of the image.
+    pub const SHA256: Self = Self {
+        sha256: true,
+        md5: false,
+    };
+}
+
+/// The result of reading an image, see [`crate::scsi::SCSIDevice::read_image`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageDigest {
+    /// The total number of bytes read
+    pub bytes: u64,
+    /// The SHA-256 of the image, if it was requested
+    pub sha256: Option<[u8; 32]>,
+    /// The MD5 of the image, if it was requested
+    pub md5: Option<[u8; 16]>,
+}
+
+/// Feeds an image through each of the requested hashes.
+pub(crate) struct ImageHasher {
+    bytes: u64,
+    sha256: Option<Sha256>,
+    md5: Option<Md5>,
+}
+
+impl ImageHasher {
+    pub(crate) fn new(hashes: ImageHashes) -> Self {
+        Self {
+            bytes: 0,
+            sha256: hashes.sha256.then(Sha256::new),
+            md5: hashes.md5.then(Md5::new),
+        }
+    }
+
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        self.bytes += data.len() as u64;
+        if let Some(sha256) = &mut self.sha256 {
+            sha256.update(data);
+        }
+        if let Some(md5) = &mut self.md5 {
+            md5.update(data);
+        }
+    }
+
+    /// Returns the total number of bytes hashed so far.
+    pub(crate) fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    pub(crate) fn finalize(self) -> ImageDigest {
+        ImageDigest {
+            bytes: self.bytes,
+            sha256: self.sha256.map(Sha256::finalize),
+            md5: self.md5.map(Md5::finalize),
+        }
+    }
+}
+
+/// Formats a digest as lowercase hex, the way tools like `sha256sum` print it.
+pub fn to_hex(digest: &[u8]) -> String {
+    digest.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::scsi::digest::{Md5, Sha256, to_hex};
+
+    fn sha256(data: &[u8]) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(data);
+        to_hex(&hasher.finalize())
+    }
+
+    fn md5(data: &[u8]) -> String {
+        let mut hasher = Md5::new();
+        hasher.update(data);
+        to_hex(&hasher.finalize())
+    }
+
+    #[test]
+    fn sha256_test_vectors() {
+        assert_eq!(
+            sha256(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            sha256(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        // Two blocks, once padded
+        assert_eq!(
+            sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+    }
+
+    #[test]
+    fn md5_test_vectors() {
+        assert_eq!(md5(b""), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(md5(b"abc"), "900150983cd24fb0d6963f7d28e17f72");
+        assert_eq!(
+            md5(b"The quick brown fox jumps over the lazy dog"),
+            "9e107d9d372bb6826bd81d3542a419d6"
+        );
+    }
+
+    #[test]
+    fn split_updates_match_a_single_update() {
+        let data: Vec<u8> = (0..1000_u32).map(|i| i as u8).collect();
+        let mut hasher = Sha256::new();
+        for chunk in data.chunks(37) {
+            hasher.update(chunk);
+        }
+        assert_eq!(to_hex(&hasher.finalize()), sha256(&data));
+    }
+}
@@ -0,0 +1,100 @@
+//! Data written over a device by [`SCSIDevice::secure_erase`](super::SCSIDevice::secure_erase).
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+/// What each pass of a secure erase overwrites the device with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErasePattern {
+    /// Every byte is set to `0x00`
+    Zeros,
+    /// Every byte is set to `0xFF`
+    Ones,
+    /// Pseudorandom data, different for every pass
+    Random,
+}
+
+impl ErasePattern {
+    /// The byte every block is filled with, `None` if the blocks differ.
+    pub(crate) fn fill_byte(self) -> Option<u8> {
+        match self {
+            Self::Zeros => Some(0x00),
+            Self::Ones => Some(0xFF),
+            Self::Random => None,
+        }
+    }
+}
+
+/// Generates the data for a single pass of a secure erase.
+///
+/// Random data comes from xorshift64*, which is fast enough to keep up with the device and
+/// reproducible from its seed, so a pass can be verified without keeping the data around.
+/// Overwriting only needs the data to be unrelated to what was there before, it isn't meant to
+/// be cryptographically secure.
+pub(crate) struct PassData {
+    pattern: ErasePattern,
+    seed: u64,
+    state: u64,
+}
+
+impl PassData {
+    /// Starts the data for a new pass, with a fresh seed if the pattern is random.
+    pub(crate) fn new(pattern: ErasePattern) -> Self {
+        Self::with_seed(pattern, RandomState::new().build_hasher().finish())
+    }
+
+    pub(crate) fn with_seed(pattern: ErasePattern, seed: u64) -> Self {
+        // The state of xorshift must never be zero
+        let seed = seed | 1;
+        Self {
+            pattern,
+            seed,
+            state: seed,
+        }
+    }
+
+    /// Returns a generator producing the same data from the start of the pass again.
+    pub(crate) fn restart(&self) -> Self {
+        Self::with_seed(self.pattern, self.seed)
+    }
+
+    /// Fills `buf` with the next bytes of the pass. `buf` must be a multiple of 8 bytes long
+    /// (like every block size), so the data doesn't depend on how the pass is split up.
+    pub(crate) fn fill(&mut self, buf: &mut [u8]) {
+        if let Some(byte) = self.pattern.fill_byte() {
+            buf.fill(byte);
+            return;
+        }
+        debug_assert!(buf.len().is_multiple_of(8));
+        for word in buf.chunks_exact_mut(8) {
+            self.state ^= self.state >> 12;
+            self.state ^= self.state << 25;
+            self.state ^= self.state >> 27;
+            let value = self.state.wrapping_mul(0x2545_F491_4F6C_DD1D);
+            word.copy_from_slice(&value.to_le_bytes());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::scsi::erase::{ErasePattern, PassData};
+
+    #[test]
+    fn random_data_is_reproducible() {
+        let mut data = PassData::with_seed(ErasePattern::Random, 42);
+        let mut whole = vec![0; 1024];
+        data.fill(&mut whole);
+        // The same seed produces the same data, however it's split up
+        let mut data = data.restart();
+        let mut halves = vec![0; 1024];
+        data.fill(&mut halves[..512]);
+        data.fill(&mut halves[512..]);
+        assert_eq!(whole, halves);
+        assert!(whole.iter().any(|byte| *byte != whole[0]));
+
+        let mut ones = vec![0; 512];
+        PassData::with_seed(ErasePattern::Ones, 42).fill(&mut ones);
+        assert!(ones.iter().all(|byte| *byte == 0xFF));
+    }
+}
@@ -0,0 +1,233 @@
+//! Adapters exposing a [`SCSIDevice`] through the standard library's I/O traits, so that
+//! existing crates (filesystem drivers, partition table parsers) can operate on a raw drive,
+//! and as a [`Stream`] of blocks.
+
+use std::io::{self, Read, Seek, SeekFrom};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use eyre::{Result, eyre};
+use futures_core::Stream;
+use tokio::runtime::Handle;
+
+use crate::scsi::SCSIDevice;
+use crate::usb::{USBDrive, UsbTransport};
+
+/// A chunk being read by a [`BlockStream`], which holds on to the device until it completes.
+type ChunkFuture<'a, T> =
+    Pin<Box<dyn Future<Output = (&'a mut SCSIDevice<T>, Result<Vec<u8>>)> + 'a>>;
+
+enum BlockStreamState<'a, T: UsbTransport> {
+    Idle(&'a mut SCSIDevice<T>),
+    Reading(ChunkFuture<'a, T>),
+    Done,
+}
+
+/// A [`Stream`] of contiguous blocks read from a [`SCSIDevice`], in chunks, see
+/// [`SCSIDevice::read_block_stream`].
+///
+/// Only one chunk is read at a time, the next read is started when the stream is next polled.
+/// The stream ends after the first error.
+pub struct BlockStream<'a, T: UsbTransport = USBDrive> {
+    state: BlockStreamState<'a, T>,
+    /// The address of the next block to read
+    lba: u64,
+    /// The address of the block after the last one to read
+    end_lba: u64,
+    /// The number of blocks in each chunk
+    chunk: u64,
+}
+
+impl<'a, T: UsbTransport> BlockStream<'a, T> {
+    pub(crate) fn new(device: &'a mut SCSIDevice<T>, start: u64, count: u64, chunk: u64) -> Self {
+        Self {
+            state: BlockStreamState::Idle(device),
+            lba: start,
+            end_lba: start.saturating_add(count),
+            chunk,
+        }
+    }
+}
+
+impl<'a, T: UsbTransport + 'a> Stream for BlockStream<'a, T> {
+    type Item = Result<Vec<u8>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            match std::mem::replace(&mut this.state, BlockStreamState::Done) {
+                BlockStreamState::Idle(_) if this.lba >= this.end_lba => return Poll::Ready(None),
+                BlockStreamState::Idle(_) if this.chunk == 0 => {
+                    return Poll::Ready(Some(Err(eyre!("chunk size must be at least one block"))));
+                }
+                BlockStreamState::Idle(device) => {
+                    let (lba, count) = (this.lba, this.chunk.min(this.end_lba - this.lba));
+                    this.lba += count;
+                    this.state = BlockStreamState::Reading(Box::pin(async move {
+                        let result = device.read_blocks(lba, count).await;
+                        (device, result)
+                    }));
+                }
+                BlockStreamState::Reading(mut read) => match read.as_mut().poll(cx) {
+                    Poll::Pending => {
+                        this.state = BlockStreamState::Reading(read);
+                        return Poll::Pending;
+                    }
+                    Poll::Ready((device, result)) => {
+                        if result.is_ok() {
+                            this.state = BlockStreamState::Idle(device);
+                        }
+                        return Poll::Ready(Some(result));
+                    }
+                },
+                BlockStreamState::Done => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+/// A byte-oriented reader over a [`SCSIDevice`], implementing [`Read`] and [`Seek`].
+///
+/// Byte offsets are translated into block aligned `READ` commands. Reads that don't start on a
+/// block boundary, or are smaller than a block, go through a single block buffer.
+///
+/// [`Read`] and [`Seek`] are blocking traits, so commands are driven to completion on the Tokio
+/// runtime the reader was created on. This means the reader must *not* be used from an async
+/// context, move it onto a blocking thread with [`tokio::task::spawn_blocking`] first.
+pub struct ScsiBlockReader<T: UsbTransport = USBDrive> {
+    device: SCSIDevice<T>,
+    runtime: Handle,
+    /// The block size of the device in *bytes*
+    block_size: u64,
+    /// The size of the device in *bytes*
+    len: u64,
+    /// The current position in *bytes*
+    position: u64,
+    /// The logical block address of the block held in `buffer`, if any
+    buffered_lba: Option<u64>,
+    buffer: Vec<u8>,
+}
+
+impl<T: UsbTransport> ScsiBlockReader<T> {
+    /// Wraps `device`, querying its capacity to determine the block size and length.
+    ///
+    /// Must be called from within a Tokio runtime, which will be used to issue commands.
+    pub async fn new(mut device: SCSIDevice<T>) -> Result<Self> {
+        let capacity = device.capacity().await?;
+        Ok(Self {
+            device,
+            runtime: Handle::current(),
+            block_size: u64::from(capacity.block_size),
+            len: capacity.total_bytes,
+            position: 0,
+            buffered_lba: None,
+            buffer: Vec::new(),
+        })
+    }
+
+    /// Returns the size of the device in *bytes*.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns `true` if the device has no storage medium.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the underlying device.
+    pub fn into_inner(self) -> SCSIDevice<T> {
+        self.device
+    }
+
+    /// Synchronously reads `count` blocks starting from `lba`.
+    fn read_blocks(&mut self, lba: u64, count: u64) -> io::Result<Vec<u8>> {
+        self.runtime
+            .block_on(self.device.read_blocks(lba, count))
+            .map_err(io::Error::other)
+    }
+}
+
+impl<T: UsbTransport> Read for ScsiBlockReader<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.len.saturating_sub(self.position);
+        let wanted = remaining.min(buf.len() as u64);
+        if wanted == 0 {
+            return Ok(0);
+        }
+        let lba = self.position / self.block_size;
+        let offset = self.position % self.block_size;
+
+        // Aligned reads of at least one block can skip the buffer entirely
+        if offset == 0 && wanted >= self.block_size {
+            let data = self.read_blocks(lba, wanted / self.block_size)?;
+            buf[..data.len()].copy_from_slice(&data);
+            self.position += data.len() as u64;
+            return Ok(data.len());
+        }
+
+        if self.buffered_lba != Some(lba) {
+            self.buffer = self.read_blocks(lba, 1)?;
+            self.buffered_lba = Some(lba);
+        }
+        let len = wanted.min(self.block_size - offset) as usize;
+        let offset = offset as usize;
+        buf[..len].copy_from_slice(&self.buffer[offset..offset + len]);
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+
+impl<T: UsbTransport> Seek for ScsiBlockReader<T> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        self.position = position.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        })?;
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Seek, SeekFrom};
+
+    use crate::scsi::Capacity;
+    use crate::scsi::io::ScsiBlockReader;
+    use crate::scsi::tests::initialized_device;
+    use crate::usb::mock::MockTransport;
+
+    #[tokio::test]
+    async fn byte_offsets_use_the_block_size() {
+        let mut block = vec![0; 4096];
+        block[100..110].copy_from_slice(b"0123456789");
+        // Byte 8292 is 100 bytes into the third 4096 byte block
+        let transport = MockTransport::new().expect(&[0x28, 0, 0, 0, 0, 2, 0, 0, 1, 0], &block);
+        let mut device = initialized_device(transport);
+        device.capacity = Some(Capacity {
+            block_count: 1024,
+            block_size: 4096,
+            total_bytes: 1024 * 4096,
+        });
+        let mut reader = ScsiBlockReader::new(device).await.unwrap();
+        assert_eq!(reader.len(), 1024 * 4096);
+
+        let (reader, buf) = tokio::task::spawn_blocking(move || {
+            reader.seek(SeekFrom::Start(2 * 4096 + 100)).unwrap();
+            let mut buf = [0; 10];
+            reader.read_exact(&mut buf).unwrap();
+            (reader, buf)
+        })
+        .await
+        .unwrap();
+        assert_eq!(&buf, b"0123456789");
+        assert!(reader.into_inner().drive.is_finished());
+    }
+}
@@ -10,122 +10,760 @@
 //!   This is an older version of the SCSI block commands specification. It contains information
 //!   about commands specific to block devices.
 
+pub mod ata;
+mod cancel;
 pub mod command;
 mod command_descriptor;
+pub mod digest;
+mod erase;
+pub mod io;
 pub mod response;
 
+pub use cancel::{CancellationToken, Cancelled};
+pub use command_descriptor::OpCode;
+pub use erase::ErasePattern;
+
 use std::time::Duration;
 
-use color_eyre::{
-    Result,
-    eyre::{Context, ensure},
-};
-use tracing::{debug, info};
+use eyre::{Context, OptionExt, Result, bail, ensure, eyre};
+use futures_core::Stream;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::{debug, info, warn};
 
 use crate::{
     scsi::{
+        ata::{AtaCommand, IdentifyDevice},
         command::CommandBlock,
-        response::{Response, ResponseParser},
+        digest::{ImageDigest, ImageHasher, ImageHashes},
+        erase::PassData,
+        io::BlockStream,
+        response::{
+            FormatCapacities, Inquiry, LogPage, ModePage, ModeParameters, Response, ResponseParser,
+            SenseData, SenseKey, SupportedOpcode, VitalProductData,
+        },
     },
-    usb::USBDrive,
+    usb::{TransportError, USBDrive, UsbTransport, cbw::CswError},
 };
 
+/// The default largest amount of data transferred by a single `READ` or `WRITE` command, in
+/// *bytes*, see [`SCSIDevice::set_max_transfer_size`].
+///
+/// Larger transfers are split into multiple commands.
+const MAX_TRANSFER_SIZE: u32 = 64 * 1024;
+/// The largest transfer size tried by [`SCSIDevice::probe_max_transfer`], in *bytes*
+const MAX_PROBED_TRANSFER_SIZE: u32 = 1024 * 1024;
+
+/// How long a command may take before it's abandoned.
+const COMMAND_TIMEOUT: Duration = Duration::from_millis(5000);
+/// The default timeout of commands that make the device do a lot of work before responding,
+/// see [`SCSIDevice::set_long_command_timeout`].
+const LONG_COMMAND_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// How many times initialization tries to get a successful `TEST UNIT READY` and `INQUIRY`,
+/// before giving up on the drive
+const READY_ATTEMPTS: u32 = 20;
+/// How long to wait between each attempt, see [`READY_ATTEMPTS`]
+const READY_BACKOFF: Duration = Duration::from_millis(250);
+
+/// The `MODE SENSE` page code requesting every mode page the device supports
+pub const ALL_MODE_PAGES: u8 = 0x3F;
+
 /// An abstraction over an underlying USB
 /// mass storage device.
 ///
 /// Commands are defined in the `command` module, and
 /// issued to the device with the `.issue_command` method.
-pub struct SCSIDevice {
-    drive: USBDrive,
-    /// The size of the drive in *blocks*
-    drive_size: u32,
-    /// The block size of the storage medium in *bytes*
-    pub block_size: u32,
+pub struct SCSIDevice<T: UsbTransport = USBDrive> {
+    drive: T,
+    /// The logical unit commands are addressed to
+    lun: u8,
+    /// The capacity of the medium, populated on first use by [`SCSIDevice::capacity`], and
+    /// cleared when the device reports the medium may have changed.
+    capacity: Option<Capacity>,
+    /// The standard INQUIRY data, see [`SCSIDevice::cached_inquiry`]
+    inquiry: Option<Inquiry>,
+    /// See [`SCSIDevice::set_retry_on_medium_change`]
+    retry_on_medium_change: bool,
+    /// See [`SCSIDevice::set_max_transfer_size`]
+    max_transfer_size: u32,
+    /// See [`SCSIDevice::set_cancellation_token`]
+    cancellation: Option<CancellationToken>,
+    /// See [`SCSIDevice::set_long_command_timeout`]
+    long_command_timeout: Duration,
 }
 
-impl SCSIDevice {
-    /// Performs SCSI initialization on the drive,
+impl<T: UsbTransport> SCSIDevice<T> {
+    /// Performs SCSI initialization on logical unit `lun` of the drive,
     /// and returns a new [`SCSIDevice`].
     ///
+    /// Most drives only have a single logical unit, LUN 0. See [`UsbTransport::max_lun`].
+    ///
     /// This initialization sequence follows the order
     /// described here: <https://www.downtowndougbrown.com/2018/12/usb-mass-storage-with-embedded-devices-tips-and-quirks/>.
     /// They are not formally documented anywhere, so the author reverse engineered from various OS implementatations.
-    pub async fn new(mut drive: USBDrive) -> Result<Self> {
+    pub async fn new(drive: T, lun: u8) -> Result<Self> {
         info!("starting device configuration");
-        // 3. Keep trying the sequence of "TEST UNIT READY" followed by "INQUIRY"
-        // until they both return success back-to-back
-        debug!("submitting TEST UNIT READY");
-        drive.submit_cbw(command::test_unit_ready()).await?;
         // At this point it's more convenient to move up a layer of abstraction and finish
         // initialization recursively
         let mut drive = Self {
             drive,
-            // Will be updated later
-            drive_size: 0,
-            block_size: 0,
+            lun,
+            capacity: None,
+            inquiry: None,
+            retry_on_medium_change: false,
+            max_transfer_size: MAX_TRANSFER_SIZE,
+            cancellation: None,
+            long_command_timeout: LONG_COMMAND_TIMEOUT,
         };
-        debug!("submitting INQUIRY");
-        // TODO: actually make something of the response, i.e deserialize into response::InquiryResponse
-        let _response = drive.issue_command(command::inquiry()).await?;
+        // 3. Keep trying the sequence of "TEST UNIT READY" followed by "INQUIRY"
+        // until they both return success back-to-back
+        let mut attempt = 1;
+        loop {
+            match drive.test_unit_ready_and_inquiry().await {
+                Ok(()) => break,
+                // Drives commonly report NOT READY or UNIT ATTENTION for a while after
+                // being plugged in, anything else isn't going to go away by retrying
+                Err(e) if attempt < READY_ATTEMPTS && e.downcast_ref::<ScsiError>().is_some() => {
+                    debug!("drive not ready (attempt {attempt}/{READY_ATTEMPTS}): {e}");
+                    tokio::time::sleep(READY_BACKOFF).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e.wrap_err("drive failed to become ready")),
+            }
+        }
         debug!("submitting PREVENT ALLOW MEDIUM REMOVAL");
         // According to the reference blog post, the result can be ignored, and many
         // drives do not support this command, but it's submitted anyway to mimic other
         // operating systems.
         let _ = drive
-            .issue_command(command::prevent_allow_medium_removal())
+            .issue_command(command::prevent_allow_medium_removal(true))
             .await;
         debug!("submitting READ CAPACITY");
-        let Response::ReadCapacity(drive_size, block_size) = drive
-            .issue_command(command::read_capacity())
-            .await?
+        let capacity = drive.capacity().await?;
+        info!("drive size: {}", capacity.describe());
+        debug!("submitting MODE SENSE");
+        let mode_parameters = drive.mode_sense(ALL_MODE_PAGES).await?;
+        ensure!(
+            !mode_parameters.write_protected,
+            "the drive is flagged as read-only"
+        );
+        // "7. just to be safe, do "TEST UNIT READY" again"
+        debug!("submitting TEST UNIT READY");
+        drive.issue_command(command::test_unit_ready()).await?;
+        info!("device initialization completed");
+        Ok(drive)
+    }
+
+    /// Releases the device, undoing the `PREVENT ALLOW MEDIUM REMOVAL` issued by
+    /// [`SCSIDevice::new`], so that the medium can be ejected afterwards.
+    pub async fn close(mut self) -> Result<()> {
+        debug!("submitting PREVENT ALLOW MEDIUM REMOVAL (allow)");
+        match self.issue_command(command::allow_medium_removal()).await {
+            Ok(_) => Ok(()),
+            // Many drives don't support the command at all, see `SCSIDevice::new`
+            Err(e) if e.downcast_ref::<ScsiError>().is_some() => {
+                debug!("drive rejected PREVENT ALLOW MEDIUM REMOVAL: {e}");
+                Ok(())
+            }
+            Err(e) => Err(e.wrap_err("attempting to allow medium removal")),
+        }
+    }
+
+    /// Polls `TEST UNIT READY` until the logical unit reports it's ready, or `timeout` elapses.
+    ///
+    /// This is needed after the unit is started with [`command::start_stop_unit`], which returns
+    /// before the medium has spun up, as that may take several seconds. Only errors that
+    /// indicate the unit is on its way to becoming ready are waited out, anything else is
+    /// returned immediately.
+    pub async fn wait_ready(&mut self, timeout: Duration) -> Result<()> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            let e = match self.issue_command(command::test_unit_ready()).await {
+                Ok(_) => return Ok(()),
+                Err(e) => e,
+            };
+            let becoming_ready = match e.downcast_ref::<ScsiError>() {
+                // LOGICAL UNIT NOT READY, with any qualifier other than "cause not reportable"
+                // and "manual intervention required" (SPC-2 Annex D)
+                Some(ScsiError::CheckCondition {
+                    sense_key: SenseKey::NotReady,
+                    asc: 0x04,
+                    ascq,
+                }) => !matches!(ascq, 0x00 | 0x03),
+                // Reported once after a reset or medium change, the next command goes through
+                Some(ScsiError::CheckCondition {
+                    sense_key: SenseKey::UnitAttention,
+                    ..
+                }) => true,
+                _ => false,
+            };
+            if !becoming_ready {
+                return Err(e.wrap_err("logical unit cannot become ready"));
+            }
+            if tokio::time::Instant::now() + READY_BACKOFF > deadline {
+                return Err(e.wrap_err(format!(
+                    "logical unit did not become ready within {timeout:?}"
+                )));
+            }
+            debug!("logical unit becoming ready: {e}");
+            tokio::time::sleep(READY_BACKOFF).await;
+        }
+    }
+
+    /// Issues `TEST UNIT READY`, followed by `INQUIRY`, as part of initialization.
+    async fn test_unit_ready_and_inquiry(&mut self) -> Result<()> {
+        debug!("submitting TEST UNIT READY");
+        self.issue_command(command::test_unit_ready()).await?;
+        debug!("submitting INQUIRY");
+        let response = self.issue_command(command::inquiry()).await?;
+        // Some drives fill the identification fields with garbage, which isn't a reason to give
+        // up on them, they just won't have any cached INQUIRY data
+        match response.into_response() {
+            Ok(Response::Inquiry(inquiry)) => self.inquiry = Some(inquiry),
+            Ok(_) => unreachable!(),
+            Err(e) => debug!("failed to parse INQUIRY data: {e}"),
+        }
+        Ok(())
+    }
+
+    /// Returns the standard INQUIRY data of the logical unit, including any data past the first
+    /// 36 bytes.
+    ///
+    /// The first 36 bytes are requested first, then if the device reports having more,
+    /// `INQUIRY` is issued again with the full length.
+    pub async fn inquiry(&mut self) -> Result<Inquiry> {
+        let Response::Inquiry(inquiry) = self
+            .issue_command(command::inquiry())
+            .await
+            .wrap_err("attempting to issue INQUIRY")?
             .into_response()?
         else {
             unreachable!();
         };
-        info!(
-            "drive size: {:.2}GiB, block size: {block_size}B",
-            (u64::from(drive_size) * u64::from(block_size)) / 1024_u64.pow(3)
-        );
-        drive.drive_size = drive_size;
-        drive.block_size = block_size;
-        debug!("submitting MODE SENSE");
-        let Response::ModeSense(read_only) = drive
-            .issue_command(command::mode_sense())
-            .await?
+        if !inquiry.is_truncated() {
+            self.inquiry = Some(inquiry.clone());
+            return Ok(inquiry);
+        }
+
+        let full_len = u8::try_from(inquiry.full_len()).unwrap_or(u8::MAX);
+        debug!("device has {full_len} bytes of INQUIRY data, requesting all of it");
+        let Response::Inquiry(inquiry) = self
+            .issue_command(command::inquiry_with_length(full_len))
+            .await
+            .wrap_err("attempting to issue INQUIRY")?
             .into_response()?
         else {
-            unreachable!()
+            unreachable!();
         };
-        ensure!(!read_only, "the drive is flagged as read-only");
-        // "7. just to be safe, do "TEST UNIT READY" again"
-        debug!("submitting TEST UNIT READY");
-        drive.issue_command(command::test_unit_ready()).await?;
-        info!("device initialization completed");
-        Ok(drive)
+        self.inquiry = Some(inquiry.clone());
+        Ok(inquiry)
+    }
+
+    /// Returns the standard INQUIRY data from when the device was initialized, or from the
+    /// latest call to [`SCSIDevice::inquiry`], without issuing any commands.
+    ///
+    /// `None` is returned if the device's INQUIRY data couldn't be parsed.
+    pub fn cached_inquiry(&self) -> Option<&Inquiry> {
+        self.inquiry.as_ref()
     }
 
     /// Issues a command to the device.
     ///
     /// This function will submit the command to the device, and wait for the
     /// response.
-    pub async fn issue_command(&mut self, command: CommandBlock) -> Result<ResponseBytes> {
+    ///
+    /// If the device reports a CHECK CONDITION, the sense data is retrieved with
+    /// `REQUEST SENSE`, and returned as a [`ScsiError::CheckCondition`].
+    ///
+    /// If the medium may have changed (a `UNIT ATTENTION`), the cached capacity is discarded,
+    /// and the command is retried once if enabled with
+    /// [`SCSIDevice::set_retry_on_medium_change`].
+    pub async fn issue_command(&mut self, command: CommandBlock<'_>) -> Result<ResponseBytes> {
+        self.issue_command_with_timeout(command, COMMAND_TIMEOUT)
+            .await
+    }
+
+    /// Issues a command like [`SCSIDevice::issue_command`], abandoning it if the device
+    /// doesn't respond within `timeout` instead of the default of 5 seconds.
+    ///
+    /// This is meant for commands that only complete once the device has done a lot of work.
+    pub async fn issue_command_with_timeout(
+        &mut self,
+        command: CommandBlock<'_>,
+        timeout: Duration,
+    ) -> Result<ResponseBytes> {
+        let retry = self.retry_on_medium_change.then(|| command.clone());
+        match (self.issue_command_once(command, timeout).await, retry) {
+            (Err(e), Some(retry))
+                if e.downcast_ref::<ScsiError>()
+                    .is_some_and(ScsiError::is_medium_change) =>
+            {
+                debug!("retrying command after a medium change");
+                self.issue_command_once(retry, timeout).await
+            }
+            (result, _) => result,
+        }
+    }
+
+    /// Issues a command like [`SCSIDevice::issue_command`], retrying failures that `policy`
+    /// considers transient, with exponential backoff between attempts.
+    ///
+    /// The last error is returned once `policy.max_attempts` is exhausted.
+    pub async fn issue_command_retrying(
+        &mut self,
+        command: CommandBlock<'_>,
+        policy: &RetryPolicy,
+    ) -> Result<ResponseBytes> {
+        let mut backoff = policy.initial_backoff;
+        let mut attempt = 1;
+        loop {
+            let e = match self.issue_command(command.clone()).await {
+                Ok(response) => return Ok(response),
+                Err(e) => e,
+            };
+            if attempt >= policy.max_attempts || !policy.is_retryable(&e) {
+                return Err(e.wrap_err(format!("command failed after {attempt} attempt(s)")));
+            }
+            debug!("attempt {attempt} failed, retrying in {backoff:?}: {e}");
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(policy.max_backoff);
+            attempt += 1;
+        }
+    }
+
+    /// Sets whether commands are retried once when they fail because the medium may have
+    /// changed, which is reported on the first command after e.g a card is swapped. Off by
+    /// default.
+    ///
+    /// Only enable this if every command is valid for any medium, as a retried write will
+    /// land on the new medium.
+    pub fn set_retry_on_medium_change(&mut self, retry: bool) {
+        self.retry_on_medium_change = retry;
+    }
+
+    /// Sets the largest amount of data transferred by a single `READ` or `WRITE` command issued
+    /// by chunked reads and writes like [`SCSIDevice::read_blocks`], in *bytes*. Defaults to
+    /// 64KiB.
+    ///
+    /// Some devices stall on transfers larger than they can handle, see
+    /// [`SCSIDevice::probe_max_transfer`].
+    pub fn set_max_transfer_size(&mut self, max_transfer_size: u32) {
+        self.max_transfer_size = max_transfer_size;
+    }
+
+    /// Finds the largest `READ (10)` the device handles, by reading from the start of the medium
+    /// with transfers of one block, then doubling the size until a read fails or 1MiB is
+    /// reached.
+    ///
+    /// The largest successful transfer size, in *bytes*, is returned, and used for subsequent
+    /// chunked reads and writes, see [`SCSIDevice::set_max_transfer_size`].
+    pub async fn probe_max_transfer(&mut self) -> Result<u32> {
+        let Capacity {
+            block_count,
+            block_size,
+            ..
+        } = self.capacity().await?;
+        let max_blocks = u64::from(MAX_PROBED_TRANSFER_SIZE / block_size)
+            .min(block_count)
+            .min(u64::from(u16::MAX)) as u16;
+        let mut largest = None;
+        let mut len: u16 = 1;
+        loop {
+            let transfer_size = u32::from(len) * block_size;
+            match self.issue_command(command::read(0, len, block_size)).await {
+                Ok(response) if response.raw().len() == transfer_size as usize => {
+                    largest = Some(transfer_size);
+                }
+                Ok(response) => {
+                    debug!(
+                        "{transfer_size}B read returned {}B, stopping probe",
+                        response.raw().len()
+                    );
+                    break;
+                }
+                Err(e) => {
+                    debug!("{transfer_size}B read failed, stopping probe: {e}");
+                    break;
+                }
+            }
+            if len >= max_blocks {
+                break;
+            }
+            len = len.saturating_mul(2).min(max_blocks);
+        }
+        let largest = largest.ok_or_eyre("the device failed to read a single block")?;
+        info!("largest transfer size: {largest}B");
+        self.max_transfer_size = largest;
+        Ok(largest)
+    }
+
+    /// Attaches a token that aborts commands when cancelled, or detaches it with `None`.
+    ///
+    /// Once `token` is cancelled, the command in flight is torn down and the transport is reset
+    /// (see [`UsbTransport::abort_recovery`]), and every command fails with [`Cancelled`]
+    /// until a new token is attached. This stops long operations like
+    /// [`SCSIDevice::write_image`] part way through, leaving the medium partially written.
+    pub fn set_cancellation_token(&mut self, token: Option<CancellationToken>) {
+        self.cancellation = token;
+    }
+
+    /// Submits a command to the transport, giving up once `timeout` elapses or the command is
+    /// cancelled.
+    ///
+    /// A command that's given up on, or that the transport timed out, may still have transfers
+    /// queued on the bulk endpoints, so the transport is always recovered with
+    /// [`UsbTransport::abort_recovery`] before returning, to keep the next command from
+    /// picking up its data or CSW.
+    async fn submit(&mut self, command: CommandBlock<'_>, timeout: Duration) -> Result<Vec<u8>> {
+        if self
+            .cancellation
+            .as_ref()
+            .is_some_and(CancellationToken::is_cancelled)
+        {
+            bail!(Cancelled);
+        }
+        let submit = tokio::time::timeout(timeout, self.drive.submit_cbw(self.lun, command));
+        let outcome = match &self.cancellation {
+            Some(token) => tokio::select! {
+                result = submit => Some(result),
+                () = token.cancelled() => None,
+            },
+            None => Some(submit.await),
+        };
+        let error = match outcome {
+            // The transport timed out a transfer part way through the command
+            Some(Ok(Err(e))) if e.downcast_ref() == Some(&TransportError::Timeout) => e,
+            Some(Ok(result)) => return result,
+            Some(Err(_)) => eyre!(TransportError::Timeout),
+            None => eyre!(Cancelled),
+        };
+        debug!("abandoned a command ({error}), recovering the transport");
+        self.drive
+            .abort_recovery()
+            .await
+            .wrap_err("failed to recover from an abandoned command")?;
+        Err(error)
+    }
+
+    /// Issues a command once, see [`SCSIDevice::issue_command`].
+    async fn issue_command_once(
+        &mut self,
+        command: CommandBlock<'_>,
+        timeout: Duration,
+    ) -> Result<ResponseBytes> {
         let parser = command.response_parser;
-        let response_bytes =
-            tokio::time::timeout(Duration::from_millis(5000), self.drive.submit_cbw(command))
-                .await
-                .context("drive failed to respond by timeout")??;
+        let result = self.submit(command, timeout).await;
+        let response_bytes = match result {
+            Ok(response_bytes) => response_bytes,
+            Err(e) if e.downcast_ref::<CswError>() == Some(&CswError::Failed) => {
+                let sense = self
+                    .request_sense()
+                    .await
+                    .wrap_err("attempting to retrieve sense data for a CHECK CONDITION")?;
+                let error = ScsiError::CheckCondition {
+                    sense_key: sense.sense_key,
+                    asc: sense.additional_sense_code,
+                    ascq: sense.additional_sense_code_qualifier,
+                };
+                if error.is_medium_change() {
+                    debug!("medium may have changed, discarding the cached capacity");
+                    self.capacity = None;
+                }
+                bail!(error);
+            }
+            Err(e) => return Err(e),
+        };
         Ok(ResponseBytes {
             bytes: response_bytes,
             parser,
         })
     }
 
+    /// Sets how long commands that only complete once the device has done a lot of work, like
+    /// [`SCSIDevice::self_test`], may take before they're abandoned. Defaults to 5 minutes.
+    pub fn set_long_command_timeout(&mut self, timeout: Duration) {
+        self.long_command_timeout = timeout;
+    }
+
+    /// Retrieves the sense data describing why the last command failed with `REQUEST SENSE`.
+    ///
+    /// [`SCSIDevice::issue_command`] already does this when a command fails, so this
+    /// is rarely needed.
+    pub async fn request_sense(&mut self) -> Result<SenseData> {
+        // Issued directly, as a CHECK CONDITION here must not trigger another REQUEST SENSE
+        let command = command::request_sense();
+        let parser = command.response_parser;
+        let response_bytes = self.submit(command, COMMAND_TIMEOUT).await?;
+        let Response::RequestSense(sense) = parser(&response_bytes)? else {
+            unreachable!();
+        };
+        Ok(sense)
+    }
+
+    /// Retrieves a vital product data page with `INQUIRY`.
+    pub async fn inquiry_vpd(&mut self, page_code: u8) -> Result<VitalProductData> {
+        let Response::VitalProductData(page) = self
+            .issue_command(command::inquiry_vpd(page_code))
+            .await
+            .wrap_err_with(|| format!("attempting to issue INQUIRY for VPD page {page_code:#04X}"))?
+            .into_response()?
+        else {
+            unreachable!();
+        };
+        Ok(page)
+    }
+
+    /// Retrieves the serial number reported by the SCSI device, from the Unit Serial Number
+    /// VPD page.
+    pub async fn unit_serial_number(&mut self) -> Result<String> {
+        self.inquiry_vpd(response::UNIT_SERIAL_NUMBER_VPD_PAGE)
+            .await?
+            .unit_serial_number()
+            .ok_or_eyre("device returned the wrong VPD page")
+    }
+
+    /// Reads the identity of the drive behind a USB-SATA bridge, with `IDENTIFY DEVICE` sent
+    /// through `ATA PASS-THROUGH (16)`.
+    ///
+    /// Fails if the bridge doesn't support ATA pass-through, which includes every USB flash
+    /// drive.
+    pub async fn ata_identify(&mut self) -> Result<IdentifyDevice> {
+        let response = self
+            .issue_command(command::ata_passthrough16(AtaCommand::identify_device()))
+            .await
+            .wrap_err("attempting to issue IDENTIFY DEVICE through ATA PASS-THROUGH")?;
+        IdentifyDevice::parse(response.raw())
+    }
+
+    /// Lists the logical units present on the device with `REPORT LUNS`.
+    ///
+    /// This is sent to the logical unit the device was opened with, but describes every
+    /// logical unit of the device.
+    pub async fn report_luns(&mut self) -> Result<Vec<u16>> {
+        let Response::ReportLuns(luns) = self
+            .issue_command(command::report_luns())
+            .await
+            .wrap_err("attempting to issue REPORT LUNS")?
+            .into_response()?
+        else {
+            unreachable!();
+        };
+        Ok(luns)
+    }
+
+    /// Lists the commands supported by the logical unit with
+    /// `REPORT SUPPORTED OPERATION CODES`.
+    ///
+    /// Useful to check whether a command like `READ (16)` is supported before relying on it.
+    /// Many devices don't support this command, in which case a [`ScsiError`] is returned.
+    pub async fn supported_opcodes(&mut self) -> Result<Vec<SupportedOpcode>> {
+        let Response::SupportedOpcodes(opcodes) = self
+            .issue_command(command::report_supported_opcodes())
+            .await
+            .wrap_err("attempting to issue REPORT SUPPORTED OPERATION CODES")?
+            .into_response()?
+        else {
+            unreachable!();
+        };
+        Ok(opcodes)
+    }
+
+    /// Returns the capacity of the device.
+    ///
+    /// The capacity is queried with `READ CAPACITY` the first time, and cached until the device
+    /// reports that the medium may have changed.
+    pub async fn capacity(&mut self) -> Result<Capacity> {
+        if let Some(capacity) = self.capacity {
+            return Ok(capacity);
+        }
+        let capacity = self.read_capacity().await?;
+        self.capacity = Some(capacity);
+        Ok(capacity)
+    }
+
+    /// Returns the block size of the medium in *bytes*, if the capacity is cached.
+    pub fn cached_block_size(&self) -> Option<u32> {
+        self.capacity.map(|capacity| capacity.block_size)
+    }
+
+    /// Queries the capacity list with `READ FORMAT CAPACITIES`.
+    ///
+    /// Unlike [`SCSIDevice::capacity`], this works without media, so it can be used to check
+    /// whether a card reader slot has a card in it, see [`FormatCapacities::has_media`].
+    pub async fn read_format_capacities(&mut self) -> Result<FormatCapacities> {
+        let Response::FormatCapacities(capacities) = self
+            .issue_command(command::read_format_capacities())
+            .await
+            .wrap_err("attempting to issue READ FORMAT CAPACITIES")?
+            .into_response()?
+        else {
+            unreachable!();
+        };
+        Ok(capacities)
+    }
+
+    /// Queries the capacity of the device with `READ CAPACITY`.
+    ///
+    /// Falls back to `READ CAPACITY (16)` for devices too large to describe
+    /// with `READ CAPACITY (10)`.
+    async fn read_capacity(&mut self) -> Result<Capacity> {
+        let Response::ReadCapacity(capacity) = self
+            .issue_command(command::read_capacity())
+            .await
+            .wrap_err("attempting to issue READ CAPACITY")?
+            .into_response()?
+        else {
+            unreachable!();
+        };
+        // "If the number of logical blocks exceeds the maximum value that is able to be specified
+        // in the RETURNED LOGICAL BLOCK ADDRESS field, the device server shall set the RETURNED
+        // LOGICAL BLOCK ADDRESS field to FFFFFFFFh" (SBC-2 5.1.10.2)
+        let capacity = if capacity.last_lba() == u32::MAX {
+            debug!("device too large for READ CAPACITY (10), submitting READ CAPACITY (16)");
+            let Response::ReadCapacity16(capacity) = self
+                .issue_command(command::read_capacity_16())
+                .await
+                .wrap_err("attempting to issue READ CAPACITY (16)")?
+                .into_response()?
+            else {
+                unreachable!();
+            };
+            Capacity {
+                block_count: capacity.block_count(),
+                block_size: capacity.block_size(),
+                total_bytes: capacity.total_bytes(),
+            }
+        } else {
+            Capacity {
+                block_count: capacity.block_count(),
+                block_size: capacity.block_size(),
+                total_bytes: capacity.total_bytes(),
+            }
+        };
+        // Every transfer is sized in blocks, so this would otherwise be divided by later on
+        ensure!(
+            capacity.block_size != 0,
+            "device reported a block length of 0 bytes"
+        );
+        Ok(capacity)
+    }
+
+    /// Queries the mode parameters of the device with `MODE SENSE`.
+    ///
+    /// `page_code` selects which mode page is returned, see [`ALL_MODE_PAGES`] and
+    /// [`response::CACHING_MODE_PAGE`].
+    pub async fn mode_sense(&mut self, page_code: u8) -> Result<ModeParameters> {
+        let Response::ModeSense(mode_parameters) = self
+            .issue_command(command::mode_sense(page_code))
+            .await
+            .wrap_err("attempting to issue MODE SENSE")?
+            .into_response()?
+        else {
+            unreachable!();
+        };
+        Ok(mode_parameters)
+    }
+
+    /// Reads a log page from the device with `LOG SENSE`.
+    ///
+    /// Useful pages include [`response::INFORMATIONAL_EXCEPTIONS_LOG_PAGE`], for failure
+    /// predictions and the drive temperature, and [`response::TEMPERATURE_LOG_PAGE`]. Most
+    /// USB flash drives don't support any log pages, and fail with `ILLEGAL REQUEST`.
+    pub async fn log_sense(&mut self, page_code: u8) -> Result<LogPage> {
+        let Response::LogSense(page) = self
+            .issue_command(command::log_sense(page_code))
+            .await
+            .wrap_err("attempting to issue LOG SENSE")?
+            .into_response()?
+        else {
+            unreachable!();
+        };
+        Ok(page)
+    }
+
+    /// Changes the mode parameters of the device with `MODE SELECT`.
+    ///
+    /// `pages` are usually obtained from [`SCSIDevice::mode_sense`], then modified. Changes are
+    /// not saved, and revert when the device is power cycled.
+    pub async fn mode_select(&mut self, pages: &[ModePage]) -> Result<()> {
+        // Mode parameter header (SPC-2 8.3 table 240). MODE DATA LENGTH is reserved for
+        // MODE SELECT, and no block descriptors are sent
+        let mut parameter_list = vec![0, 0, 0, 0];
+        for page in pages {
+            page.encode(&mut parameter_list);
+        }
+        ensure!(
+            parameter_list.len() <= usize::from(u8::MAX),
+            "mode parameter list too long for MODE SELECT (6), was {} bytes",
+            parameter_list.len()
+        );
+        self.issue_command(command::mode_select(&parameter_list))
+            .await
+            .wrap_err("attempting to issue MODE SELECT")?;
+
+        Ok(())
+    }
+
+    /// Low level formats the medium with `FORMAT UNIT`, using the device's default options.
+    ///
+    /// **This erases every block on the device.** As a safeguard, nothing is sent unless
+    /// `force` is set. Formatting can take minutes, so the command returns as soon as it's
+    /// accepted, and the device is then polled until it's ready again, for up to the timeout set
+    /// with [`SCSIDevice::set_long_command_timeout`]. The capacity is queried again afterwards,
+    /// as it may have changed.
+    pub async fn format_unit(&mut self, force: bool) -> Result<()> {
+        ensure!(
+            force,
+            "FORMAT UNIT erases the entire device, `force` must be set to proceed"
+        );
+        info!("formatting the device, this may take a while");
+        // Short parameter list header (SBC-2 5.1.2.2 table 11) with only IMMED (byte 1, bit 1)
+        // set, so the status is returned before the format completes. Leaving FOV unset keeps
+        // the device's default options, and no defect list follows.
+        let parameter_list = [0, 0x02, 0, 0];
+        self.issue_command(command::format_unit(false, Some(&parameter_list)))
+            .await
+            .wrap_err("attempting to issue FORMAT UNIT")?;
+        self.capacity = None;
+        // The device reports LOGICAL UNIT NOT READY, FORMAT IN PROGRESS until it's done
+        self.wait_ready(self.long_command_timeout)
+            .await
+            .wrap_err("waiting for FORMAT UNIT to complete")?;
+
+        Ok(())
+    }
+
+    /// Enables or disables the device's volatile write cache, by setting the `WCE` bit of the
+    /// Caching mode page.
+    ///
+    /// With the write cache disabled, every `WRITE` has reached the medium by the time it
+    /// completes.
+    pub async fn set_write_cache(&mut self, enabled: bool) -> Result<()> {
+        let mode_parameters = self.mode_sense(response::CACHING_MODE_PAGE).await?;
+        let mut page = mode_parameters
+            .page(response::CACHING_MODE_PAGE)
+            .ok_or_eyre("device did not return the Caching mode page")?
+            .clone();
+        let flags = page
+            .data
+            .first_mut()
+            .ok_or_eyre("Caching mode page is empty")?;
+        // WCE
+        if enabled {
+            *flags |= 0b0000_0100;
+        } else {
+            *flags &= !0b0000_0100;
+        }
+        self.mode_select(&[page]).await
+    }
+
     /// A higher level wrapper over the SCSI `READ` command.
     ///
     /// Reads `len` contiguous blocks, starting from `logical_block_address`.
     pub async fn read(&mut self, logical_block_address: u32, len: u16) -> Result<Vec<u8>> {
+        let block_size = self.capacity().await?.block_size;
         let response = self
-            .issue_command(command::read(logical_block_address, len, self.block_size))
+            .issue_command(command::read(logical_block_address, len, block_size))
             .await
             .wrap_err("attempting to issue READ")?
             .raw()
@@ -133,6 +771,580 @@ impl SCSIDevice {
 
         Ok(response)
     }
+
+    /// Reads `count` contiguous blocks, starting from `start_lba`.
+    ///
+    /// Unlike [`SCSIDevice::read`], reads of any size are supported, they're split into as many
+    /// `READ` commands as needed. `READ (16)` is used for blocks past the reach of `READ (10)`.
+    pub async fn read_blocks(&mut self, start_lba: u64, count: u64) -> Result<Vec<u8>> {
+        let Capacity {
+            block_count,
+            block_size,
+            ..
+        } = self.capacity().await?;
+        let end_lba = start_lba
+            .checked_add(count)
+            .filter(|end_lba| *end_lba <= block_count)
+            .ok_or_eyre("attempted to read past the end of the drive")?;
+        let blocks_per_command = blocks_per_command(self.max_transfer_size, block_size);
+
+        let mut output = Vec::with_capacity((count * u64::from(block_size)) as usize);
+        let mut lba = start_lba;
+        while lba < end_lba {
+            let len = (end_lba - lba).min(blocks_per_command) as u16;
+            let command = match u32::try_from(lba) {
+                Ok(lba) => command::read(lba, len, block_size),
+                Err(_) => command::read_16(lba, u32::from(len), block_size)?,
+            };
+            let response = self
+                .issue_command(command)
+                .await
+                .wrap_err_with(|| format!("attempting to read {len} blocks at LBA {lba}"))?;
+            let expected = usize::from(len) * block_size as usize;
+            if response.raw().len() != expected {
+                return Err(eyre!(TransportError::ShortTransfer {
+                    got: response.raw().len(),
+                    expected,
+                })
+                .wrap_err(format!("reading {len} blocks at LBA {lba}")));
+            }
+            output.extend_from_slice(response.raw());
+            lba += u64::from(len);
+        }
+
+        Ok(output)
+    }
+
+    /// Reads `count` contiguous blocks starting from `start`, as a stream of chunks of up to
+    /// `chunk` blocks each.
+    ///
+    /// Unlike [`SCSIDevice::read_blocks`], each chunk can be processed as soon as it arrives,
+    /// rather than buffering the whole read in memory.
+    pub fn read_block_stream(
+        &mut self,
+        start: u64,
+        count: u64,
+        chunk: u64,
+    ) -> impl Stream<Item = Result<Vec<u8>>> + '_ {
+        BlockStream::new(self, start, count, chunk)
+    }
+
+    /// Writes `data` to contiguous blocks, starting from `start_lba`. The length of `data` must be
+    /// a multiple of the block size.
+    ///
+    /// Unlike [`SCSIDevice::write`], writes of any size are supported, they're split into as
+    /// many `WRITE` commands as needed. `WRITE (16)` is used for blocks past the reach of
+    /// `WRITE (10)`.
+    ///
+    /// If `flush` is set, the device's write cache is flushed once every block has been written,
+    /// see [`SCSIDevice::synchronize_cache`].
+    pub async fn write_blocks(&mut self, start_lba: u64, data: &[u8], flush: bool) -> Result<()> {
+        let Capacity {
+            block_count,
+            block_size,
+            ..
+        } = self.capacity().await?;
+        let block_len = block_size as usize;
+        let chunk_size =
+            blocks_per_command(self.max_transfer_size, block_size) as usize * block_len;
+        ensure!(
+            data.len().is_multiple_of(block_len),
+            "data length must be a multiple of the block size ({block_size}B), was {}",
+            data.len()
+        );
+        start_lba
+            .checked_add((data.len() / block_len) as u64)
+            .filter(|end_lba| *end_lba <= block_count)
+            .ok_or_eyre("attempted to write past the end of the drive")?;
+
+        let mut lba = start_lba;
+        for chunk in data.chunks(chunk_size) {
+            let len = (chunk.len() / block_len) as u16;
+            let command = match u32::try_from(lba) {
+                Ok(lba) => command::write(lba, len, chunk),
+                Err(_) => command::write_16(lba, u32::from(len), block_size, chunk)?,
+            };
+            self.issue_command(command)
+                .await
+                .wrap_err_with(|| format!("attempting to write {len} blocks at LBA {lba}"))?;
+            lba += u64::from(len);
+        }
+
+        if flush {
+            self.synchronize_cache().await?;
+        }
+
+        Ok(())
+    }
+
+    /// A higher level wrapper over the SCSI `VERIFY` command.
+    ///
+    /// Checks that `block_count` contiguous blocks starting from `logical_block_address` can be
+    /// read from the medium. If `compare` is provided, the blocks are also compared against it
+    /// on the device, without transferring them back to the host. The length of `compare`
+    /// must be `block_count` blocks.
+    pub async fn verify(
+        &mut self,
+        logical_block_address: u32,
+        block_count: u16,
+        compare: Option<&[u8]>,
+    ) -> Result<()> {
+        if let Some(data) = compare {
+            let block_size = self.capacity().await?.block_size;
+            let expected_len = usize::from(block_count) * block_size as usize;
+            ensure!(
+                data.len() == expected_len,
+                "comparison data should be {expected_len} bytes, was {}",
+                data.len()
+            );
+        }
+        self.issue_command(command::verify(logical_block_address, block_count, compare))
+            .await
+            .wrap_err("attempting to issue VERIFY")?;
+
+        Ok(())
+    }
+
+    /// Overwrites every block of the device `passes` times with `pattern`, then optionally
+    /// reads the device back to check that every block holds the data of the last pass.
+    ///
+    /// Fixed patterns are written with `WRITE SAME (10)`, which sends a single block per command
+    /// instead of the whole range. Devices that don't implement it (reported as an ILLEGAL
+    /// REQUEST) are written with `WRITE (10)`/`WRITE (16)` instead, as is random data. The write
+    /// cache is flushed after each pass.
+    ///
+    /// Flash devices remap blocks behind the host's back, so blocks that were retired by the
+    /// controller may keep old data regardless of how many passes are made.
+    pub async fn secure_erase(
+        &mut self,
+        passes: u8,
+        pattern: ErasePattern,
+        verify: bool,
+    ) -> Result<()> {
+        ensure!(passes > 0, "a secure erase needs at least one pass");
+        let mut write_same = pattern.fill_byte().is_some();
+        let mut last_pass = None;
+        for pass in 1..=passes {
+            info!("secure erase pass {pass}/{passes}, writing {pattern:?}");
+            let data = PassData::new(pattern);
+            self.erase_pass(&mut data.restart(), &mut write_same)
+                .await
+                .wrap_err_with(|| format!("secure erase pass {pass} failed"))?;
+            last_pass = Some(data);
+        }
+        if verify && let Some(data) = last_pass {
+            info!("verifying secure erase");
+            self.check_erased(&mut data.restart()).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes `data` over the whole device, see [`SCSIDevice::secure_erase`]. `write_same` is
+    /// cleared if the device turns out not to support `WRITE SAME (10)`.
+    async fn erase_pass(&mut self, data: &mut PassData, write_same: &mut bool) -> Result<()> {
+        let Capacity {
+            block_count,
+            block_size,
+            ..
+        } = self.capacity().await?;
+        let blocks_per_write = blocks_per_command(self.max_transfer_size, block_size);
+        let blocks_per_write_same =
+            (WRITE_SAME_MAX_BYTES / u64::from(block_size)).clamp(1, u64::from(u16::MAX));
+        let mut buf = Vec::new();
+        let mut lba = 0;
+        while lba < block_count {
+            let len = (block_count - lba).min(blocks_per_write_same);
+            // WRITE SAME (10) can only address the first 2^32 blocks
+            if *write_same && u32::try_from(lba + len).is_ok() {
+                buf.resize(block_size as usize, 0);
+                data.fill(&mut buf);
+                match self
+                    .issue_command(command::write_same10(lba as u32, len as u16, &buf))
+                    .await
+                {
+                    Ok(_) => {
+                        lba += len;
+                        continue;
+                    }
+                    Err(e) if is_unsupported(&e) => {
+                        warn!("WRITE SAME (10) is unsupported, falling back to WRITE");
+                        *write_same = false;
+                    }
+                    Err(e) => {
+                        return Err(e.wrap_err(format!(
+                            "attempting to write the same block to {len} blocks at LBA {lba}"
+                        )));
+                    }
+                }
+            }
+            let len = (block_count - lba).min(blocks_per_write);
+            buf.resize((len * u64::from(block_size)) as usize, 0);
+            data.fill(&mut buf);
+            self.write_blocks(lba, &buf, false).await?;
+            lba += len;
+        }
+        self.synchronize_cache().await
+    }
+
+    /// Reads the whole device back, checking it matches `data`, see [`SCSIDevice::secure_erase`].
+    async fn check_erased(&mut self, data: &mut PassData) -> Result<()> {
+        let Capacity {
+            block_count,
+            block_size,
+            ..
+        } = self.capacity().await?;
+        let blocks_per_read = blocks_per_command(self.max_transfer_size, block_size);
+        let mut expected = Vec::new();
+        let mut lba = 0;
+        while lba < block_count {
+            let len = (block_count - lba).min(blocks_per_read);
+            let read = self.read_blocks(lba, len).await?;
+            expected.resize(read.len(), 0);
+            data.fill(&mut expected);
+            if let Some(offset) = read.iter().zip(&expected).position(|(a, b)| a != b) {
+                bail!(
+                    "secure erase verification failed, block {} was not overwritten",
+                    lba + (offset / block_size as usize) as u64
+                );
+            }
+            lba += len;
+        }
+
+        Ok(())
+    }
+
+    /// Writes an image streamed from `src` to the device, starting from the first block.
+    ///
+    /// The image is written in chunks of up to the maximum transfer size
+    /// (see [`SCSIDevice::set_max_transfer_size`]), and `progress` is called
+    /// with the total number of bytes written so far after each one. If the image doesn't end
+    /// on a block boundary, the last block is padded with zeroes. The write cache is flushed
+    /// once the whole image has been written.
+    pub async fn write_image<R: AsyncRead + Unpin>(
+        &mut self,
+        mut src: R,
+        mut progress: impl FnMut(u64),
+    ) -> Result<()> {
+        let block_size = self.capacity().await?.block_size;
+        let chunk_size =
+            blocks_per_command(self.max_transfer_size, block_size) as usize * block_size as usize;
+        let mut buf = vec![0; chunk_size];
+        let mut lba = 0;
+        let mut bytes_written = 0;
+        loop {
+            // Fill the buffer entirely, so every chunk but the last is a full transfer
+            let mut len = 0;
+            while len < chunk_size {
+                match src
+                    .read(&mut buf[len..])
+                    .await
+                    .wrap_err("failed to read the image")?
+                {
+                    0 => break,
+                    read => len += read,
+                }
+            }
+            if len == 0 {
+                break;
+            }
+            let padded_len = len.next_multiple_of(block_size as usize);
+            buf[len..padded_len].fill(0);
+            self.write_blocks(lba, &buf[..padded_len], false).await?;
+            lba += (padded_len / block_size as usize) as u64;
+            bytes_written += len as u64;
+            progress(bytes_written);
+            if len < chunk_size {
+                break;
+            }
+        }
+        self.synchronize_cache().await
+    }
+
+    /// Reads every block of the device, streaming them into `dst`.
+    ///
+    /// The device is read in chunks of up to the maximum transfer size
+    /// (see [`SCSIDevice::set_max_transfer_size`]), and `progress` is called
+    /// with the total number of bytes read so far after each one.
+    ///
+    /// Each of the requested `hashes` is computed over the data as it's read, so an image can be
+    /// verified without reading the device a second time.
+    pub async fn read_image<W: AsyncWrite + Unpin>(
+        &mut self,
+        mut dst: W,
+        hashes: ImageHashes,
+        mut progress: impl FnMut(u64),
+    ) -> Result<ImageDigest> {
+        let Capacity {
+            block_count,
+            block_size,
+            ..
+        } = self.capacity().await?;
+        let blocks_per_command = blocks_per_command(self.max_transfer_size, block_size);
+        let mut hasher = ImageHasher::new(hashes);
+        let mut lba = 0;
+        while lba < block_count {
+            // The last chunk is shorter if the block count isn't a multiple of the chunk size
+            let len = (block_count - lba).min(blocks_per_command);
+            let data = self.read_blocks(lba, len).await?;
+            dst.write_all(&data)
+                .await
+                .wrap_err("failed to write the image")?;
+            hasher.update(&data);
+            lba += len;
+            progress(hasher.bytes());
+        }
+        dst.flush().await.wrap_err("failed to write the image")?;
+
+        Ok(hasher.finalize())
+    }
+
+    /// Runs the device's default self-test with `SEND DIAGNOSTIC`.
+    ///
+    /// A failed self-test is reported as a [`ScsiError::CheckCondition`], with the sense data
+    /// describing what went wrong (typically a `HARDWARE ERROR`). The self-test may run for up
+    /// to the timeout set with [`SCSIDevice::set_long_command_timeout`].
+    pub async fn self_test(&mut self) -> Result<()> {
+        debug!("submitting SEND DIAGNOSTIC (self-test)");
+        // The status is only returned once the self-test has finished, which takes a while on
+        // spinning drives
+        self.issue_command_with_timeout(command::send_diagnostic(true), self.long_command_timeout)
+            .await
+            .wrap_err("device self-test failed")?;
+
+        Ok(())
+    }
+
+    /// Flushes the device's volatile write cache to the medium with `SYNCHRONIZE CACHE`.
+    ///
+    /// Until this is done, written data may be lost if the device is unplugged.
+    pub async fn synchronize_cache(&mut self) -> Result<()> {
+        // A block count of zero covers the entire medium
+        self.issue_command(command::synchronize_cache(0, 0))
+            .await
+            .wrap_err("attempting to issue SYNCHRONIZE CACHE")?;
+
+        Ok(())
+    }
+
+    /// A higher level wrapper over the SCSI `WRITE` command.
+    ///
+    /// Writes `data` to contiguous blocks, starting from `logical_block_address`. The length
+    /// of `data` must be a multiple of the block size.
+    pub async fn write(&mut self, logical_block_address: u32, data: &[u8]) -> Result<()> {
+        let block_size = self.capacity().await?.block_size as usize;
+        ensure!(
+            !data.is_empty() && data.len().is_multiple_of(block_size),
+            "data length must be a non-zero multiple of the block size ({block_size}B), was {}",
+            data.len()
+        );
+        let transfer_len = u16::try_from(data.len() / block_size)
+            .wrap_err("too many blocks to write with a single WRITE")?;
+        self.issue_command(command::write(logical_block_address, transfer_len, data))
+            .await
+            .wrap_err("attempting to issue WRITE")?;
+
+        Ok(())
+    }
+}
+
+/// The most data a single `WRITE SAME` is asked to write, in *bytes*.
+///
+/// The device only receives one block, but it still has to write the whole range before the
+/// command times out.
+const WRITE_SAME_MAX_BYTES: u64 = 4 * 1024 * 1024;
+
+/// Returns true if a command failed because the device doesn't implement it (or the options it
+/// was issued with), which SPC-2 reports as an ILLEGAL REQUEST.
+fn is_unsupported(error: &eyre::Report) -> bool {
+    matches!(
+        error.downcast_ref::<ScsiError>(),
+        Some(ScsiError::CheckCondition {
+            sense_key: SenseKey::IllegalRequest,
+            ..
+        })
+    )
+}
+
+/// The number of blocks transferred by each command of a chunked read or write, with
+/// transfers of up to `max_transfer_size` *bytes*.
+fn blocks_per_command(max_transfer_size: u32, block_size: u32) -> u64 {
+    u64::from((max_transfer_size / block_size).clamp(1, u32::from(u16::MAX)))
+}
+
+/// Configures which failures [`SCSIDevice::issue_command_retrying`] retries, and how often.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// The total number of times a command is issued, including the first attempt
+    pub max_attempts: u32,
+    /// The delay before the first retry, doubled for each retry after that
+    pub initial_backoff: Duration,
+    /// The upper bound of the delay between retries
+    pub max_backoff: Duration,
+    /// Commands failing with a CHECK CONDITION are retried if the sense key is in this list
+    pub retryable_sense_keys: Vec<SenseKey>,
+    /// If set, commands that failed because of a stall that couldn't be recovered, or a timeout,
+    /// are retried. The transport has already been recovered from a timeout by then, see
+    /// [`UsbTransport::abort_recovery`].
+    pub retry_transport_errors: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(2),
+            retryable_sense_keys: vec![SenseKey::UnitAttention, SenseKey::NotReady],
+            retry_transport_errors: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Returns true if a command that failed with `error` should be retried.
+    fn is_retryable(&self, error: &eyre::Report) -> bool {
+        if let Some(ScsiError::CheckCondition { sense_key, .. }) = error.downcast_ref::<ScsiError>()
+        {
+            return self.retryable_sense_keys.contains(sense_key);
+        }
+        self.retry_transport_errors
+            && matches!(
+                error.downcast_ref::<TransportError>(),
+                Some(TransportError::Stall | TransportError::Timeout)
+            )
+    }
+}
+
+/// An error reported by a SCSI device.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ScsiError {
+    /// The command failed, the sense data describes why.
+    ///
+    /// See SPC-2 Annex D for the meaning of `asc` and `ascq`.
+    CheckCondition {
+        sense_key: SenseKey,
+        /// `ADDITIONAL SENSE CODE`
+        asc: u8,
+        /// `ADDITIONAL SENSE CODE QUALIFIER`
+        ascq: u8,
+    },
+}
+
+impl ScsiError {
+    /// Returns true if the error is a `UNIT ATTENTION` reporting that the medium may have
+    /// changed, either `NOT READY TO READY CHANGE, MEDIUM MAY HAVE CHANGED` (28h) or
+    /// `POWER ON, RESET, OR BUS DEVICE RESET OCCURRED` (29h).
+    pub fn is_medium_change(&self) -> bool {
+        match self {
+            Self::CheckCondition { sense_key, asc, .. } => {
+                *sense_key == SenseKey::UnitAttention && matches!(asc, 0x28 | 0x29)
+            }
+        }
+    }
+}
+
+/// A one line summary of the device, from whatever INQUIRY data and capacity have already been
+/// retrieved, e.g `SCSIDevice(Kingston DataTraveler 3.0, 28.9 GiB, 512B blocks)`.
+///
+/// No commands are issued, parts that aren't known yet are reported as unknown.
+impl<T: UsbTransport> std::fmt::Display for SCSIDevice<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SCSIDevice(")?;
+        match &self.inquiry {
+            Some(inquiry) => {
+                let identity = format!("{} {}", inquiry.vendor(), inquiry.product());
+                write!(f, "{}", identity.trim())?;
+            }
+            None => write!(f, "unknown device")?,
+        }
+        match &self.capacity {
+            Some(capacity) => write!(
+                f,
+                ", {}, {}B blocks)",
+                human_size(capacity.total_bytes),
+                capacity.block_size
+            ),
+            None => write!(f, ", unknown capacity)"),
+        }
+    }
+}
+
+impl std::fmt::Display for ScsiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::CheckCondition {
+                sense_key,
+                asc,
+                ascq,
+            } => {
+                write!(
+                    f,
+                    "CHECK CONDITION, sense key: {sense_key}, ASC/ASCQ: {asc:02X}h/{ascq:02X}h"
+                )?;
+                if let Some(description) = response::additional_sense_description(*asc, *ascq) {
+                    write!(f, " ({description})")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ScsiError {}
+
+/// The size of a device's storage medium.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Capacity {
+    /// The number of logical blocks on the device
+    pub block_count: u64,
+    /// The size of each logical block in *bytes*
+    pub block_size: u32,
+    /// The total size of the device in *bytes*
+    pub total_bytes: u64,
+}
+
+impl Capacity {
+    /// Returns a human readable description of the capacity, like
+    /// `28.9 GiB (31,037,849,600 bytes, 60,620,800 × 512)`.
+    pub fn describe(&self) -> String {
+        format!(
+            "{} ({} bytes, {} × {})",
+            human_size(self.total_bytes),
+            group_thousands(self.total_bytes),
+            group_thousands(self.block_count),
+            self.block_size
+        )
+    }
+}
+
+/// Formats a size in bytes with the largest binary unit it fits in, to one decimal place,
+/// like `28.9 GiB`.
+pub fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 6] = ["KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+/// Formats `n` with commas between each group of three digits.
+fn group_thousands(n: u64) -> String {
+    let digits = n.to_string();
+    let mut output = String::with_capacity(digits.len() + digits.len() / 3);
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            output.push(',');
+        }
+        output.push(digit);
+    }
+    output
 }
 
 pub struct ResponseBytes {
@@ -151,3 +1363,576 @@ impl ResponseBytes {
         (self.parser)(&self.bytes)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::future::poll_fn;
+    use std::pin::pin;
+    use std::time::Duration;
+
+    use futures_core::Stream;
+
+    use crate::scsi::digest::{ImageHashes, Md5, Sha256};
+    use crate::scsi::response::{self, Response, SenseKey};
+    use crate::scsi::{
+        CancellationToken, Cancelled, Capacity, ErasePattern, LONG_COMMAND_TIMEOUT,
+        MAX_TRANSFER_SIZE, RetryPolicy, SCSIDevice, ScsiError, command, human_size,
+    };
+    use crate::usb::TransportError;
+    use crate::usb::cbw::CswError;
+    use crate::usb::mock::{MockTransport, RecordingTransport};
+
+    const TEST_UNIT_READY: [u8; 6] = [0x00, 0, 0, 0, 0, 0];
+    const REQUEST_SENSE: [u8; 6] = [0x03, 0, 0, 0, 18, 0];
+
+    /// Fixed format sense data (SPC-2 7.23.2) reporting `key`, with the additional sense code
+    /// and qualifier `asc` and `ascq`, as returned to [`REQUEST_SENSE`].
+    fn sense(key: u8, asc: u8, ascq: u8) -> [u8; 18] {
+        [
+            0x70, 0, key, 0, 0, 0, 0, 0x0A, 0, 0, 0, 0, asc, ascq, 0, 0, 0, 0,
+        ]
+    }
+
+    /// A device with 4096 blocks of 512 bytes, that has already been initialized. Shared with
+    /// the tests of the submodules, which override the capacity as needed.
+    pub(crate) fn initialized_device(transport: MockTransport) -> SCSIDevice<MockTransport> {
+        SCSIDevice {
+            drive: transport,
+            lun: 0,
+            capacity: Some(Capacity {
+                block_count: 4096,
+                block_size: 512,
+                total_bytes: 4096 * 512,
+            }),
+            inquiry: None,
+            retry_on_medium_change: false,
+            max_transfer_size: MAX_TRANSFER_SIZE,
+            cancellation: None,
+            long_command_timeout: LONG_COMMAND_TIMEOUT,
+        }
+    }
+
+    #[tokio::test]
+    async fn initialization_sequence() {
+        let transport = MockTransport::new()
+            .expect(&TEST_UNIT_READY, &[])
+            .expect(&[0x12, 0, 0, 0, 36, 0], &[0; 36])
+            .expect(&[0x1E, 0, 0, 0, 0b11, 0], &[])
+            // 4096 blocks of 512 bytes
+            .expect(
+                &[0x25, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+                &[0, 0, 0x0F, 0xFF, 0, 0, 2, 0],
+            )
+            // An empty mode parameter header
+            .expect(&[0x1A, 0b1000, 0x3F, 0, 192, 0], &[3, 0, 0, 0])
+            .expect(&TEST_UNIT_READY, &[]);
+        let device = SCSIDevice::new(transport, 0).await.unwrap();
+        assert_eq!(device.capacity.unwrap().block_count, 4096);
+        assert_eq!(device.cached_block_size(), Some(512));
+        assert!(device.drive.is_finished());
+    }
+
+    #[tokio::test]
+    async fn replay_recorded_initialization() {
+        let transport = RecordingTransport::load(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/fixtures/sandisk_cruzer_init.txt"
+        ))
+        .unwrap();
+        let device = SCSIDevice::new(transport, 0).await.unwrap();
+        assert_eq!(device.capacity.unwrap().block_count, 2097152);
+        assert_eq!(device.cached_block_size(), Some(512));
+        let inquiry = device.cached_inquiry().unwrap();
+        assert_eq!(inquiry.vendor(), "SanDisk");
+        assert_eq!(inquiry.product(), "Cruzer Blade");
+        assert!(device.drive.is_finished());
+    }
+
+    #[tokio::test]
+    async fn check_condition_fetches_sense_data() {
+        // An empty card reader slot, reporting NOT READY - MEDIUM NOT PRESENT
+        let not_present = sense(0x02, 0x3A, 0x00);
+        let transport = MockTransport::new()
+            .expect_failure(&TEST_UNIT_READY, CswError::Failed)
+            .expect(&REQUEST_SENSE, &not_present);
+        let mut device = initialized_device(transport);
+        let error = device
+            .issue_command(command::test_unit_ready())
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            error.downcast_ref::<ScsiError>(),
+            Some(&ScsiError::CheckCondition {
+                sense_key: SenseKey::NotReady,
+                asc: 0x3A,
+                ascq: 0x00,
+            })
+        );
+        assert!(device.drive.is_finished());
+    }
+
+    #[tokio::test]
+    async fn wait_ready_waits_out_spin_up() {
+        // NOT READY - LOGICAL UNIT IS IN PROCESS OF BECOMING READY
+        let becoming_ready = sense(0x02, 0x04, 0x01);
+        // NOT READY - MEDIUM NOT PRESENT
+        let not_present = sense(0x02, 0x3A, 0x00);
+        let transport = MockTransport::new()
+            .expect_failure(&TEST_UNIT_READY, CswError::Failed)
+            .expect(&REQUEST_SENSE, &becoming_ready)
+            .expect(&TEST_UNIT_READY, &[])
+            .expect_failure(&TEST_UNIT_READY, CswError::Failed)
+            .expect(&REQUEST_SENSE, &not_present);
+        let mut device = initialized_device(transport);
+        device.wait_ready(Duration::from_secs(5)).await.unwrap();
+        // A missing medium won't appear by waiting
+        let error = device
+            .wait_ready(Duration::from_secs(5))
+            .await
+            .expect_err("should fail without waiting");
+        assert!(matches!(
+            error.downcast_ref::<ScsiError>(),
+            Some(ScsiError::CheckCondition { asc: 0x3A, .. })
+        ));
+        assert!(device.drive.is_finished());
+    }
+
+    #[tokio::test]
+    async fn medium_change_invalidates_capacity() {
+        // UNIT ATTENTION - NOT READY TO READY CHANGE, MEDIUM MAY HAVE CHANGED
+        let medium_changed = sense(0x06, 0x28, 0x00);
+        let transport = MockTransport::new()
+            .expect_failure(&TEST_UNIT_READY, CswError::Failed)
+            .expect(&REQUEST_SENSE, &medium_changed)
+            // A smaller card with 4096 byte blocks was inserted
+            .expect(
+                &[0x25, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+                &[0, 0, 0, 0xFF, 0, 0, 0x10, 0],
+            );
+        let mut device = initialized_device(transport);
+        assert_eq!(device.cached_block_size(), Some(512));
+        let result = device.issue_command(command::test_unit_ready()).await;
+        assert!(result.is_err());
+        assert_eq!(device.cached_block_size(), None);
+        assert_eq!(device.capacity().await.unwrap().block_size, 4096);
+        assert!(device.drive.is_finished());
+    }
+
+    #[tokio::test]
+    async fn medium_change_retries_once() {
+        // UNIT ATTENTION - POWER ON, RESET, OR BUS DEVICE RESET OCCURRED
+        let reset = sense(0x06, 0x29, 0x00);
+        let transport = MockTransport::new()
+            .expect_failure(&TEST_UNIT_READY, CswError::Failed)
+            .expect(&REQUEST_SENSE, &reset)
+            .expect(&TEST_UNIT_READY, &[]);
+        let mut device = initialized_device(transport);
+        device.set_retry_on_medium_change(true);
+        device
+            .issue_command(command::test_unit_ready())
+            .await
+            .unwrap();
+        assert_eq!(device.cached_block_size(), None);
+        assert!(device.drive.is_finished());
+    }
+
+    #[tokio::test]
+    async fn advanced_format_block_size() {
+        let transport = MockTransport::new()
+            // 1024 blocks of 4096 bytes
+            .expect(
+                &[0x25, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+                &[0, 0, 0x03, 0xFF, 0, 0, 0x10, 0],
+            )
+            // 64KiB per command is only 16 blocks
+            .expect(&[0x28, 0, 0, 0, 0, 0, 0, 0, 16, 0], &[1; 16 * 4096])
+            .expect(&[0x28, 0, 0, 0, 0, 16, 0, 0, 4, 0], &[2; 4 * 4096])
+            .expect(&[0x2A, 0, 0, 0, 0, 5, 0, 0, 1, 0], &[]);
+        let mut device = initialized_device(transport);
+        device.capacity = None;
+
+        let capacity = device.capacity().await.unwrap();
+        assert_eq!(capacity.block_size, 4096);
+        assert_eq!(capacity.total_bytes, 1024 * 4096);
+        let data = device.read_blocks(0, 20).await.unwrap();
+        assert_eq!(data.len(), 20 * 4096);
+        assert_eq!(data[16 * 4096], 2);
+        device.write_blocks(5, &[0xAA; 4096], false).await.unwrap();
+        // A 512 byte sector is only part of a block
+        assert!(device.write_blocks(5, &[0xAA; 512], false).await.is_err());
+        assert!(device.drive.is_finished());
+    }
+
+    #[tokio::test]
+    async fn zero_block_length_is_rejected() {
+        let transport = MockTransport::new().expect(
+            &[0x25, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            &[0, 0, 0x03, 0xFF, 0, 0, 0, 0],
+        );
+        let mut device = initialized_device(transport);
+        device.capacity = None;
+        assert!(device.capacity().await.is_err());
+        // Nothing is cached, so reads can't divide by the bogus block length
+        assert_eq!(device.cached_block_size(), None);
+        assert!(device.drive.is_finished());
+    }
+
+    #[tokio::test]
+    async fn probed_transfer_size_is_used_for_reads() {
+        let mut transport = MockTransport::new();
+        for len in [1, 2, 4, 8, 16, 32] {
+            transport = transport.expect(
+                &[0x28, 0, 0, 0, 0, 0, 0, 0, len, 0],
+                &vec![0; usize::from(len) * 512],
+            );
+        }
+        // The bridge can't handle 32KiB transfers
+        let transport = transport
+            .expect_failure(&[0x28, 0, 0, 0, 0, 0, 0, 0, 64, 0], CswError::PhaseError)
+            .expect(&[0x28, 0, 0, 0, 0, 0, 0, 0, 32, 0], &[1; 32 * 512])
+            .expect(&[0x28, 0, 0, 0, 0, 32, 0, 0, 8, 0], &[2; 8 * 512]);
+        let mut device = initialized_device(transport);
+        assert_eq!(device.probe_max_transfer().await.unwrap(), 16 * 1024);
+        let data = device.read_blocks(0, 40).await.unwrap();
+        assert_eq!(data.len(), 40 * 512);
+        assert!(device.drive.is_finished());
+    }
+
+    #[test]
+    fn display_summary() {
+        let mut device = initialized_device(MockTransport::new());
+        assert_eq!(
+            device.to_string(),
+            "SCSIDevice(unknown device, 2.0 MiB, 512B blocks)"
+        );
+
+        let mut data = [b' '; 36];
+        data[..8].fill(0);
+        data[8..16].copy_from_slice(b"Kingston");
+        data[16..28].copy_from_slice(b"DataTraveler");
+        let Response::Inquiry(inquiry) = response::inquiry(&data).unwrap() else {
+            unreachable!()
+        };
+        device.inquiry = Some(inquiry);
+        device.capacity = None;
+        assert_eq!(
+            device.to_string(),
+            "SCSIDevice(Kingston DataTraveler, unknown capacity)"
+        );
+    }
+
+    #[test]
+    fn describe_check_condition() {
+        let error = ScsiError::CheckCondition {
+            sense_key: SenseKey::NotReady,
+            asc: 0x3A,
+            ascq: 0x00,
+        };
+        assert_eq!(
+            error.to_string(),
+            "CHECK CONDITION, sense key: NOT READY, ASC/ASCQ: 3Ah/00h (MEDIUM NOT PRESENT)"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn timing_out_recovers_the_transport() {
+        let transport = MockTransport::new()
+            .expect_hang(&[0x28, 0, 0, 0, 0, 0, 0, 0, 1, 0])
+            .expect_hang(&REQUEST_SENSE);
+        let mut device = initialized_device(transport);
+        let error = device.read_blocks(0, 1).await.unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<TransportError>(),
+            Some(&TransportError::Timeout)
+        );
+        assert_eq!(device.drive.abort_recoveries, 1);
+        assert!(device.request_sense().await.is_err());
+        assert_eq!(device.drive.abort_recoveries, 2);
+        assert!(device.drive.is_finished());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn self_tests_use_the_long_timeout() {
+        const SELF_TEST: [u8; 6] = [0x1D, 0x04, 0, 0, 0, 0];
+        let transport = MockTransport::new()
+            .expect_delayed(&SELF_TEST, &[], Duration::from_secs(60))
+            .expect_delayed(&SELF_TEST, &[], Duration::from_secs(60));
+        let mut device = initialized_device(transport);
+        device.self_test().await.unwrap();
+        assert_eq!(device.drive.abort_recoveries, 0);
+
+        device.set_long_command_timeout(Duration::from_secs(30));
+        let error = device.self_test().await.unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<TransportError>(),
+            Some(&TransportError::Timeout)
+        );
+        assert_eq!(device.drive.abort_recoveries, 1);
+        assert!(device.drive.is_finished());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn timeouts_are_retried_after_recovery() {
+        let transport = MockTransport::new()
+            .expect_hang(&TEST_UNIT_READY)
+            .expect_failure(&TEST_UNIT_READY, CswError::PhaseError)
+            .expect(&TEST_UNIT_READY, &[]);
+        let mut device = initialized_device(transport);
+        let result = device
+            .issue_command_retrying(command::test_unit_ready(), &RetryPolicy::default())
+            .await;
+        assert!(result.is_err());
+        // Only the timeout needed recovering, the phase error isn't retryable
+        assert_eq!(device.drive.abort_recoveries, 1);
+        device
+            .issue_command_retrying(command::test_unit_ready(), &RetryPolicy::default())
+            .await
+            .unwrap();
+        assert!(device.drive.is_finished());
+    }
+
+    #[tokio::test]
+    async fn image_is_hashed_as_it_is_read() {
+        let transport = MockTransport::new()
+            .expect(&[0x28, 0, 0, 0, 0, 0, 0, 0, 2, 0], &[1; 2 * 512])
+            .expect(&[0x28, 0, 0, 0, 0, 2, 0, 0, 1, 0], &[2; 512]);
+        let mut device = initialized_device(transport);
+        device.capacity = Some(Capacity {
+            block_count: 3,
+            block_size: 512,
+            total_bytes: 3 * 512,
+        });
+        device.set_max_transfer_size(2 * 512);
+        let hashes = ImageHashes {
+            sha256: true,
+            md5: true,
+        };
+        let mut image = Vec::new();
+        let digest = device.read_image(&mut image, hashes, |_| {}).await.unwrap();
+        assert_eq!(digest.bytes, 3 * 512);
+
+        let mut sha256 = Sha256::new();
+        sha256.update(&image);
+        assert_eq!(digest.sha256, Some(sha256.finalize()));
+        let mut md5 = Md5::new();
+        md5.update(&image);
+        assert_eq!(digest.md5, Some(md5.finalize()));
+        assert!(device.drive.is_finished());
+    }
+
+    /// A device with 4 blocks of 512 bytes
+    fn small_device(transport: MockTransport) -> SCSIDevice<MockTransport> {
+        let mut device = initialized_device(transport);
+        device.capacity = Some(Capacity {
+            block_count: 4,
+            block_size: 512,
+            total_bytes: 4 * 512,
+        });
+        device
+    }
+
+    #[tokio::test]
+    async fn secure_erase_writes_the_same_block() {
+        let transport = MockTransport::new()
+            .expect(&[0x41, 0, 0, 0, 0, 0, 0, 0, 4, 0], &[])
+            .expect(&[0x35, 0, 0, 0, 0, 0, 0, 0, 0, 0], &[])
+            .expect(&[0x41, 0, 0, 0, 0, 0, 0, 0, 4, 0], &[])
+            .expect(&[0x35, 0, 0, 0, 0, 0, 0, 0, 0, 0], &[])
+            .expect(&[0x28, 0, 0, 0, 0, 0, 0, 0, 4, 0], &[0; 4 * 512]);
+        let mut device = small_device(transport);
+        device
+            .secure_erase(2, ErasePattern::Zeros, true)
+            .await
+            .unwrap();
+        assert_eq!(device.drive.data_out, [[0; 512], [0; 512]]);
+        assert!(device.drive.is_finished());
+    }
+
+    #[tokio::test]
+    async fn secure_erase_falls_back_to_write() {
+        // ILLEGAL REQUEST, INVALID COMMAND OPERATION CODE
+        let unsupported = sense(0x05, 0x20, 0x00);
+        let transport = MockTransport::new()
+            .expect_failure(&[0x41, 0, 0, 0, 0, 0, 0, 0, 4, 0], CswError::Failed)
+            .expect(&REQUEST_SENSE, &unsupported)
+            .expect(&[0x2A, 0, 0, 0, 0, 0, 0, 0, 4, 0], &[])
+            .expect(&[0x35, 0, 0, 0, 0, 0, 0, 0, 0, 0], &[])
+            // Partially overwritten
+            .expect(
+                &[0x28, 0, 0, 0, 0, 0, 0, 0, 4, 0],
+                &[[0xFF; 512], [0; 512]].repeat(2).concat(),
+            );
+        let mut device = small_device(transport);
+        let error = device
+            .secure_erase(1, ErasePattern::Ones, true)
+            .await
+            .unwrap_err();
+        assert_eq!(device.drive.data_out[1], [0xFF; 4 * 512]);
+        assert!(error.to_string().contains("block 1 was not overwritten"));
+        assert!(device.drive.is_finished());
+    }
+
+    #[tokio::test]
+    async fn cancelling_aborts_the_command_in_flight() {
+        let transport = MockTransport::new().expect_hang(&[0x28, 0, 0, 0, 0, 0, 0, 0, 1, 0]);
+        let mut device = initialized_device(transport);
+        let token = CancellationToken::new();
+        device.set_cancellation_token(Some(token.clone()));
+        let (result, ()) = tokio::join!(device.read_blocks(0, 1), async {
+            tokio::task::yield_now().await;
+            token.cancel();
+        });
+        assert!(result.unwrap_err().downcast_ref::<Cancelled>().is_some());
+        assert_eq!(device.drive.abort_recoveries, 1);
+        // Nothing else is submitted until a new token is attached
+        let result = device.read_blocks(0, 1).await;
+        assert!(result.unwrap_err().downcast_ref::<Cancelled>().is_some());
+        assert_eq!(device.drive.submitted.len(), 1);
+    }
+
+    #[test]
+    fn describe_capacity() {
+        assert_eq!(human_size(512), "512 B");
+        assert_eq!(human_size(1536), "1.5 KiB");
+        let capacity = Capacity {
+            block_count: 60_620_800,
+            block_size: 512,
+            total_bytes: 31_037_849_600,
+        };
+        assert_eq!(
+            capacity.describe(),
+            "28.9 GiB (31,037,849,600 bytes, 60,620,800 × 512)"
+        );
+    }
+
+    #[tokio::test]
+    async fn transient_failures_are_retried() {
+        // NOT READY - LOGICAL UNIT IS IN PROCESS OF BECOMING READY
+        let becoming_ready = sense(0x02, 0x04, 0x01);
+        // ILLEGAL REQUEST - INVALID COMMAND OPERATION CODE
+        let illegal_request = sense(0x05, 0x20, 0x00);
+        let transport = MockTransport::new()
+            .expect_failure(&TEST_UNIT_READY, CswError::Failed)
+            .expect(&REQUEST_SENSE, &becoming_ready)
+            .expect(&TEST_UNIT_READY, &[])
+            .expect_failure(&TEST_UNIT_READY, CswError::Failed)
+            .expect(&REQUEST_SENSE, &illegal_request);
+        let mut device = initialized_device(transport);
+        let policy = RetryPolicy {
+            initial_backoff: Duration::ZERO,
+            ..RetryPolicy::default()
+        };
+        device
+            .issue_command_retrying(command::test_unit_ready(), &policy)
+            .await
+            .unwrap();
+        // Not a transient failure, so it isn't retried
+        let result = device
+            .issue_command_retrying(command::test_unit_ready(), &policy)
+            .await;
+        assert!(result.is_err());
+        assert!(device.drive.is_finished());
+    }
+
+    #[tokio::test]
+    async fn write_image_pads_the_last_block() {
+        let image = vec![0xAA; 128 * 512 + 100];
+        let transport = MockTransport::new()
+            .expect(&[0x2A, 0, 0, 0, 0, 0, 0, 0, 128, 0], &[])
+            .expect(&[0x2A, 0, 0, 0, 0, 128, 0, 0, 1, 0], &[])
+            .expect(&[0x35, 0, 0, 0, 0, 0, 0, 0, 0, 0], &[]);
+        let mut device = initialized_device(transport);
+        let mut reported = Vec::new();
+        device
+            .write_image(image.as_slice(), |written| reported.push(written))
+            .await
+            .unwrap();
+        assert_eq!(reported, [128 * 512, 128 * 512 + 100]);
+        let last_block = &device.drive.data_out[1];
+        assert_eq!(last_block.len(), 512);
+        assert!(last_block[..100].iter().all(|byte| *byte == 0xAA));
+        assert!(last_block[100..].iter().all(|byte| *byte == 0));
+        assert!(device.drive.is_finished());
+    }
+
+    #[tokio::test]
+    async fn large_reads_are_chunked() {
+        // 64KiB per command, with 512 byte blocks
+        let transport = MockTransport::new()
+            .expect(&[0x28, 0, 0, 0, 0, 10, 0, 0, 128, 0], &[1; 128 * 512])
+            .expect(&[0x28, 0, 0, 0, 0, 138, 0, 0, 128, 0], &[2; 128 * 512])
+            .expect(&[0x28, 0, 0, 0, 1, 10, 0, 0, 44, 0], &[3; 44 * 512]);
+        let mut device = initialized_device(transport);
+        let data = device.read_blocks(10, 300).await.unwrap();
+        assert_eq!(data.len(), 300 * 512);
+        assert_eq!(data[128 * 512], 2);
+        assert_eq!(data[256 * 512], 3);
+        assert!(device.drive.is_finished());
+    }
+
+    #[tokio::test]
+    async fn format_unit_requires_force() {
+        let mut device = initialized_device(MockTransport::new());
+        assert!(device.format_unit(false).await.is_err());
+        assert!(device.drive.submitted.is_empty());
+
+        let transport = MockTransport::new()
+            .expect(&[0x04, 0x10, 0, 0, 0, 0], &[])
+            .expect(&TEST_UNIT_READY, &[]);
+        let mut device = initialized_device(transport);
+        device.format_unit(true).await.unwrap();
+        assert!(device.drive.is_finished());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn format_unit_polls_until_formatted() {
+        // NOT READY - LOGICAL UNIT NOT READY, FORMAT IN PROGRESS
+        let formatting = sense(0x02, 0x04, 0x04);
+        let mut transport = MockTransport::new().expect(&[0x04, 0x10, 0, 0, 0, 0], &[]);
+        // Formatting takes far longer than a single command may
+        for _ in 0..40 {
+            transport = transport
+                .expect_failure(&TEST_UNIT_READY, CswError::Failed)
+                .expect(&REQUEST_SENSE, &formatting);
+        }
+        let transport = transport.expect(&TEST_UNIT_READY, &[]);
+        let mut device = initialized_device(transport);
+        device.format_unit(true).await.unwrap();
+        // Only IMMED is set in the parameter list header
+        assert_eq!(device.drive.data_out, [vec![0, 0x02, 0, 0]]);
+        assert_eq!(device.drive.abort_recoveries, 0);
+        assert!(device.drive.is_finished());
+    }
+
+    #[tokio::test]
+    async fn block_stream_yields_chunks() {
+        let transport = MockTransport::new()
+            .expect(&[0x28, 0, 0, 0, 0, 4, 0, 0, 2, 0], &[1; 2 * 512])
+            .expect(&[0x28, 0, 0, 0, 0, 6, 0, 0, 1, 0], &[2; 512]);
+        let mut device = initialized_device(transport);
+        let mut chunks = Vec::new();
+        {
+            let mut stream = pin!(device.read_block_stream(4, 3, 2));
+            while let Some(chunk) = poll_fn(|cx| stream.as_mut().poll_next(cx)).await {
+                chunks.push(chunk.unwrap());
+            }
+        }
+        assert_eq!(chunks, [vec![1; 2 * 512], vec![2; 512]]);
+        assert!(device.drive.is_finished());
+    }
+
+    #[tokio::test]
+    async fn short_reads_are_reported() {
+        let transport = MockTransport::new().expect(&[0x28, 0, 0, 0, 0, 0, 0, 0, 2, 0], &[0; 512]);
+        let mut device = initialized_device(transport);
+        let error = device.read_blocks(0, 2).await.err().unwrap();
+        assert_eq!(
+            error.downcast_ref::<TransportError>(),
+            Some(&TransportError::ShortTransfer {
+                got: 512,
+                expected: 1024
+            })
+        );
+    }
+}
@@ -1,64 +1,886 @@
 //! Representations for responses to SCSI commands.
 
-use color_eyre::eyre::ensure;
+use std::borrow::Cow;
 
-pub type ResponseParser = fn(&[u8]) -> color_eyre::Result<Response>;
+use eyre::{bail, ensure};
+use tracing::debug;
+
+use super::OpCode;
+
+pub type ResponseParser = fn(&[u8]) -> eyre::Result<Response>;
 
 pub enum Response {
     Inquiry(Inquiry),
-    /// A tuple of (DRIVE SIZE, BLOCK_SIZE)
-    /// where drive size is in blocks, and block size
-    /// is in bytes
-    ReadCapacity(u32, u32),
-    /// True if the drive is read-only
-    ModeSense(bool),
+    VitalProductData(VitalProductData),
+    ReportLuns(Vec<u16>),
+    SupportedOpcodes(Vec<SupportedOpcode>),
+    ReadCapacity(ReadCapacity),
+    ReadCapacity16(ReadCapacity16),
+    FormatCapacities(FormatCapacities),
+    ModeSense(ModeParameters),
+    LogSense(LogPage),
+    RequestSense(SenseData),
+    /// The Data-In response as is, see [`raw`]
+    Raw(Vec<u8>),
     None,
 }
 
-pub fn no_response(buf: &[u8]) -> color_eyre::Result<Response> {
-    ensure!(buf.is_empty());
+/// The parser for commands without a Data-In phase.
+///
+/// Some drives still report a few bytes of data for non-data commands, which carry no meaning,
+/// so they're discarded rather than failing the command.
+pub fn no_response(buf: &[u8]) -> eyre::Result<Response> {
+    if !buf.is_empty() {
+        debug!(
+            "discarding {} bytes returned by a command without a response",
+            buf.len()
+        );
+    }
     Ok(Response::None)
 }
 
-pub fn inquiry(buf: &[u8]) -> color_eyre::Result<Response> {
+/// Passes the response through undecoded, for commands built with
+/// [`CommandBlock::raw`](super::command::CommandBlock::raw).
+pub fn raw(buf: &[u8]) -> eyre::Result<Response> {
+    Ok(Response::Raw(buf.to_vec()))
+}
+
+/// The length of the standard INQUIRY data, as described in SPC-2 7.3.2 table 46.
+pub const STANDARD_INQUIRY_LEN: usize = 36;
+
+/// Parses INQUIRY data, which is at least the 36 standard bytes. Any bytes past those are kept
+/// in [`Inquiry::additional_data`].
+pub fn inquiry(buf: &[u8]) -> eyre::Result<Response> {
     ensure!(
-        buf.len() == std::mem::size_of::<Inquiry>(),
-        "provided slice length does not match struct size"
+        buf.len() >= STANDARD_INQUIRY_LEN,
+        "INQUIRY data should be at least {STANDARD_INQUIRY_LEN} bytes, was {}",
+        buf.len()
     );
-    // SAFETY: it's been validated that the slice size matches the struct size
-    let s: &Inquiry = unsafe { &*(buf.as_ptr() as *const Inquiry) };
-    Ok(Response::Inquiry(s.clone()))
+    // "The ADDITIONAL LENGTH field shall specify the length in bytes of the parameters. If the
+    // ALLOCATION LENGTH of the CDB is too small to transfer all of the parameters, the
+    // ADDITIONAL LENGTH shall not be adjusted to reflect the truncation."
+    let full_len = 5 + usize::from(buf[4]);
+    Ok(Response::Inquiry(Inquiry {
+        peripheral_info: buf[0],
+        flags: buf[1..4].try_into()?,
+        // Bytes 4 through 7 contain the ADDITIONAL LENGTH and flags that aren't currently needed
+        vendor_identification: buf[8..16].try_into()?,
+        product_identification: buf[16..32].try_into()?,
+        product_revision_level: buf[32..36].try_into()?,
+        full_len,
+        additional_data: buf
+            [STANDARD_INQUIRY_LEN..buf.len().min(full_len.max(STANDARD_INQUIRY_LEN))]
+            .to_vec(),
+    }))
+}
+
+/// Parses a vital product data page returned by `INQUIRY` with the EVPD bit set,
+/// as described in SPC-2 8.4.1 table 257.
+pub fn vital_product_data(buf: &[u8]) -> eyre::Result<Response> {
+    ensure!(buf.len() >= 4, "VPD page header is truncated");
+    let page_len = usize::from(buf[3]);
+    ensure!(
+        buf.len() >= 4 + page_len,
+        "VPD page {:#04X} is truncated, expected {page_len} bytes, was {}",
+        buf[1],
+        buf.len() - 4
+    );
+    Ok(Response::VitalProductData(VitalProductData {
+        page_code: buf[1],
+        data: buf[4..4 + page_len].to_vec(),
+    }))
+}
+
+/// The page code of the Unit Serial Number VPD page, SPC-2 8.4.8
+pub const UNIT_SERIAL_NUMBER_VPD_PAGE: u8 = 0x80;
+
+/// A vital product data page, see SPC-2 8.4.
+#[derive(Clone, Debug)]
+pub struct VitalProductData {
+    /// `PAGE CODE`
+    pub page_code: u8,
+    /// The contents of the page following the header
+    pub data: Vec<u8>,
+}
+
+impl VitalProductData {
+    /// Decodes the `PRODUCT SERIAL NUMBER` field, if this is the Unit Serial Number page.
+    ///
+    /// This is the serial number reported by the SCSI device, which may differ from the one in
+    /// the USB device descriptor, or be present when that one is blank.
+    pub fn unit_serial_number(&self) -> Option<String> {
+        if self.page_code != UNIT_SERIAL_NUMBER_VPD_PAGE {
+            return None;
+        }
+        // The serial number is ASCII, and usually right-aligned with leading spaces
+        let serial = String::from_utf8_lossy(&self.data);
+        Some(serial.trim_matches([' ', '\0']).to_owned())
+    }
+}
+
+/// Parses the LUN list returned by `REPORT LUNS`, as described in SPC-2 7.19 table 91.
+///
+/// Only the first level of each LUN (SAM-2 4.12) is decoded, as USB mass storage devices
+/// don't use hierarchical addressing.
+pub fn report_luns(buf: &[u8]) -> eyre::Result<Response> {
+    ensure!(buf.len() >= 8, "REPORT LUNS header is truncated");
+    // LUN LIST LENGTH, in bytes
+    let list_len = u32::from_be_bytes(buf[0..4].try_into()?) as usize;
+    // The list may be longer than the allocation length, in which case it's truncated
+    let list = &buf[8..buf.len().min(8 + list_len)];
+    let luns = list
+        .chunks_exact(8)
+        // The top two bits are the ADDRESS METHOD, for both the peripheral device and flat space
+        // addressing methods, the rest of the first two bytes are the LUN
+        .map(|lun| u16::from_be_bytes([lun[0] & 0x3F, lun[1]]))
+        .collect();
+    Ok(Response::ReportLuns(luns))
+}
+
+/// A command listed by `REPORT SUPPORTED OPERATION CODES`, as described in SPC-3 table 247.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SupportedOpcode {
+    pub operation_code: u8,
+    /// Set if the command is selected by a service action, like `SERVICE ACTION IN (16)`
+    pub service_action: Option<u16>,
+    /// The length of the CDB in *bytes*
+    pub cdb_length: u16,
+}
+
+impl SupportedOpcode {
+    /// Decodes the operation code, returning `None` for operations that aren't modelled.
+    pub fn opcode(&self) -> Option<OpCode> {
+        OpCode::from_u8(self.operation_code)
+    }
+}
+
+/// Parses the command list returned by `REPORT SUPPORTED OPERATION CODES` with the
+/// `REPORTING OPTIONS` set to 0, as described in SPC-3 6.23.2 table 246.
+pub fn report_supported_opcodes(buf: &[u8]) -> eyre::Result<Response> {
+    ensure!(
+        buf.len() >= 4,
+        "REPORT SUPPORTED OPERATION CODES header is truncated"
+    );
+    // COMMAND DATA LENGTH, in bytes
+    let data_len = u32::from_be_bytes(buf[0..4].try_into()?) as usize;
+    // The list may be longer than the allocation length, in which case it's truncated
+    let mut descriptors = &buf[4..buf.len().min(4 + data_len)];
+    let mut opcodes = Vec::new();
+    while descriptors.len() >= 8 {
+        let descriptor = &descriptors[..8];
+        // SERVACTV (bit 0), set if the SERVICE ACTION field is valid
+        let service_action =
+            (descriptor[5] & 0b01 != 0).then(|| u16::from_be_bytes([descriptor[2], descriptor[3]]));
+        opcodes.push(SupportedOpcode {
+            operation_code: descriptor[0],
+            service_action,
+            cdb_length: u16::from_be_bytes([descriptor[6], descriptor[7]]),
+        });
+        // CTDP (bit 1), set if a 12 byte command timeouts descriptor follows
+        let descriptor_len = if descriptor[5] & 0b10 != 0 { 20 } else { 8 };
+        descriptors = &descriptors[descriptor_len.min(descriptors.len())..];
+    }
+    Ok(Response::SupportedOpcodes(opcodes))
 }
 
 /// Described in SBC-2 Table 29
-pub fn read_capacity(buf: &[u8]) -> color_eyre::Result<Response> {
-    ensure!(buf.len() == 8);
-    let mut capacity_bytes = [0u8; 4];
-    capacity_bytes.copy_from_slice(&buf[0..4]);
-    let mut block_size_bytes = [0u8; 4];
-    block_size_bytes.copy_from_slice(&buf[4..]);
-    // Yes, these are big endian while everything else is little endian, no, I don't know why
-    Ok(Response::ReadCapacity(
-        // The response technically contains the address of the last block, so we need to
-        // correct for the zero-based index
-        u32::from_be_bytes(capacity_bytes) + 1,
-        u32::from_be_bytes(block_size_bytes),
-    ))
-}
-
-/// "if bit 6 of byte 2 is set, the drive is read only"
-pub fn mode_sense(buf: &[u8]) -> color_eyre::Result<Response> {
-    ensure!(buf.len() == 192);
-    let read_only = buf[2] & 0b_0100_0000 != 0;
-    // I'm not confident that this is implemented correctly
-    Ok(Response::ModeSense(read_only))
-}
-
-#[derive(Clone)]
-#[repr(C, packed)]
+pub fn read_capacity(buf: &[u8]) -> eyre::Result<Response> {
+    ensure!(
+        buf.len() == 8,
+        "READ CAPACITY (10) data should be 8 bytes, was {}",
+        buf.len()
+    );
+    let mut last_lba = [0u8; 4];
+    last_lba.copy_from_slice(&buf[0..4]);
+    let mut block_size = [0u8; 4];
+    block_size.copy_from_slice(&buf[4..]);
+    Ok(Response::ReadCapacity(ReadCapacity {
+        last_lba,
+        block_size,
+    }))
+}
+
+/// READ CAPACITY (10) parameter data, as described in SBC-2 5.1.10.2 table 29.
+///
+/// Yes, these are big endian while everything else is little endian, no, I don't know why
+#[derive(Copy, Clone, Debug)]
+pub struct ReadCapacity {
+    /// `RETURNED LOGICAL BLOCK ADDRESS`
+    last_lba: [u8; 4],
+    /// `BLOCK LENGTH IN BYTES`
+    block_size: [u8; 4],
+}
+
+impl ReadCapacity {
+    /// The address of the last logical block on the device.
+    pub fn last_lba(&self) -> u32 {
+        u32::from_be_bytes(self.last_lba)
+    }
+
+    /// The size of each logical block in bytes.
+    pub fn block_size(&self) -> u32 {
+        u32::from_be_bytes(self.block_size)
+    }
+
+    /// The number of logical blocks on the device.
+    ///
+    /// The response contains the address of the last block, so this corrects
+    /// for the zero-based index.
+    pub fn block_count(&self) -> u64 {
+        u64::from(self.last_lba()) + 1
+    }
+
+    /// The total capacity of the device in bytes.
+    pub fn total_bytes(&self) -> u64 {
+        self.block_count() * u64::from(self.block_size())
+    }
+}
+
+/// Parses the capacity list returned by `READ FORMAT CAPACITIES`, as described in
+/// MMC-5 6.24.2 (also USB Mass Storage Class - UFI 4.10).
+pub fn read_format_capacities(buf: &[u8]) -> eyre::Result<Response> {
+    ensure!(
+        buf.len() >= 12,
+        "READ FORMAT CAPACITIES data should be at least 12 bytes, was {}",
+        buf.len()
+    );
+    // CAPACITY LIST LENGTH (byte 3), the length of the descriptors that follow the header
+    let list_len = usize::from(buf[3]);
+    ensure!(
+        list_len >= 8,
+        "READ FORMAT CAPACITIES has no current capacity descriptor"
+    );
+    // The list may be longer than the allocation length, in which case it's truncated
+    let list = &buf[4..buf.len().min(4 + list_len)];
+    let block_count = |descriptor: &[u8]| u32::from_be_bytes(descriptor[0..4].try_into().unwrap());
+    let be_u24 = |bytes: &[u8]| u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+
+    // The first descriptor is always the Current/Maximum Capacity Descriptor
+    let current = &list[0..8];
+    let current = CurrentCapacity {
+        block_count: block_count(current),
+        descriptor_type: CapacityDescriptorType::from(current[4]),
+        block_size: be_u24(&current[5..8]),
+    };
+    let formattable = list[8..]
+        .chunks_exact(8)
+        .map(|descriptor| FormattableCapacity {
+            block_count: block_count(descriptor),
+            format_type: descriptor[4] >> 2,
+            type_dependent_parameter: be_u24(&descriptor[5..8]),
+        })
+        .collect();
+    Ok(Response::FormatCapacities(FormatCapacities {
+        current,
+        formattable,
+    }))
+}
+
+/// The `DESCRIPTOR TYPE` of the current capacity descriptor, see MMC-5 table 279.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CapacityDescriptorType {
+    /// The maximum capacity of unformatted media
+    Unformatted,
+    /// The capacity of the media currently inserted
+    Formatted,
+    /// No media is present, the capacity is the maximum the device supports
+    NoMedia,
+    /// A reserved value
+    Reserved,
+}
+
+impl From<u8> for CapacityDescriptorType {
+    /// Decodes the lower 2 bits of byte 4 of the descriptor
+    fn from(byte: u8) -> Self {
+        match byte & 0b11 {
+            0b01 => Self::Unformatted,
+            0b10 => Self::Formatted,
+            0b11 => Self::NoMedia,
+            _ => Self::Reserved,
+        }
+    }
+}
+
+/// The Current/Maximum Capacity Descriptor of `READ FORMAT CAPACITIES`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CurrentCapacity {
+    /// `NUMBER OF BLOCKS`
+    pub block_count: u32,
+    /// Whether the capacity describes the inserted media, or there is none
+    pub descriptor_type: CapacityDescriptorType,
+    /// `BLOCK LENGTH`, in *bytes*
+    pub block_size: u32,
+}
+
+/// A capacity the media can be formatted to, see MMC-5 6.24.2.4.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FormattableCapacity {
+    /// `NUMBER OF BLOCKS`
+    pub block_count: u32,
+    /// `FORMAT TYPE`, to be used with `FORMAT UNIT`
+    pub format_type: u8,
+    /// `TYPE DEPENDENT PARAMETER`, usually the block length in *bytes*
+    pub type_dependent_parameter: u32,
+}
+
+/// The capacity list returned by `READ FORMAT CAPACITIES`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FormatCapacities {
+    pub current: CurrentCapacity,
+    pub formattable: Vec<FormattableCapacity>,
+}
+
+impl FormatCapacities {
+    /// Returns true if the device reports media as being present.
+    pub fn has_media(&self) -> bool {
+        self.current.descriptor_type != CapacityDescriptorType::NoMedia
+    }
+}
+
+/// The length of the READ CAPACITY (16) parameter data, as described in SBC-2 5.1.11.2 table 31.
+pub(crate) const READ_CAPACITY_16_LEN: usize = 32;
+
+/// Described in SBC-2 Table 31
+pub fn read_capacity_16(buf: &[u8]) -> eyre::Result<Response> {
+    ensure!(
+        buf.len() == READ_CAPACITY_16_LEN,
+        "READ CAPACITY (16) data should be {READ_CAPACITY_16_LEN} bytes, was {}",
+        buf.len()
+    );
+    Ok(Response::ReadCapacity16(ReadCapacity16 {
+        last_lba: buf[0..8].try_into()?,
+        block_size: buf[8..12].try_into()?,
+        protection: buf[12],
+        exponents: buf[13],
+    }))
+}
+
+/// READ CAPACITY (16) parameter data, as described in SBC-2 5.1.11.2 table 31.
+///
+/// Unlike [`ReadCapacity`], the last logical block address is 64 bits wide, so this can
+/// describe devices larger than 2TiB.
+#[derive(Copy, Clone, Debug)]
+pub struct ReadCapacity16 {
+    /// `RETURNED LOGICAL BLOCK ADDRESS`
+    last_lba: [u8; 8],
+    /// `LOGICAL BLOCK LENGTH IN BYTES`
+    block_size: [u8; 4],
+    /// `P_TYPE` (bits 3-1) and `PROT_EN` (bit 0)
+    protection: u8,
+    /// `P_I_EXPONENT` (bits 7-4) and `LOGICAL BLOCKS PER PHYSICAL BLOCK EXPONENT` (bits 3-0),
+    /// added in SBC-3
+    exponents: u8,
+}
+
+impl ReadCapacity16 {
+    /// The address of the last logical block on the device.
+    pub fn last_lba(&self) -> u64 {
+        u64::from_be_bytes(self.last_lba)
+    }
+
+    /// The size of each logical block in bytes.
+    pub fn block_size(&self) -> u32 {
+        u32::from_be_bytes(self.block_size)
+    }
+
+    /// The number of logical blocks on the device.
+    ///
+    /// The response contains the address of the last block, so this corrects
+    /// for the zero-based index.
+    pub fn block_count(&self) -> u64 {
+        self.last_lba() + 1
+    }
+
+    /// The total capacity of the device in bytes.
+    pub fn total_bytes(&self) -> u64 {
+        self.block_count() * u64::from(self.block_size())
+    }
+
+    /// Whether the medium is formatted with protection information.
+    pub fn protection_enabled(&self) -> bool {
+        self.protection & 0b1 != 0
+    }
+
+    /// The type of protection the medium is formatted with, only meaningful if
+    /// [`ReadCapacity16::protection_enabled`].
+    pub fn protection_type(&self) -> u8 {
+        (self.protection >> 1) & 0b111
+    }
+
+    /// The number of logical blocks that make up each physical block, e.g 8 for a drive with
+    /// 512 byte logical blocks and 4096 byte physical blocks.
+    pub fn logical_blocks_per_physical_block(&self) -> u32 {
+        1 << (self.exponents & 0x0F)
+    }
+}
+
+/// Walks the mode parameter list returned by `MODE SENSE (6)`, as described in SPC-2 8.3.
+///
+/// The list starts with a 4 byte header (table 240), followed by block descriptors, which
+/// are skipped, and then the mode pages themselves.
+pub fn mode_sense(buf: &[u8]) -> eyre::Result<Response> {
+    ensure!(buf.len() >= 4, "mode parameter header is truncated");
+    // "The MODE DATA LENGTH field specifies the length in bytes of the following data that is
+    // available to be transferred. The mode data length does not include itself."
+    let end = (usize::from(buf[0]) + 1).min(buf.len());
+    let block_descriptor_len = usize::from(buf[3]);
+    let mut pages = Vec::new();
+    let mut offset = 4 + block_descriptor_len;
+    while offset < end {
+        let page = &buf[offset..end];
+        // SPC-3 7.4.5: the SPF bit selects between the page_0 and sub_page formats,
+        // which have a 2 and 4 byte header respectively
+        let (subpage_code, header_len, page_len) = if page[0] & 0b0100_0000 != 0 {
+            ensure!(page.len() >= 4, "mode sub-page header is truncated");
+            (
+                Some(page[1]),
+                4,
+                usize::from(u16::from_be_bytes([page[2], page[3]])),
+            )
+        } else {
+            ensure!(page.len() >= 2, "mode page header is truncated");
+            (None, 2, usize::from(page[1]))
+        };
+        ensure!(
+            page.len() >= header_len + page_len,
+            "mode page {:#04X} is truncated",
+            page[0] & 0x3F
+        );
+        pages.push(ModePage {
+            page_code: page[0] & 0x3F,
+            subpage_code,
+            parameters_saveable: page[0] & 0b1000_0000 != 0,
+            data: page[header_len..header_len + page_len].to_vec(),
+        });
+        offset += header_len + page_len;
+    }
+
+    Ok(Response::ModeSense(ModeParameters {
+        medium_type: buf[1],
+        // SBC-2 6.3.1 table 101: bit 7 of the DEVICE-SPECIFIC PARAMETER is WP
+        write_protected: buf[2] & 0b1000_0000 != 0,
+        pages,
+    }))
+}
+
+/// The mode parameters returned by `MODE SENSE`, see SPC-2 8.3.
+#[derive(Clone, Debug)]
+pub struct ModeParameters {
+    /// `MEDIUM TYPE`, 0 for direct-access block devices
+    pub medium_type: u8,
+    /// True if the medium is write protected, and the drive is read-only
+    pub write_protected: bool,
+    /// Every mode page returned by the device, in order
+    pub pages: Vec<ModePage>,
+}
+
+impl ModeParameters {
+    /// Returns the mode page with a matching page code, if the device returned it.
+    pub fn page(&self, page_code: u8) -> Option<&ModePage> {
+        self.pages
+            .iter()
+            .find(|page| page.page_code == page_code && page.subpage_code.is_none())
+    }
+
+    /// Decodes the Caching mode page, if the device returned it.
+    pub fn caching(&self) -> Option<CachingPage> {
+        let page = self.page(CACHING_MODE_PAGE)?;
+        // Byte 2 of the page, counting the header
+        let flags = *page.data.first()?;
+        Some(CachingPage {
+            write_cache_enabled: flags & 0b0000_0100 != 0,
+            read_cache_disabled: flags & 0b0000_0001 != 0,
+        })
+    }
+}
+
+/// A single mode page, see SPC-2 8.3.4.
+#[derive(Clone, Debug)]
+pub struct ModePage {
+    /// `PAGE CODE`
+    pub page_code: u8,
+    /// `SUBPAGE CODE`, only present for pages in the sub_page format
+    pub subpage_code: Option<u8>,
+    /// `PS`, set if the page can be saved to non-volatile storage
+    pub parameters_saveable: bool,
+    /// The mode parameters following the page header
+    pub data: Vec<u8>,
+}
+
+impl ModePage {
+    /// Appends the page to a `MODE SELECT` parameter list, header included.
+    ///
+    /// `PS` is reserved for `MODE SELECT`, so it's always cleared (SPC-2 8.3.4).
+    pub fn encode(&self, parameter_list: &mut Vec<u8>) {
+        match self.subpage_code {
+            Some(subpage_code) => {
+                parameter_list.push(0b0100_0000 | self.page_code);
+                parameter_list.push(subpage_code);
+                parameter_list.extend_from_slice(&(self.data.len() as u16).to_be_bytes());
+            }
+            None => {
+                parameter_list.push(self.page_code);
+                parameter_list.push(self.data.len() as u8);
+            }
+        }
+        parameter_list.extend_from_slice(&self.data);
+    }
+}
+
+/// The page code of the Caching mode page, SBC-2 6.3.3
+pub const CACHING_MODE_PAGE: u8 = 0x08;
+
+/// The fields of the Caching mode page (SBC-2 6.3.3 table 104) relevant to data integrity.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CachingPage {
+    /// `WCE`, if set the device may report a write as complete before it reaches the medium
+    pub write_cache_enabled: bool,
+    /// `RCD`, if set all reads are serviced from the medium
+    pub read_cache_disabled: bool,
+}
+
+/// Parses the log page returned by `LOG SENSE`, as described in SPC-2 8.2.1.
+///
+/// The page starts with a 4 byte header (table 192), followed by log parameters, each with
+/// their own 4 byte header (table 193).
+pub fn log_sense(buf: &[u8]) -> eyre::Result<Response> {
+    ensure!(buf.len() >= 4, "log page header is truncated");
+    // The Supported Log Pages page (SPC-2 8.2.11) lists page codes rather than log parameters,
+    // so it can't be decoded like the other pages
+    ensure!(
+        buf[0] & 0x3F != 0x00,
+        "the Supported Log Pages page can't be parsed as log parameters"
+    );
+    let page_len = usize::from(u16::from_be_bytes([buf[2], buf[3]]));
+    // The page is cut short if it didn't fit in the allocation length
+    let end = (4 + page_len).min(buf.len());
+    let mut parameters = Vec::new();
+    let mut offset = 4;
+    while offset < end {
+        let parameter = &buf[offset..end];
+        ensure!(parameter.len() >= 4, "log parameter header is truncated");
+        let code = u16::from_be_bytes([parameter[0], parameter[1]]);
+        let len = usize::from(parameter[3]);
+        ensure!(
+            parameter.len() >= 4 + len,
+            "log parameter {code:#06X} is truncated"
+        );
+        parameters.push(LogParameter {
+            code,
+            control: parameter[2],
+            value: parameter[4..4 + len].to_vec(),
+        });
+        offset += 4 + len;
+    }
+
+    Ok(Response::LogSense(LogPage {
+        page_code: buf[0] & 0x3F,
+        // SPC-3 adds the SUBPAGE CODE in byte 1, which is reserved (zero) in SPC-2
+        subpage_code: buf[1],
+        parameters,
+    }))
+}
+
+/// The page code of the Temperature log page, SPC-2 8.2.13
+pub const TEMPERATURE_LOG_PAGE: u8 = 0x0D;
+/// The page code of the Informational Exceptions log page, SPC-3 7.2.5
+pub const INFORMATIONAL_EXCEPTIONS_LOG_PAGE: u8 = 0x2F;
+
+/// A log page returned by `LOG SENSE`, see SPC-2 8.2.1.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LogPage {
+    /// `PAGE CODE`
+    pub page_code: u8,
+    /// `SUBPAGE CODE`, zero for devices that don't support subpages
+    pub subpage_code: u8,
+    /// Every log parameter on the page, in order
+    pub parameters: Vec<LogParameter>,
+}
+
+impl LogPage {
+    /// Returns the log parameter with a matching parameter code, if the page has one.
+    pub fn parameter(&self, code: u16) -> Option<&LogParameter> {
+        self.parameters
+            .iter()
+            .find(|parameter| parameter.code == code)
+    }
+
+    /// Returns the most recent temperature reported by the Temperature (SPC-2 8.2.13 table 218)
+    /// or Informational Exceptions (SPC-3 7.2.5 table 250) page, in degrees Celsius.
+    ///
+    /// `None` is returned for any other page, or if the device doesn't have a valid reading.
+    pub fn temperature(&self) -> Option<u8> {
+        let temperature = match self.page_code {
+            // Parameter 0000h is the current temperature, the reading is in byte 1 of the value
+            TEMPERATURE_LOG_PAGE => *self.parameter(0x0000)?.value.get(1)?,
+            INFORMATIONAL_EXCEPTIONS_LOG_PAGE => self.informational_exceptions()?.temperature?,
+            _ => return None,
+        };
+        // "A TEMPERATURE field set to FFh indicates that the temperature is not available"
+        (temperature != 0xFF).then_some(temperature)
+    }
+
+    /// Decodes the general parameter of the Informational Exceptions page (SPC-3 7.2.5),
+    /// or returns `None` for any other page.
+    pub fn informational_exceptions(&self) -> Option<InformationalExceptions> {
+        if self.page_code != INFORMATIONAL_EXCEPTIONS_LOG_PAGE {
+            return None;
+        }
+        let value = &self.parameter(0x0000)?.value;
+        Some(InformationalExceptions {
+            asc: *value.first()?,
+            ascq: *value.get(1)?,
+            temperature: value
+                .get(2)
+                .copied()
+                .filter(|temperature| *temperature != 0xFF),
+        })
+    }
+}
+
+/// A single log parameter, see SPC-2 8.2.1 table 193.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LogParameter {
+    /// `PARAMETER CODE`, the meaning of which depends on the log page
+    pub code: u16,
+    /// The `DU`, `DS`, `TSD`, `ETC`, `TMC` and `LBIN`/`LP` flags
+    pub control: u8,
+    /// The parameter value following the header
+    pub value: Vec<u8>,
+}
+
+/// The general parameter of the Informational Exceptions log page, see SPC-3 7.2.5 table 250.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct InformationalExceptions {
+    /// `INFORMATIONAL EXCEPTION ADDITIONAL SENSE CODE`, zero if no failure is predicted
+    pub asc: u8,
+    /// `INFORMATIONAL EXCEPTION ADDITIONAL SENSE CODE QUALIFIER`
+    pub ascq: u8,
+    /// `MOST RECENT TEMPERATURE READING` in degrees Celsius, if the device has one
+    pub temperature: Option<u8>,
+}
+
+impl InformationalExceptions {
+    /// Returns true if the device predicts it's going to fail.
+    ///
+    /// Failure predictions are reported with an ASC of 5Dh (FAILURE PREDICTION THRESHOLD
+    /// EXCEEDED), see SPC-3 Annex D.
+    pub fn failure_predicted(&self) -> bool {
+        self.asc == 0x5D
+    }
+}
+
+/// Decodes the sense data returned by `REQUEST SENSE`.
+///
+/// Both fixed format (SPC-2 7.20.2, response codes 70h/71h) and descriptor format
+/// (SPC-3 4.5.2, response codes 72h/73h) sense data are understood.
+pub fn request_sense(buf: &[u8]) -> eyre::Result<Response> {
+    ensure!(!buf.is_empty(), "sense data is empty");
+    let response_code = buf[0] & 0x7F;
+    let sense = match response_code {
+        0x70 | 0x71 => {
+            // Everything up to and including the ADDITIONAL SENSE CODE QUALIFIER
+            ensure!(
+                buf.len() >= 14,
+                "fixed format sense data too short, was {} bytes",
+                buf.len()
+            );
+            SenseData {
+                deferred: response_code == 0x71,
+                sense_key: SenseKey::from(buf[2]),
+                additional_sense_code: buf[12],
+                additional_sense_code_qualifier: buf[13],
+            }
+        }
+        0x72 | 0x73 => {
+            ensure!(
+                buf.len() >= 4,
+                "descriptor format sense data too short, was {} bytes",
+                buf.len()
+            );
+            SenseData {
+                deferred: response_code == 0x73,
+                sense_key: SenseKey::from(buf[1]),
+                additional_sense_code: buf[2],
+                additional_sense_code_qualifier: buf[3],
+            }
+        }
+        _ => bail!("unsupported sense data response code 0x{response_code:X}"),
+    };
+    Ok(Response::RequestSense(sense))
+}
+
+/// The general category of error a command failed with.
+///
+/// Described in SPC-2 7.20.2 table 107
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SenseKey {
+    /// No specific sense key information to report
+    NoSense = 0x0,
+    /// The command completed successfully, with some recovery action performed by the device
+    RecoveredError = 0x1,
+    /// The logical unit is not accessible
+    NotReady = 0x2,
+    /// A non-recoverable error, probably caused by a flaw in the medium
+    MediumError = 0x3,
+    /// A non-recoverable hardware failure
+    HardwareError = 0x4,
+    /// An illegal parameter in the CDB or in the parameter data
+    IllegalRequest = 0x5,
+    /// The medium may have been changed, or the device reset
+    UnitAttention = 0x6,
+    /// A command attempted to read or write a block that is protected
+    DataProtect = 0x7,
+    /// A write-once device or sequential access device encountered blank medium
+    BlankCheck = 0x8,
+    VendorSpecific = 0x9,
+    CopyAborted = 0xA,
+    /// The device aborted the command, the application client may be able to recover
+    /// by trying again.
+    AbortedCommand = 0xB,
+    /// Obsolete
+    Equal = 0xC,
+    /// A buffered device has reached the end-of-partition
+    VolumeOverflow = 0xD,
+    /// The source data did not match the data read from the medium
+    Miscompare = 0xE,
+    Reserved = 0xF,
+}
+
+impl From<u8> for SenseKey {
+    /// Decodes the sense key out of the lower 4 bits of `byte`
+    fn from(byte: u8) -> Self {
+        match byte & 0x0F {
+            0x0 => Self::NoSense,
+            0x1 => Self::RecoveredError,
+            0x2 => Self::NotReady,
+            0x3 => Self::MediumError,
+            0x4 => Self::HardwareError,
+            0x5 => Self::IllegalRequest,
+            0x6 => Self::UnitAttention,
+            0x7 => Self::DataProtect,
+            0x8 => Self::BlankCheck,
+            0x9 => Self::VendorSpecific,
+            0xA => Self::CopyAborted,
+            0xB => Self::AbortedCommand,
+            0xC => Self::Equal,
+            0xD => Self::VolumeOverflow,
+            0xE => Self::Miscompare,
+            _ => Self::Reserved,
+        }
+    }
+}
+
+impl std::fmt::Display for SenseKey {
+    /// Formats the sense key with its name from SPC-2 7.20.2 table 107, e.g `NOT READY`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::NoSense => "NO SENSE",
+            Self::RecoveredError => "RECOVERED ERROR",
+            Self::NotReady => "NOT READY",
+            Self::MediumError => "MEDIUM ERROR",
+            Self::HardwareError => "HARDWARE ERROR",
+            Self::IllegalRequest => "ILLEGAL REQUEST",
+            Self::UnitAttention => "UNIT ATTENTION",
+            Self::DataProtect => "DATA PROTECT",
+            Self::BlankCheck => "BLANK CHECK",
+            Self::VendorSpecific => "VENDOR SPECIFIC",
+            Self::CopyAborted => "COPY ABORTED",
+            Self::AbortedCommand => "ABORTED COMMAND",
+            Self::Equal => "EQUAL",
+            Self::VolumeOverflow => "VOLUME OVERFLOW",
+            Self::Miscompare => "MISCOMPARE",
+            Self::Reserved => "RESERVED",
+        })
+    }
+}
+
+/// The additional sense codes most commonly reported by USB storage devices, as ASC, ASCQ and
+/// description, from SPC-2 Annex D table D.1.
+const ADDITIONAL_SENSE_CODES: &[(u8, u8, &str)] = &[
+    (0x00, 0x00, "NO ADDITIONAL SENSE INFORMATION"),
+    (0x04, 0x00, "LOGICAL UNIT NOT READY, CAUSE NOT REPORTABLE"),
+    (0x04, 0x01, "LOGICAL UNIT IS IN PROCESS OF BECOMING READY"),
+    (
+        0x04,
+        0x02,
+        "LOGICAL UNIT NOT READY, INITIALIZING COMMAND REQUIRED",
+    ),
+    (
+        0x04,
+        0x03,
+        "LOGICAL UNIT NOT READY, MANUAL INTERVENTION REQUIRED",
+    ),
+    (0x04, 0x04, "LOGICAL UNIT NOT READY, FORMAT IN PROGRESS"),
+    (0x0C, 0x00, "WRITE ERROR"),
+    (0x11, 0x00, "UNRECOVERED READ ERROR"),
+    (0x1A, 0x00, "PARAMETER LIST LENGTH ERROR"),
+    (0x1D, 0x00, "MISCOMPARE DURING VERIFY OPERATION"),
+    (0x20, 0x00, "INVALID COMMAND OPERATION CODE"),
+    (0x21, 0x00, "LOGICAL BLOCK ADDRESS OUT OF RANGE"),
+    (0x24, 0x00, "INVALID FIELD IN CDB"),
+    (0x25, 0x00, "LOGICAL UNIT NOT SUPPORTED"),
+    (0x26, 0x00, "INVALID FIELD IN PARAMETER LIST"),
+    (0x27, 0x00, "WRITE PROTECTED"),
+    (
+        0x28,
+        0x00,
+        "NOT READY TO READY CHANGE, MEDIUM MAY HAVE CHANGED",
+    ),
+    (0x29, 0x00, "POWER ON, RESET, OR BUS DEVICE RESET OCCURRED"),
+    (0x29, 0x01, "POWER ON OCCURRED"),
+    (0x2A, 0x01, "MODE PARAMETERS CHANGED"),
+    (0x31, 0x00, "MEDIUM FORMAT CORRUPTED"),
+    (0x3A, 0x00, "MEDIUM NOT PRESENT"),
+    (0x3A, 0x01, "MEDIUM NOT PRESENT - TRAY CLOSED"),
+    (0x3A, 0x02, "MEDIUM NOT PRESENT - TRAY OPEN"),
+    (0x44, 0x00, "INTERNAL TARGET FAILURE"),
+    (0x5D, 0x00, "FAILURE PREDICTION THRESHOLD EXCEEDED"),
+];
+
+/// Looks up the description of an additional sense code and qualifier, e.g
+/// `MEDIUM NOT PRESENT` for 3Ah/00h.
+///
+/// Only common codes are known, `None` is returned for anything else.
+pub fn additional_sense_description(asc: u8, ascq: u8) -> Option<&'static str> {
+    ADDITIONAL_SENSE_CODES
+        .iter()
+        .find(|(code, qualifier, _)| *code == asc && *qualifier == ascq)
+        .map(|(_, _, description)| *description)
+}
+
+/// The fields of the sense data needed to determine why a command failed.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SenseData {
+    /// Set if the sense data describes a deferred error, meaning the error
+    /// was caused by a previous command that already returned GOOD status.
+    pub deferred: bool,
+    pub sense_key: SenseKey,
+    /// `ADDITIONAL SENSE CODE` (ASC) - further describes the error reported by the sense key.
+    ///
+    /// See SPC-2 Annex D for a list of values.
+    pub additional_sense_code: u8,
+    /// `ADDITIONAL SENSE CODE QUALIFIER` (ASCQ) - further describes the
+    /// additional sense code.
+    pub additional_sense_code_qualifier: u8,
+}
+
+impl SenseData {
+    /// Describes the additional sense code and qualifier, if they're common enough to be known,
+    /// see [`additional_sense_description`].
+    pub fn description(&self) -> Option<&'static str> {
+        additional_sense_description(
+            self.additional_sense_code,
+            self.additional_sense_code_qualifier,
+        )
+    }
+}
+
+/// Standard INQUIRY data, as described in SPC-2 7.3.2 table 46.
+#[derive(Clone, Debug)]
 pub struct Inquiry {
     /// Contains both the PERIPHERAL QUALIFIER (bits 7:5) and PERIPHERAL DEVICE TYPE (bits 4:0)
-    /// and PERIPHERAL DEVICE TYPE (bits 4:0) fields.
+    /// fields, see [`Inquiry::peripheral_qualifier`] and [`Inquiry::device_type`].
     ///
     /// The PERIPHERAL QUALIFIER field describes the current state
     /// of the device.
@@ -75,6 +897,437 @@ pub struct Inquiry {
     /// The PERIPHERAL DEVICE TYPE field should also be 0h0 because a USB flash drive
     /// is a direct access device. (see table 48)
     pub peripheral_info: u8,
-    /// Fields that are not needed
-    unparsed: [u8; 35],
+    /// Bytes 1 through 3, containing the `RMB` bit, the `VERSION` field and the
+    /// `RESPONSE DATA FORMAT` field amongst other flags.
+    flags: [u8; 3],
+    /// `T10 VENDOR IDENTIFICATION` - "eight bytes of ASCII data identifying the vendor of
+    /// the product."
+    vendor_identification: [u8; 8],
+    /// `PRODUCT IDENTIFICATION` - "sixteen bytes of ASCII data as defined by the vendor."
+    product_identification: [u8; 16],
+    /// `PRODUCT REVISION LEVEL` - "four bytes of ASCII data as defined by the vendor."
+    product_revision_level: [u8; 4],
+    /// The length of all of the INQUIRY data the device has, from the `ADDITIONAL LENGTH` field
+    full_len: usize,
+    /// The bytes following the standard 36, like the version descriptors
+    /// (SPC-2 7.3.2 table 46). Only present if they were requested.
+    pub additional_data: Vec<u8>,
+}
+
+impl Inquiry {
+    /// The vendor of the device, e.g `Kingston`
+    pub fn vendor(&self) -> Cow<'_, str> {
+        ascii_field(&self.vendor_identification)
+    }
+
+    /// The product name of the device, e.g `DataTraveler 3.0`
+    pub fn product(&self) -> Cow<'_, str> {
+        ascii_field(&self.product_identification)
+    }
+
+    /// The product revision, typically a firmware version
+    pub fn revision(&self) -> Cow<'_, str> {
+        ascii_field(&self.product_revision_level)
+    }
+
+    /// The total length of the INQUIRY data the device has, which may be more than was
+    /// requested.
+    pub fn full_len(&self) -> usize {
+        self.full_len
+    }
+
+    /// Returns true if the device had more INQUIRY data than was requested.
+    pub fn is_truncated(&self) -> bool {
+        STANDARD_INQUIRY_LEN + self.additional_data.len() < self.full_len
+    }
+
+    /// Returns true if the `RMB` (removable medium) bit is set, meaning the medium can be
+    /// removed from the device, like the card in a card reader.
+    ///
+    /// Many USB flash drives set this bit even though their medium is fixed.
+    pub fn is_removable(&self) -> bool {
+        self.flags[0] & 0b1000_0000 != 0
+    }
+
+    /// The `VERSION` field, the version of the SCSI standard the device claims to conform to.
+    ///
+    /// `00h` means no standard is claimed, `02h` is SCSI-2, `03h` is SPC, `04h` is SPC-2 and
+    /// `05h` is SPC-3 (SPC-2 7.3.2 table 49).
+    pub fn scsi_version(&self) -> u8 {
+        self.flags[1]
+    }
+
+    /// The `RESPONSE DATA FORMAT` field, which "shall be set to two" for INQUIRY data in the
+    /// format described by SPC-2. Values below two are obsolete formats.
+    pub fn response_data_format(&self) -> u8 {
+        self.flags[2] & 0x0F
+    }
+
+    /// Decodes the PERIPHERAL QUALIFIER field
+    pub fn peripheral_qualifier(&self) -> PeripheralQualifier {
+        PeripheralQualifier::from(self.peripheral_info)
+    }
+
+    /// Decodes the PERIPHERAL DEVICE TYPE field
+    pub fn device_type(&self) -> DeviceType {
+        DeviceType::from(self.peripheral_info)
+    }
+}
+
+/// The state of the logical unit a device reports in INQUIRY data.
+///
+/// Described in SPC-2 7.3.2 table 47
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PeripheralQualifier {
+    /// A device of the reported type is connected to the logical unit. This does not mean
+    /// it's ready for access.
+    Connected,
+    /// The logical unit supports a device of the reported type, but none is connected
+    NotConnected,
+    Reserved,
+    /// The logical unit can't support a device, the device type is always unknown
+    NotSupported,
+    VendorSpecific,
+}
+
+impl From<u8> for PeripheralQualifier {
+    /// Decodes the peripheral qualifier out of the upper 3 bits of `byte`
+    fn from(byte: u8) -> Self {
+        match byte >> 5 {
+            0b000 => Self::Connected,
+            0b001 => Self::NotConnected,
+            0b010 => Self::Reserved,
+            0b011 => Self::NotSupported,
+            _ => Self::VendorSpecific,
+        }
+    }
+}
+
+/// The type of device connected to a logical unit.
+///
+/// Described in SPC-2 7.3.2 table 48
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DeviceType {
+    /// A block device, e.g a flash drive or hard drive (SBC-2)
+    DirectAccess = 0x00,
+    /// e.g a tape drive
+    SequentialAccess = 0x01,
+    Printer = 0x02,
+    Processor = 0x03,
+    WriteOnce = 0x04,
+    /// A CD or DVD drive
+    CdDvd = 0x05,
+    Scanner = 0x06,
+    OpticalMemory = 0x07,
+    MediumChanger = 0x08,
+    Communications = 0x09,
+    StorageArrayController = 0x0C,
+    EnclosureServices = 0x0D,
+    /// A block device using the reduced block command set (RBC)
+    SimplifiedDirectAccess = 0x0E,
+    OpticalCardReaderWriter = 0x0F,
+    /// No device type, or the device type is unknown
+    Unknown = 0x1F,
+    Reserved,
+}
+
+impl From<u8> for DeviceType {
+    /// Decodes the device type out of the lower 5 bits of `byte`
+    fn from(byte: u8) -> Self {
+        match byte & 0x1F {
+            0x00 => Self::DirectAccess,
+            0x01 => Self::SequentialAccess,
+            0x02 => Self::Printer,
+            0x03 => Self::Processor,
+            0x04 => Self::WriteOnce,
+            0x05 => Self::CdDvd,
+            0x06 => Self::Scanner,
+            0x07 => Self::OpticalMemory,
+            0x08 => Self::MediumChanger,
+            0x09 => Self::Communications,
+            0x0C => Self::StorageArrayController,
+            0x0D => Self::EnclosureServices,
+            0x0E => Self::SimplifiedDirectAccess,
+            0x0F => Self::OpticalCardReaderWriter,
+            0x1F => Self::Unknown,
+            _ => Self::Reserved,
+        }
+    }
+}
+
+/// Converts a space padded ASCII field into a string.
+///
+/// "ASCII data fields shall contain only graphic codes (i.e., code values 20h through 7Eh).
+/// Left-aligned fields shall place any unused bytes at the end of the field (highest offset)
+/// and the unused bytes shall be filled with space characters (20h)." (SPC-2 4.4.1)
+///
+/// Some devices pad with null bytes instead, so those are trimmed too. Cheap bridges don't always
+/// stick to graphic codes either, any other bytes are replaced with U+FFFD.
+fn ascii_field(field: &[u8]) -> Cow<'_, str> {
+    let len = field
+        .iter()
+        .rposition(|byte| !matches!(byte, b' ' | 0))
+        .map_or(0, |last| last + 1);
+    let field = &field[..len];
+    if let Ok(text) = std::str::from_utf8(field)
+        && field.iter().all(|byte| matches!(byte, 0x20..=0x7E))
+    {
+        return Cow::Borrowed(text);
+    }
+    field
+        .iter()
+        .map(|&byte| match byte {
+            0x20..=0x7E => char::from(byte),
+            _ => char::REPLACEMENT_CHARACTER,
+        })
+        .collect::<String>()
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::scsi::response::{
+        self, DeviceType, INFORMATIONAL_EXCEPTIONS_LOG_PAGE, PeripheralQualifier, Response,
+        SenseKey, SupportedOpcode,
+    };
+
+    #[test]
+    fn describe_sense_data() {
+        assert_eq!(SenseKey::NotReady.to_string(), "NOT READY");
+        assert_eq!(
+            response::additional_sense_description(0x3A, 0x00),
+            Some("MEDIUM NOT PRESENT")
+        );
+        assert_eq!(response::additional_sense_description(0x3A, 0x7F), None);
+    }
+
+    #[test]
+    fn residual_bytes_are_discarded() {
+        assert!(matches!(response::no_response(&[]), Ok(Response::None)));
+        assert!(matches!(response::no_response(&[0; 4]), Ok(Response::None)));
+    }
+
+    #[test]
+    fn decode_peripheral_info() {
+        let mut buf = [b' '; 36];
+        // A connected CD-ROM drive
+        buf[0] = 0x05;
+        let Response::Inquiry(inquiry) = response::inquiry(&buf).unwrap() else {
+            panic!("wrong response variant");
+        };
+        assert_eq!(
+            inquiry.peripheral_qualifier(),
+            PeripheralQualifier::Connected
+        );
+        assert_eq!(inquiry.device_type(), DeviceType::CdDvd);
+        // An unsupported logical unit
+        buf[0] = 0x7F;
+        let Response::Inquiry(inquiry) = response::inquiry(&buf).unwrap() else {
+            panic!("wrong response variant");
+        };
+        assert_eq!(
+            inquiry.peripheral_qualifier(),
+            PeripheralQualifier::NotSupported
+        );
+        assert_eq!(inquiry.device_type(), DeviceType::Unknown);
+    }
+
+    #[test]
+    fn non_graphic_identification_is_replaced() {
+        let mut buf = [b' '; 36];
+        buf[8..16].copy_from_slice(b"Gen\xE9ric\0");
+        buf[16..21].copy_from_slice(b"Flash");
+        let Response::Inquiry(inquiry) = response::inquiry(&buf).unwrap() else {
+            panic!("wrong response variant");
+        };
+        assert_eq!(inquiry.vendor(), "Gen\u{FFFD}ric");
+        assert_eq!(inquiry.product(), "Flash");
+        assert_eq!(inquiry.revision(), "");
+    }
+
+    #[test]
+    fn decode_inquiry_flags() {
+        let mut buf = [b' '; 36];
+        // A removable SPC-2 device
+        buf[..4].copy_from_slice(&[0x00, 0x80, 0x04, 0x02]);
+        let Response::Inquiry(inquiry) = response::inquiry(&buf).unwrap() else {
+            panic!("wrong response variant");
+        };
+        assert!(inquiry.is_removable());
+        assert_eq!(inquiry.scsi_version(), 4);
+        assert_eq!(inquiry.response_data_format(), 2);
+        // A fixed device, with the NORMACA and HISUP bits set alongside the format
+        buf[..4].copy_from_slice(&[0x00, 0x00, 0x05, 0x32]);
+        let Response::Inquiry(inquiry) = response::inquiry(&buf).unwrap() else {
+            panic!("wrong response variant");
+        };
+        assert!(!inquiry.is_removable());
+        assert_eq!(inquiry.response_data_format(), 2);
+    }
+
+    #[test]
+    fn decode_additional_inquiry_data() {
+        let mut buf = [b' '; 96];
+        // ADDITIONAL LENGTH, 96 bytes in total
+        buf[4] = 91;
+        let Response::Inquiry(inquiry) = response::inquiry(&buf[..36]).unwrap() else {
+            panic!("wrong response variant");
+        };
+        assert_eq!(inquiry.full_len(), 96);
+        assert!(inquiry.is_truncated());
+        let Response::Inquiry(inquiry) = response::inquiry(&buf).unwrap() else {
+            panic!("wrong response variant");
+        };
+        assert_eq!(inquiry.additional_data.len(), 60);
+        assert!(!inquiry.is_truncated());
+    }
+
+    #[test]
+    fn decode_caching_mode_page() {
+        // A write protected drive with a block descriptor, followed by the Caching mode page
+        // with WCE set, and trailing padding past the MODE DATA LENGTH
+        let mut buf = [0u8; 192];
+        // Mode parameter header
+        buf[..4].copy_from_slice(&[3 + 8 + 2 + 18, 0, 0x80, 8]);
+        // Caching mode page header, followed by the WCE bit
+        buf[12..15].copy_from_slice(&[0x88, 18, 0x04]);
+        let Response::ModeSense(mode) = response::mode_sense(&buf).unwrap() else {
+            panic!("wrong response variant");
+        };
+        assert!(mode.write_protected);
+        assert_eq!(mode.pages.len(), 1);
+        assert!(mode.pages[0].parameters_saveable);
+        assert_eq!(
+            mode.caching(),
+            Some(response::CachingPage {
+                write_cache_enabled: true,
+                read_cache_disabled: false,
+            })
+        );
+    }
+
+    #[test]
+    fn decode_supported_opcodes() {
+        let mut buf = [0u8; 40];
+        buf[3] = 36;
+        // READ (10)
+        buf[4..12].copy_from_slice(&[0x28, 0, 0, 0, 0, 0, 0, 10]);
+        // READ CAPACITY (16), with a command timeouts descriptor
+        buf[12..20].copy_from_slice(&[0x9E, 0, 0, 0x10, 0, 0b11, 0, 16]);
+        buf[32..40].copy_from_slice(&[0x12, 0, 0, 0, 0, 0, 0, 6]);
+        let Response::SupportedOpcodes(opcodes) = response::report_supported_opcodes(&buf).unwrap()
+        else {
+            panic!("wrong response variant");
+        };
+        assert_eq!(
+            opcodes,
+            [
+                SupportedOpcode {
+                    operation_code: 0x28,
+                    service_action: None,
+                    cdb_length: 10,
+                },
+                SupportedOpcode {
+                    operation_code: 0x9E,
+                    service_action: Some(0x10),
+                    cdb_length: 16,
+                },
+                SupportedOpcode {
+                    operation_code: 0x12,
+                    service_action: None,
+                    cdb_length: 6,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn decode_report_luns() {
+        // A two slot card reader, followed by padding past the LUN LIST LENGTH
+        let mut buf = [0u8; 40];
+        buf[3] = 16;
+        buf[17] = 1;
+        buf[32] = 0xFF;
+        let Response::ReportLuns(luns) = response::report_luns(&buf).unwrap() else {
+            panic!("wrong response variant");
+        };
+        assert_eq!(luns, [0, 1]);
+    }
+
+    #[test]
+    fn decode_informational_exceptions() {
+        let buf = [
+            0x2F, 0, 0, 8, // Page header
+            0, 0, 0x03, 4, // General parameter header
+            0x5D, 0x10, 38, 0, // FAILURE PREDICTION THRESHOLD EXCEEDED, 38°C
+        ];
+        let Response::LogSense(page) = response::log_sense(&buf).unwrap() else {
+            panic!("wrong response variant");
+        };
+        assert_eq!(page.page_code, INFORMATIONAL_EXCEPTIONS_LOG_PAGE);
+        assert_eq!(page.parameters.len(), 1);
+        let exceptions = page.informational_exceptions().unwrap();
+        assert!(exceptions.failure_predicted());
+        assert_eq!(exceptions.ascq, 0x10);
+        assert_eq!(page.temperature(), Some(38));
+
+        // Temperature log page without a valid reading
+        let buf = [0x0D, 0, 0, 6, 0, 0, 0x03, 2, 0, 0xFF];
+        let Response::LogSense(page) = response::log_sense(&buf).unwrap() else {
+            panic!("wrong response variant");
+        };
+        assert_eq!(page.temperature(), None);
+        // Parameters running past the end of the page
+        assert!(response::log_sense(&[0x0D, 0, 0, 6, 0, 0, 0x03, 8, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn decode_format_capacities() {
+        // An empty SD card slot, followed by a formattable capacity
+        let buf = [
+            0, 0, 0, 16, 0x00, 0x3B, 0x60, 0x00, 0b11, 0x00, 0x02, 0x00, 0x00, 0x3B, 0x60, 0x00,
+            0x00, 0x00, 0x02, 0x00,
+        ];
+        let Response::FormatCapacities(capacities) =
+            response::read_format_capacities(&buf).unwrap()
+        else {
+            panic!("wrong response variant");
+        };
+        assert!(!capacities.has_media());
+        assert_eq!(capacities.current.block_count, 0x003B_6000);
+        assert_eq!(capacities.current.block_size, 512);
+        assert_eq!(capacities.formattable.len(), 1);
+        assert_eq!(capacities.formattable[0].type_dependent_parameter, 512);
+    }
+
+    #[test]
+    fn decode_read_capacity_16() {
+        // A 4TB drive with 512 byte logical blocks over 4096 byte physical blocks
+        let mut buf = [0u8; response::READ_CAPACITY_16_LEN];
+        buf[0..8].copy_from_slice(&0x1_D1C0_BEAF_u64.to_be_bytes());
+        buf[8..12].copy_from_slice(&512_u32.to_be_bytes());
+        buf[13] = 3;
+        let Response::ReadCapacity16(capacity) = response::read_capacity_16(&buf).unwrap() else {
+            panic!("wrong response variant");
+        };
+        assert_eq!(capacity.block_count(), 0x1_D1C0_BEB0);
+        assert_eq!(capacity.total_bytes(), 0x1_D1C0_BEB0 * 512);
+        assert_eq!(capacity.logical_blocks_per_physical_block(), 8);
+        assert!(!capacity.protection_enabled());
+    }
+
+    #[test]
+    fn decode_fixed_format_sense_data() {
+        // An empty card reader slot, reporting NOT READY - MEDIUM NOT PRESENT
+        let buf = [
+            0x70, 0, 0x02, 0, 0, 0, 0, 0x0A, 0, 0, 0, 0, 0x3A, 0x00, 0, 0, 0, 0,
+        ];
+        let Response::RequestSense(sense) = response::request_sense(&buf).unwrap() else {
+            panic!("wrong response variant");
+        };
+        assert_eq!(sense.sense_key, SenseKey::NotReady);
+        assert_eq!(sense.additional_sense_code, 0x3A);
+        assert_eq!(sense.additional_sense_code_qualifier, 0x00);
+        assert!(!sense.deferred);
+    }
 }
@@ -1,6 +1,8 @@
 //! Code specific to the USB mass storage bulk only protocol.
 
-use color_eyre::eyre::ensure;
+use eyre::bail;
+
+use crate::scsi::command::CommandBlock;
 
 /// Signature that identifies a packet as a CBW.
 ///
@@ -15,12 +17,14 @@ const CSW_SIGNATURE: u32 = 0x53425355;
 
 /// A command block wrapper is *always* 31 bytes in size*
 const CBW_SIZE: usize = 31;
+/// A command status wrapper is *always* 13 bytes in size
+pub const CSW_SIZE: usize = 13;
 
 /// Described under section 5.1 of the USB mass storage spec under the subheading
 /// `bmCBWFlags`.
 ///
 ///
-#[derive(PartialEq, Copy, Clone)]
+#[derive(PartialEq, Copy, Clone, Debug)]
 pub enum CBWDirection {
     /// Data-Out: from host to the device
     DataOut = 0b0000_0000,
@@ -33,6 +37,20 @@ pub enum CBWDirection {
     NonDirectional = 255,
 }
 
+impl CBWDirection {
+    /// Returns the value of `bmCBWFlags` for this direction.
+    ///
+    /// Only bit 7 (*Direction*) is defined, the rest are obsolete or reserved and "the host
+    /// shall set these bits to zero", so [`CBWDirection::NonDirectional`] is sent as a
+    /// Data-Out.
+    pub fn flags(self) -> u8 {
+        match self {
+            Self::DataIn => Self::DataIn as u8,
+            Self::DataOut | Self::NonDirectional => Self::DataOut as u8,
+        }
+    }
+}
+
 /// The CBW wraps an SCSi command.
 ///
 /// The CBW is always exactly 31 bytes in size, and in little endian format.
@@ -61,14 +79,14 @@ pub struct CommandBlockWrapper {
     /// bit in *bmCBWFlags*."
     pub data_transfer_length: [u8; 4],
     /// `bmCBWFlags` - A one byte field specifying the direction
-    /// of data transfer.
-    pub direction: CBWDirection,
+    /// of data transfer, see [`CBWDirection::flags`].
+    pub flags: u8,
     /// `bCBWLUN` - "The device Logical Unit Number (LUN) to which the command block
     /// is being sent. For devices that support multiple LUNs, the host shall
     /// place into this field, the LUN to which this command block is addressed.
     /// Otherwise, the host shall set this field to zero."
     ///
-    /// Multiple LUNs are not currently supported, so this field can just be zero.
+    /// Only the lower 4 bits are used.
     pub lun: u8,
     /// `bCBWCBLength` - "The valid length of the *CBWCB* in bytes. This defines the
     /// valid length of the command block. The only legal values are 1 through 16
@@ -85,6 +103,37 @@ pub struct CommandBlockWrapper {
 }
 
 impl CommandBlockWrapper {
+    /// Wraps `command_block`, addressed to logical unit `lun`.
+    pub fn new(tag: u32, lun: u8, command_block: &CommandBlock<'_>) -> Self {
+        Self {
+            signature: CBW_SIGNATURE.to_le_bytes(),
+            tag: tag.to_le_bytes(),
+            data_transfer_length: command_block.data_transfer_len.to_le_bytes(),
+            flags: command_block.direction.flags(),
+            lun,
+            command_block_length: command_block.len() as u8,
+            command: command_block.get(),
+        }
+    }
+
+    /// Serializes the CBW into its 31 byte wire format (section 5.1), field by field.
+    ///
+    /// Unlike [`CommandBlockWrapper::as_slice`], this doesn't depend on the memory layout of
+    /// the struct.
+    pub fn to_bytes(&self) -> [u8; CBW_SIZE] {
+        let mut bytes = [0; CBW_SIZE];
+        bytes[0..4].copy_from_slice(&self.signature);
+        bytes[4..8].copy_from_slice(&self.tag);
+        bytes[8..12].copy_from_slice(&self.data_transfer_length);
+        bytes[12] = self.flags;
+        // `bCBWLUN` only uses the lower 4 bits
+        bytes[13] = self.lun & 0x0F;
+        // `bCBWCBLength` only uses the lower 5 bits
+        bytes[14] = self.command_block_length & 0x1F;
+        bytes[15..31].copy_from_slice(&self.command);
+        bytes
+    }
+
     /// Returns a slice containing the entirety of `self` that is exactly [`CBW_SIZE`] bytes in length
     pub fn as_slice(&'_ self) -> &[u8] {
         const {
@@ -104,6 +153,78 @@ impl CommandBlockWrapper {
     }
 }
 
+// Fields of a packed struct can't be borrowed, so they're copied out before formatting
+impl std::fmt::Debug for CommandBlockWrapper {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self {
+            signature,
+            tag,
+            data_transfer_length,
+            flags,
+            lun,
+            command_block_length,
+            command,
+        } = *self;
+        let command_len = usize::from(command_block_length).min(command.len());
+        f.debug_struct("CommandBlockWrapper")
+            .field("signature", &u32::from_le_bytes(signature))
+            .field("tag", &u32::from_le_bytes(tag))
+            .field(
+                "data_transfer_length",
+                &u32::from_le_bytes(data_transfer_length),
+            )
+            .field("flags", &flags)
+            .field("lun", &lun)
+            .field("command", &&command[..command_len])
+            .finish()
+    }
+}
+
+/// An error reported by, or detected in, a [`CommandStatusWrapper`].
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CswError {
+    /// The device reported the command as having failed.
+    ///
+    /// For SCSI devices, this corresponds to a CHECK CONDITION, and the reason can be
+    /// retrieved with `REQUEST SENSE`.
+    Failed,
+    /// The device reported a phase error, meaning the host and device disagree on the state
+    /// of the transport. Recovering requires a reset recovery (section 5.3.4).
+    PhaseError,
+    /// The packet received was not a CSW.
+    SignatureMismatch { found: u32 },
+    /// The CSW does not correspond to the CBW that was sent.
+    TagMismatch { expected: u32, found: u32 },
+    /// The residue is larger than the amount of data the CBW asked for, so the CSW isn't
+    /// meaningful (section 6.3).
+    InvalidResidue { residue: u32, transfer_len: u32 },
+}
+
+impl std::fmt::Display for CswError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Failed => write!(f, "command status reported as Failed"),
+            Self::PhaseError => write!(f, "command status reported as Phase Error"),
+            Self::SignatureMismatch { found } => write!(
+                f,
+                "invalid magic number for command status wrapper, should be 0x{CSW_SIGNATURE:X}, is 0x{found:X}"
+            ),
+            Self::TagMismatch { expected, found } => {
+                write!(f, "invalid command tag, should be {expected}, is {found}")
+            }
+            Self::InvalidResidue {
+                residue,
+                transfer_len,
+            } => write!(
+                f,
+                "invalid data residue of {residue} bytes, for a transfer of {transfer_len} bytes"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CswError {}
+
 #[repr(u8)]
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum CommandStatus {
@@ -114,7 +235,7 @@ pub enum CommandStatus {
 
 /// A packet containing the status/return value of a command block executed by the USB device.
 #[repr(C, packed)]
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone)]
 pub struct CommandStatusWrapper {
     /// `dCSWSignature` - "Signature that helps identify this data packet as a CSW.
     /// The signature field shall contain the value 53425355h (little endian), indicating CSW."
@@ -144,34 +265,62 @@ pub struct CommandStatusWrapper {
 }
 
 impl CommandStatusWrapper {
-    /// Cast the provided slice into a command status wrapper.
+    /// Parses a command status wrapper out of a buffer, which must be exactly [`CSW_SIZE`] bytes.
     ///
-    /// This function validates that the `signature` is correct.
-    pub fn from_slice(buf: &[u8]) -> color_eyre::Result<&CommandStatusWrapper> {
-        ensure!(
-            buf.len() == std::mem::size_of::<CommandStatusWrapper>(),
-            "provided buffer *must* be same size as struct (CSW_SIZE), was instead {}",
-            buf.len()
-        );
-        // Casting to an enum if it might be an invalid option is undefined behavior.
-        // Valid options (as defined by the spec) are values between 0 and 2
-        let last_byte = buf.last().unwrap();
-        ensure!(
-            (0..=2).contains(last_byte),
-            "the command status field is invalid, should be in 0..=2, was {last_byte}"
-        );
+    /// See [`CommandStatusWrapper::from_bytes`].
+    pub fn from_slice(buf: &[u8]) -> eyre::Result<CommandStatusWrapper> {
+        let Ok(bytes) = <&[u8; CSW_SIZE]>::try_from(buf) else {
+            bail!(
+                "a CSW must be exactly {CSW_SIZE} bytes, was instead {}",
+                buf.len()
+            );
+        };
+        Self::from_bytes(bytes)
+    }
 
-        // SAFETY: The buffer *must* be the same size as the struct
-        let csw: &'_ CommandStatusWrapper =
-            unsafe { &*(buf.as_ptr() as *const CommandStatusWrapper) };
-        let signature = csw.signature;
-        ensure!(
-            signature == CSW_SIGNATURE,
-            "invalid magic number for command status wrapper, should be 0x53425355, is 0x{:X}",
-            signature
-        );
+    /// Parses a command status wrapper from its 13 byte wire format (section 5.2), field by
+    /// field.
+    ///
+    /// This function validates that the `signature` is correct, and the status is one defined
+    /// by the spec.
+    pub fn from_bytes(buf: &[u8; CSW_SIZE]) -> eyre::Result<CommandStatusWrapper> {
+        let signature = u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]);
+        if signature != CSW_SIGNATURE {
+            bail!(CswError::SignatureMismatch { found: signature });
+        }
+        let status = match buf[12] {
+            0 => CommandStatus::Passed,
+            1 => CommandStatus::Failed,
+            2 => CommandStatus::PhaseError,
+            // "All other values are reserved"
+            status => {
+                bail!("the command status field is invalid, should be in 0..=2, was {status}")
+            }
+        };
+
+        Ok(CommandStatusWrapper {
+            signature,
+            tag: u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]),
+            data_residue: u32::from_le_bytes([buf[8], buf[9], buf[10], buf[11]]),
+            status,
+        })
+    }
+}
 
-        Ok(csw)
+impl std::fmt::Debug for CommandStatusWrapper {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self {
+            signature,
+            tag,
+            data_residue,
+            status,
+        } = *self;
+        f.debug_struct("CommandStatusWrapper")
+            .field("signature", &signature)
+            .field("tag", &tag)
+            .field("data_residue", &data_residue)
+            .field("status", &status)
+            .finish()
     }
 }
 
@@ -202,7 +351,40 @@ impl Default for TagGenerator {
 
 #[cfg(test)]
 mod tests {
-    use crate::usb::cbw::CommandStatusWrapper;
+    use crate::scsi::command;
+    use crate::usb::cbw::{CommandBlockWrapper, CommandStatus, CommandStatusWrapper, CswError};
+
+    #[test]
+    fn command_block_length_matches_command() {
+        let cbw = CommandBlockWrapper::new(1, 0, &command::test_unit_ready());
+        assert_eq!({ cbw.command_block_length }, 6);
+        let cbw = CommandBlockWrapper::new(1, 0, &command::read(0, 1, 512));
+        assert_eq!({ cbw.command_block_length }, 10);
+        // Bytes past the command block are zeroed
+        assert_eq!(cbw.as_slice()[15], 0x28);
+        assert!(cbw.as_slice()[25..].iter().all(|byte| *byte == 0));
+    }
+
+    #[test]
+    fn serialize_read_cbw() {
+        // READ (10) of the first 512 byte block, as sent by Linux's usb-storage driver
+        let expected = [
+            0x55, 0x53, 0x42, 0x43, 0x01, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x80, 0x00,
+            0x0A, 0x28, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00,
+        ];
+        let cbw = CommandBlockWrapper::new(1, 0, &command::read(0, 1, 512));
+        assert_eq!(cbw.to_bytes(), expected);
+        assert_eq!(cbw.as_slice(), expected);
+    }
+
+    #[test]
+    fn debug_only_shows_command_block() {
+        let cbw = CommandBlockWrapper::new(7, 0, &command::test_unit_ready());
+        let debug = format!("{cbw:?}");
+        assert!(debug.contains("tag: 7"));
+        assert!(debug.contains("command: [0, 0, 0, 0, 0, 0]"));
+    }
 
     #[test]
     fn catch_invalid_enum_repr() {
@@ -213,4 +395,27 @@ mod tests {
         let e = r.expect_err("should catch invalid command status");
         assert!(e.root_cause().to_string().contains("command status"));
     }
+
+    #[test]
+    fn decode_csw() {
+        // A failed MODE SENSE, with tag 0x7B and 188 bytes of residue
+        let input_packet = [0x55, 0x53, 0x42, 0x53, 0x7B, 0, 0, 0, 0xBC, 0, 0, 0, 0x01];
+        let csw = CommandStatusWrapper::from_bytes(&input_packet).unwrap();
+        assert_eq!({ csw.tag }, 0x7B);
+        assert_eq!({ csw.data_residue }, 188);
+        assert_eq!({ csw.status }, CommandStatus::Failed);
+        assert!(CommandStatusWrapper::from_slice(&input_packet[..12]).is_err());
+    }
+
+    #[test]
+    fn catch_invalid_signature() {
+        // A CBW signature where a CSW signature is expected
+        let input_packet = [0x55, 0x53, 0x42, 0x43, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let e = CommandStatusWrapper::from_slice(&input_packet)
+            .expect_err("should catch invalid signature");
+        assert_eq!(
+            e.downcast_ref::<CswError>(),
+            Some(&CswError::SignatureMismatch { found: 0x43425355 })
+        );
+    }
 }
@@ -1,20 +1,30 @@
 //! Interactions with USB mass storage devices
 
 pub mod cbw;
+#[cfg(test)]
+pub mod mock;
+use std::collections::HashSet;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
 use std::time::Duration;
 
-use color_eyre::Result;
-use color_eyre::eyre::{ContextCompat, bail, ensure};
+use eyre::{ContextCompat, Result, bail, ensure, eyre};
+use futures_core::Stream;
 use nusb::descriptors::TransferType;
+use nusb::hotplug::HotplugWatch;
 use nusb::io::{EndpointRead, EndpointWrite};
-use nusb::transfer::{Bulk, ControlIn, ControlOut, ControlType, Direction, In, Out, Recipient};
-use nusb::{Device, DeviceInfo, Interface, list_devices};
+use nusb::transfer::{
+    Bulk, ControlIn, ControlOut, ControlType, Direction, In, Out, Recipient, TransferError,
+};
+use nusb::{Device, DeviceId, DeviceInfo, Endpoint, Interface, list_devices, watch_devices};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tracing::{debug, error, info, warn};
+use tracing::{debug, error, info, trace, warn};
 
 use crate::scsi;
+use crate::scsi::command::CommandBlock;
 use crate::usb::cbw::{
-    CBWDirection, CommandBlockWrapper, CommandStatus, CommandStatusWrapper, TagGenerator,
+    CBWDirection, CSW_SIZE, CommandBlockWrapper, CommandStatus, CommandStatusWrapper, CswError,
+    TagGenerator,
 };
 /// https://www.usb.org/defined-class-codes
 const MASS_STORAGE_USB_CLASS: u8 = 0x08;
@@ -22,41 +32,513 @@ const MASS_STORAGE_USB_CLASS: u8 = 0x08;
 const MASS_STORAGE_SCSI_SUBCLASS: u8 = 0x06;
 /// Transport protocol
 const MASS_STORAGE_BULK_ONLY_TRANSPORT: u8 = 0x50;
+/// `GET MAX LUN`, addressed to the interface numbered `interface_number` (section 3.2).
+///
 /// <https://en.wikipedia.org/wiki/Logical_unit_number>
-const MAX_LUN_REQUEST: ControlIn = ControlIn {
-    control_type: ControlType::Class,
-    recipient: Recipient::Interface,
-    request: 0xfe,
-    value: 0,
-    index: 0,
-    length: 1,
-};
+fn max_lun_request(interface_number: u8) -> ControlIn {
+    ControlIn {
+        control_type: ControlType::Class,
+        recipient: Recipient::Interface,
+        request: 0xfe,
+        value: 0,
+        index: u16::from(interface_number),
+        length: 1,
+    }
+}
 /// Returns a list of every USB storage device currently connected to the host machine
+///
+/// Devices are sorted by bus, then by address, so that the same devices are listed in the same
+/// order across runs, rather than whatever order the OS reports them in.
 pub async fn enumerate_usb_storage_devices() -> Result<impl Iterator<Item = DeviceInfo>> {
     let all_usb_devices = list_devices().await?;
 
     // Each USB device typically exposes one or more *interfaces* as a
     // way to interact with specific functionality of the device.
-    let usb_storage_devices = all_usb_devices.filter(|dev| {
-        //debug!("scanning usb device: {:#?}", dev);
-        dev.class() == MASS_STORAGE_USB_CLASS
-            || dev.interfaces().any(|interface| {
-                interface.class() == MASS_STORAGE_USB_CLASS
-                    && interface.subclass() == MASS_STORAGE_SCSI_SUBCLASS
-                    && interface.protocol() == MASS_STORAGE_BULK_ONLY_TRANSPORT
-            })
+    let mut usb_storage_devices: Vec<_> = all_usb_devices.filter(is_usb_storage_device).collect();
+    usb_storage_devices
+        .sort_by(|a, b| (a.bus_id(), a.device_address()).cmp(&(b.bus_id(), b.device_address())));
+    Ok(usb_storage_devices.into_iter())
+}
+
+/// Returns the number of the mass storage interface of a device.
+///
+/// Composite devices (e.g a phone exposing storage alongside other functions) don't necessarily
+/// put it first. Interfaces speaking a supported protocol are preferred, otherwise any mass
+/// storage interface is returned, to be rejected with a clear error when opened.
+fn find_storage_interface(dev: &DeviceInfo) -> Option<u8> {
+    let storage_interfaces: Vec<_> = dev
+        .interfaces()
+        .filter(|interface| interface.class() == MASS_STORAGE_USB_CLASS)
+        .collect();
+    let supported = storage_interfaces.iter().find(|interface| {
+        interface.subclass() == MASS_STORAGE_SCSI_SUBCLASS
+            && interface.protocol() == MASS_STORAGE_BULK_ONLY_TRANSPORT
     });
-    Ok(usb_storage_devices)
+    supported
+        .or(storage_interfaces.first())
+        .map(|interface| interface.interface_number())
+}
+
+/// Returns true if the device is a USB storage device we can talk to
+fn is_usb_storage_device(dev: &DeviceInfo) -> bool {
+    trace!("scanning usb device: {dev:#?}");
+    dev.class() == MASS_STORAGE_USB_CLASS
+        || dev.interfaces().any(|interface| {
+            interface.class() == MASS_STORAGE_USB_CLASS
+                && interface.subclass() == MASS_STORAGE_SCSI_SUBCLASS
+                && interface.protocol() == MASS_STORAGE_BULK_ONLY_TRANSPORT
+        })
+}
+
+/// A USB storage device being connected to or disconnected from the host machine
+#[derive(Debug)]
+pub enum HotplugEvent {
+    /// A USB storage device was connected
+    Arrived(DeviceInfo),
+    /// A previously connected USB storage device was disconnected
+    Left(DeviceId),
+}
+
+/// Returns a stream of USB storage devices being connected and disconnected.
+///
+/// Only changes are reported, use [`enumerate_usb_storage_devices`] for the devices that are
+/// already connected.
+pub async fn watch_storage_devices() -> Result<impl Stream<Item = HotplugEvent>> {
+    // Start watching before enumerating, so a device can't slip in between the two
+    let watch = watch_devices()?;
+    // A disconnected device can no longer be inspected, so the storage devices are tracked
+    // to know which disconnections are relevant
+    let known = enumerate_usb_storage_devices()
+        .await?
+        .map(|dev| dev.id())
+        .collect();
+    Ok(StorageDeviceWatch { watch, known })
+}
+
+/// [`HotplugWatch`] filtered down to USB storage devices, see [`watch_storage_devices`]
+struct StorageDeviceWatch {
+    watch: HotplugWatch,
+    known: HashSet<DeviceId>,
+}
+
+impl Stream for StorageDeviceWatch {
+    type Item = HotplugEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            let event = match ready!(Pin::new(&mut self.watch).poll_next(cx)) {
+                Some(event) => event,
+                None => return Poll::Ready(None),
+            };
+            match event {
+                nusb::hotplug::HotplugEvent::Connected(dev) if is_usb_storage_device(&dev) => {
+                    debug!("USB storage device connected: {dev:?}");
+                    self.known.insert(dev.id());
+                    return Poll::Ready(Some(HotplugEvent::Arrived(dev)));
+                }
+                nusb::hotplug::HotplugEvent::Disconnected(id) if self.known.remove(&id) => {
+                    debug!("USB storage device disconnected: {id:?}");
+                    return Poll::Ready(Some(HotplugEvent::Left(id)));
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Returns the first connected USB storage device with the given Vendor ID and Product ID
+pub async fn find_storage_device(vid: u16, pid: u16) -> Result<Option<DeviceInfo>> {
+    Ok(enumerate_usb_storage_devices()
+        .await?
+        .find(|dev| dev.vendor_id() == vid && dev.product_id() == pid))
+}
+
+/// Returns the connected USB storage device with the given serial number.
+///
+/// Useful for telling apart several identical drives, which share a Vendor ID and Product ID.
+/// Devices that don't report a serial number never match.
+pub async fn find_by_serial(serial: &str) -> Result<Option<DeviceInfo>> {
+    Ok(enumerate_usb_storage_devices()
+        .await?
+        .find(|dev| dev.serial_number() == Some(serial)))
+}
+
+/// Identifying information about a USB storage device, detached from `nusb` types.
+///
+/// Produced by [`summarize`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StorageDeviceSummary {
+    /// USB Vendor ID (VID)
+    pub vendor_id: u16,
+    /// USB Product ID (PID)
+    pub product_id: u16,
+    /// The serial number string reported by the device, if any
+    pub serial_number: Option<String>,
+    /// The manufacturer string reported by the device, if any
+    pub manufacturer: Option<String>,
+    /// The product string reported by the device, if any
+    pub product: Option<String>,
+    /// An OS specific identifier for the bus the device is connected to
+    pub bus_id: String,
+    /// The address of the device on its bus
+    pub device_address: u8,
+}
+
+/// Extracts a [`StorageDeviceSummary`] from a device returned by enumeration.
+pub fn summarize(info: &DeviceInfo) -> StorageDeviceSummary {
+    StorageDeviceSummary {
+        vendor_id: info.vendor_id(),
+        product_id: info.product_id(),
+        serial_number: info.serial_number().map(str::to_owned),
+        manufacturer: info.manufacturer_string().map(str::to_owned),
+        product: info.product_string().map(str::to_owned),
+        bus_id: info.bus_id().to_owned(),
+        device_address: info.device_address(),
+    }
+}
+
+/// Walks the endpoint descriptors of `interface`, returning the addresses
+/// of its (Bulk-In, Bulk-Out) endpoint pair.
+///
+/// USB Mass Storage Class - Bulk Only Transport 4.3 requires the interface to expose
+/// one of each. Addresses vary from device to device (e.g `0x81`/`0x02`), so they
+/// can't be assumed.
+fn find_bulk_endpoints(interface: &Interface) -> Result<(u8, u8)> {
+    let mut bulk_in_address: Option<u8> = None;
+    let mut bulk_out_address: Option<u8> = None;
+
+    let descriptor = interface
+        .descriptor()
+        .wrap_err("claimed interface has no descriptor for its current alt setting")?;
+    for endpoint in descriptor.endpoints() {
+        if endpoint.transfer_type() != TransferType::Bulk {
+            continue;
+        }
+        // The direction is encoded in bit 7 of the endpoint address
+        if endpoint.direction() == Direction::In {
+            if bulk_in_address.is_some() {
+                warn!("multiple Bulk-In endpoints, picking arbitrarily");
+            }
+            bulk_in_address = Some(endpoint.address());
+        } else {
+            if bulk_out_address.is_some() {
+                warn!("multiple Bulk-Out endpoints, picking arbitrarily");
+            }
+            bulk_out_address = Some(endpoint.address());
+        }
+    }
+
+    let bulk_in_address = bulk_in_address.wrap_err("USB device has no exposed Bulk-In endpoint")?;
+    let bulk_out_address =
+        bulk_out_address.wrap_err("USB device has no exposed Bulk-Out endpoint")?;
+    debug!(
+        "found Bulk-In endpoint 0x{bulk_in_address:02X}, Bulk-Out endpoint 0x{bulk_out_address:02X}"
+    );
+    Ok((bulk_in_address, bulk_out_address))
+}
+
+/// Ensures an interface speaks the SCSI transparent command set over Bulk-Only Transport, the
+/// only combination supported.
+///
+/// Other transports (e.g UFI floppy drives, or CBI) don't respond to CBWs, so talking to them
+/// would hang rather than fail. The names are from the USB Mass Storage Class Specification
+/// Overview, sections 2 and 3.
+fn check_interface_protocol(class: u8, subclass: u8, protocol: u8) -> Result<()> {
+    ensure!(
+        class == MASS_STORAGE_USB_CLASS,
+        "interface is not a mass storage interface, class is 0x{class:02X}"
+    );
+    let subclass_name = match subclass {
+        0x01 => "RBC",
+        0x02 => "MMC-5 (ATAPI)",
+        0x04 => "UFI",
+        0x05 => "SFF-8070i",
+        MASS_STORAGE_SCSI_SUBCLASS => "SCSI transparent command set",
+        0x07 => "LSD FS",
+        0x08 => "IEEE 1667",
+        _ => "unknown",
+    };
+    ensure!(
+        subclass == MASS_STORAGE_SCSI_SUBCLASS,
+        "unsupported mass storage subclass 0x{subclass:02X} ({subclass_name}), only the SCSI transparent command set (0x06) is supported"
+    );
+    let protocol_name = match protocol {
+        0x00 | 0x01 => "CBI",
+        MASS_STORAGE_BULK_ONLY_TRANSPORT => "Bulk-Only Transport",
+        0x62 => "UAS",
+        _ => "unknown",
+    };
+    ensure!(
+        protocol == MASS_STORAGE_BULK_ONLY_TRANSPORT,
+        "unsupported mass storage transport 0x{protocol:02X} ({protocol_name}), only Bulk-Only Transport (0x50) is supported"
+    );
+    Ok(())
+}
+
+/// Returns true if `error` was caused by the endpoint stalling.
+fn is_stall(error: &std::io::Error) -> bool {
+    error
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<TransferError>())
+        == Some(&TransferError::Stall)
+}
+
+/// A failure of the underlying USB transfers, as opposed to a failed command.
+///
+/// Raised into the [`eyre::Report`] returned by the transport, so it can be recovered
+/// with `downcast_ref` to decide how to recover.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TransportError {
+    /// An endpoint stalled, and it could not be recovered by clearing the halt.
+    Stall,
+    /// Less data was received than the command requires.
+    ShortTransfer { got: usize, expected: usize },
+    /// The device did not respond in time.
+    Timeout,
+    /// The device was disconnected, any further commands will fail.
+    Disconnected,
+}
+
+impl std::fmt::Display for TransportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Stall => write!(f, "endpoint stalled"),
+            Self::ShortTransfer { got, expected } => {
+                write!(f, "short transfer, received {got} of {expected} bytes")
+            }
+            Self::Timeout => write!(f, "transfer timed out"),
+            Self::Disconnected => write!(f, "device disconnected"),
+        }
+    }
+}
+
+impl std::error::Error for TransportError {}
+
+impl TransportError {
+    /// Classifies an error returned by a control transfer.
+    fn from_transfer_error(error: TransferError) -> Option<Self> {
+        match error {
+            TransferError::Stall => Some(Self::Stall),
+            // nusb reports a timed out transfer as cancelled
+            TransferError::Cancelled => Some(Self::Timeout),
+            TransferError::Disconnected => Some(Self::Disconnected),
+            _ => None,
+        }
+    }
+}
+
+/// Converts an error returned by a bulk endpoint into a report, raising a [`TransportError`]
+/// if it matches one.
+fn transport_error(error: std::io::Error) -> eyre::Report {
+    if error.kind() == std::io::ErrorKind::TimedOut {
+        return eyre!(TransportError::Timeout);
+    }
+    match error
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<TransferError>())
+        .and_then(|inner| TransportError::from_transfer_error(*inner))
+    {
+        Some(transport_error) => eyre!(transport_error),
+        None => error.into(),
+    }
+}
+
+/// Converts an error returned by a control transfer into a report, raising a
+/// [`TransportError`] if it matches one.
+fn control_transfer_error(error: TransferError) -> eyre::Report {
+    match TransportError::from_transfer_error(error) {
+        Some(transport_error) => eyre!(transport_error),
+        None => error.into(),
+    }
+}
+
+/// How the Data-In stage of a command ended.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct DataIn {
+    /// The number of bytes actually read into the buffer
+    received: usize,
+    /// True if the device ended the stage by stalling the Bulk-In pipe
+    stalled: bool,
+}
+
+/// Reads the Data-In stage of a command into `buf`.
+///
+/// A single read only returns the contents of one transfer, so this keeps reading until
+/// `buf` is full, or the device ends the stage early with a short packet
+/// (section 6.7.2, case Hi > Di) or a stall. The stall itself is left for the caller to clear.
+async fn read_data_in(reader: &mut EndpointRead<Bulk>, buf: &mut [u8]) -> std::io::Result<DataIn> {
+    if buf.is_empty() {
+        // Without a Data-In phase, the next thing the device sends is the CSW, which mustn't be
+        // consumed here
+        return Ok(DataIn {
+            received: 0,
+            stalled: false,
+        });
+    }
+    let mut reader = reader.until_short_packet();
+    let data_in = fill_data_in(&mut reader, buf).await?;
+    if !data_in.stalled && data_in.received < buf.len() {
+        // The end of a short packet was reached
+        reader
+            .consume_end()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::UnexpectedEof, e))?;
+    }
+    Ok(data_in)
+}
+
+/// Reads into `buf` until it's full, `reader` reaches its end, or the pipe stalls.
+///
+/// Everything read before a stall is counted, so that stale bytes left in `buf` by a previous
+/// command are never mistaken for data.
+async fn fill_data_in(
+    reader: &mut (impl tokio::io::AsyncRead + Unpin),
+    buf: &mut [u8],
+) -> std::io::Result<DataIn> {
+    let mut received = 0;
+    while received < buf.len() {
+        match reader.read(&mut buf[received..]).await {
+            Ok(0) => break,
+            Ok(len) => received += len,
+            Err(e) if is_stall(&e) => {
+                return Ok(DataIn {
+                    received,
+                    stalled: true,
+                });
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(DataIn {
+        received,
+        stalled: false,
+    })
+}
+
+/// Returns the direction of a command's data stage, or `None` if it doesn't have one.
+///
+/// "If this field is zero, the device and the host shall transfer no data between the CBW and
+/// the associated CSW" (section 5.1, `dCBWDataTransferLength`), so the host goes straight from
+/// sending the CBW to reading the CSW.
+fn data_stage(command_block: &CommandBlock<'_>) -> Option<CBWDirection> {
+    match command_block.direction {
+        _ if command_block.data_transfer_len == 0 => None,
+        CBWDirection::NonDirectional => None,
+        direction => Some(direction),
+    }
+}
+
+/// Returns the length of the valid prefix of a Data-In buffer.
+///
+/// `dCSWDataResidue` is the difference between the `expected` length (`dCBWDataTransferLength`)
+/// and the amount of relevant data the device sent (section 5.2). A device may also end the data
+/// stage early with a short packet, so the result is further capped at the number of bytes
+/// actually `received`.
+///
+/// A residue larger than the expected length makes the CSW meaningless (section 6.3), and is
+/// rejected with [`CswError::InvalidResidue`].
+fn valid_data_in_len(expected: u32, received: usize, residue: u32) -> Result<usize, CswError> {
+    let relevant = expected
+        .checked_sub(residue)
+        .ok_or(CswError::InvalidResidue {
+            residue,
+            transfer_len: expected,
+        })?;
+    Ok((relevant as usize).min(received))
+}
+
+/// The Bulk-In reader is only missing if a stall recovery was interrupted part way through
+const READER_UNAVAILABLE: &str =
+    "Bulk-In endpoint unavailable, a previous stall recovery was interrupted";
+
+/// The Bulk-Out writer is only missing if an abort recovery was interrupted part way through
+const WRITER_UNAVAILABLE: &str =
+    "Bulk-Out endpoint unavailable, a previous abort recovery was interrupted";
+
+/// Wraps the Bulk-Out endpoint in a buffered writer.
+fn new_writer(endpoint: Endpoint<Bulk, Out>, config: &UsbOpenConfig) -> EndpointWrite<Bulk> {
+    endpoint
+        .writer(config.write_buffer)
+        .with_num_transfers(config.num_transfers)
+}
+
+/// Wraps the Bulk-In endpoint in a buffered reader.
+fn new_reader(endpoint: Endpoint<Bulk, In>, config: &UsbOpenConfig) -> EndpointRead<Bulk> {
+    endpoint
+        .reader(config.read_buffer)
+        .with_num_transfers(config.num_transfers)
+}
+
+/// A transport able to deliver SCSI command blocks to a device's logical units.
+///
+/// [`USBDrive`] is the real implementation, this exists so that SCSI logic can be exercised
+/// without hardware, see `MockTransport` in the crate's tests.
+pub trait UsbTransport {
+    /// Submits `command_block` to logical unit `lun`, returning the Data-In response, if any.
+    ///
+    /// A failed command is reported as a [`CswError::Failed`].
+    fn submit_cbw(
+        &mut self,
+        lun: u8,
+        command_block: CommandBlock<'_>,
+    ) -> impl Future<Output = Result<Vec<u8>>>;
+
+    /// Puts the transport back into a usable state after a [`UsbTransport::submit_cbw`] future
+    /// was dropped before it completed.
+    fn abort_recovery(&mut self) -> impl Future<Output = Result<()>>;
+
+    /// The highest logical unit number supported by the device.
+    fn max_lun(&self) -> u8;
+}
+
+/// Tuning options for the bulk endpoints, passed to [`USBDrive::with_config`].
+///
+/// Buffer sizes are rounded up to a multiple of the endpoint's max packet size. Larger buffers and
+/// more transfers in flight improve throughput on fast links, at the cost of memory.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct UsbOpenConfig {
+    /// The size of each Bulk-In transfer in *bytes*
+    pub read_buffer: usize,
+    /// The size of each Bulk-Out transfer in *bytes*
+    pub write_buffer: usize,
+    /// The number of transfers kept in flight on each endpoint
+    pub num_transfers: usize,
+    /// On Linux, the kernel's `usb-storage` driver binds to the device as soon as it's plugged
+    /// in, which prevents claiming the interface. If set, the kernel driver is detached when the
+    /// device is opened, and reattached when the [`USBDrive`] is dropped. Has no effect on other
+    /// platforms.
+    ///
+    /// Detaching requires write access to the device node (`/dev/bus/usb/BBB/DDD`), so either
+    /// run as root, or add a udev rule granting access, e.g
+    /// `SUBSYSTEM=="usb", ATTRS{idVendor}=="0781", MODE="0666"`.
+    pub detach_kernel_driver: bool,
+}
+
+impl Default for UsbOpenConfig {
+    fn default() -> Self {
+        Self {
+            read_buffer: 128,
+            write_buffer: 128,
+            num_transfers: 8,
+            detach_kernel_driver: true,
+        }
+    }
 }
 
 pub struct USBDrive {
-    bulk_write: EndpointWrite<Bulk>,
+    /// Only `None` while the writer is being rebuilt after an aborted command,
+    /// see [`USBDrive::abort_recovery`].
+    bulk_write: Option<EndpointWrite<Bulk>>,
     bulk_in_address: u8,
-    bulk_read: EndpointRead<Bulk>,
+    /// Only `None` while the reader is being rebuilt after a stall,
+    /// see [`USBDrive::recover_bulk_in_stall`].
+    bulk_read: Option<EndpointRead<Bulk>>,
     bulk_out_address: u8,
     interface: Interface,
+    /// The highest logical unit number the device supports
+    max_lun: u8,
     tag_generator: TagGenerator,
     response_buf: Vec<u8>,
+    config: UsbOpenConfig,
 }
 
 impl USBDrive {
@@ -65,85 +547,142 @@ impl USBDrive {
     /// This initialization sequence follows the order
     /// described here: <https://www.downtowndougbrown.com/2018/12/usb-mass-storage-with-embedded-devices-tips-and-quirks/>.
     /// They are not formally documented anywhere, so the author reverse engineered from various OS implementatations.
-    pub async fn new(device_info: DeviceInfo) -> Result<Self> {
+    pub async fn new(device_info: &DeviceInfo) -> Result<Self> {
+        Self::with_config(device_info, UsbOpenConfig::default()).await
+    }
+
+    /// Opens the connected USB storage device identified by `id`, as reported by
+    /// [`DeviceInfo::id`] or [`HotplugEvent::Left`].
+    ///
+    /// This is meant for reattaching to a device after something like a bus reset, without
+    /// tracking its [`DeviceInfo`]. The id belongs to a single connection of the device, so a
+    /// device that was unplugged and plugged back in will typically have a new one.
+    pub async fn open_by_id(id: DeviceId) -> Result<Self> {
+        let device_info = enumerate_usb_storage_devices()
+            .await?
+            .find(|dev| dev.id() == id)
+            .wrap_err_with(|| format!("no USB storage device with id {id:?} is connected"))?;
+        Self::new(&device_info).await
+    }
+
+    /// Like [`USBDrive::new`], but with the bulk endpoints tuned by `config`.
+    pub async fn with_config(device_info: &DeviceInfo, config: UsbOpenConfig) -> Result<Self> {
         // 1. Claim the USB device to read and write to it
         info!("opening device...");
         let device: Device = device_info.open().await?;
-        info!("device opened, claiming interface...");
-        let interface: nusb::Interface = device.detach_and_claim_interface(0).await?;
+        // Not every platform reports interfaces during enumeration, in which case
+        // the storage interface is assumed to be the first one
+        let interface_number = find_storage_interface(device_info).unwrap_or(0);
+        info!("device opened, claiming interface {interface_number}...");
+        let interface: nusb::Interface = if config.detach_kernel_driver {
+            device.detach_and_claim_interface(interface_number).await?
+        } else {
+            device.claim_interface(interface_number).await?
+        };
         info!("interface claimed, opening endpoints");
+        let descriptor = interface
+            .descriptor()
+            .wrap_err("claimed interface has no descriptor")?;
+        check_interface_protocol(
+            descriptor.class(),
+            descriptor.subclass(),
+            descriptor.protocol(),
+        )?;
         debug!("performing endpoint lookup");
 
-        let mut bulk_in_address: Option<u8> = None;
-        let mut bulk_out_address: Option<u8> = None;
-
-        for endpoint in interface
-            .descriptor()
-            .expect("device must have descriptor")
-            .endpoints()
-        {
-            if endpoint.transfer_type() == TransferType::Bulk {
-                if endpoint.direction() == Direction::In {
-                    if bulk_in_address.is_some() {
-                        warn!("multiple Bulk-In endpoints, picking arbitrarily");
-                    }
-                    bulk_in_address = Some(endpoint.address());
-                } else if endpoint.direction() == Direction::Out {
-                    if bulk_out_address.is_some() {
-                        warn!("multiple Bulk-Out endpoints, picking arbitrarily");
-                    }
-                    bulk_out_address = Some(endpoint.address());
-                }
-            }
-        }
+        let (bulk_in_address, bulk_out_address) = find_bulk_endpoints(&interface)?;
         // 2. Request the maximum LUN
         debug!("requesting max LUN");
-        let max_lun = interface
-            .control_in(MAX_LUN_REQUEST, Duration::from_millis(500))
-            .await?
-            .len();
+        let max_lun = match interface
+            .control_in(
+                max_lun_request(interface_number),
+                Duration::from_millis(500),
+            )
+            .await
+        {
+            Ok(response) => *response
+                .first()
+                .wrap_err("device returned no data for GET MAX LUN")?,
+            // "Devices that do not support multiple LUNs may STALL this command." (section 3.2)
+            Err(TransferError::Stall) => 0,
+            Err(e) => return Err(e.into()),
+        };
+        // "The device shall return one byte of data that contains the maximum LUN supported
+        // by the device", with a maximum of 15
         ensure!(
-            max_lun == 1,
-            "devices with more than one LUN are not supported"
+            max_lun <= 15,
+            "device reported an invalid max LUN of {max_lun}"
         );
+        debug!("device supports {} LUN(s)", max_lun + 1);
 
         debug!("initializing endpoints");
-        let bulk_in_address =
-            bulk_in_address.wrap_err("USB device has no exposed Bulk-In endpoint")?;
-        let bulk_out_address =
-            bulk_out_address.wrap_err("USB device has no exposed Bulk-Out endpoint")?;
         // Initialize bulk in/out endpoints
-        let writer = interface
-            .endpoint::<Bulk, Out>(bulk_out_address)?
-            .writer(128)
-            .with_num_transfers(8);
-        let reader = interface
-            .endpoint::<Bulk, In>(bulk_in_address)?
-            .reader(128)
-            .with_num_transfers(8);
+        let writer = new_writer(interface.endpoint::<Bulk, Out>(bulk_out_address)?, &config);
+        let reader = new_reader(interface.endpoint::<Bulk, In>(bulk_in_address)?, &config);
         // At this point we can talk to the device, but no usb mass storage specific
         // setup has been performed
         let device = Self {
-            bulk_write: writer,
-            bulk_read: reader,
+            bulk_write: Some(writer),
+            bulk_read: Some(reader),
             bulk_in_address,
             bulk_out_address,
             interface,
+            max_lun,
             tag_generator: TagGenerator::new(),
             response_buf: vec![0; 2048],
+            config,
         };
 
         Ok(device)
     }
 
-    /// Submit a command block wrapper, returning a slice of any bytes recieved.
+    /// The highest logical unit number supported by the device.
     ///
-    /// No validation is performed, the input is serialized, sent, and response bytes recieved.
-    #[tracing::instrument(skip_all)]
+    /// Most devices only have a single logical unit, LUN 0. Devices like card readers
+    /// may expose one per slot.
+    pub fn max_lun(&self) -> u8 {
+        self.max_lun
+    }
+
+    /// The claimed mass storage interface, for control requests on the default endpoint.
+    ///
+    /// Requests made through this bypass the drive, so they shouldn't be issued while a command
+    /// is in flight.
+    pub fn interface(&self) -> &Interface {
+        &self.interface
+    }
+
+    /// Returns a handle to logical unit `lun` of the device.
+    ///
+    /// Handles share the bulk endpoints of the drive, every command sent through one is
+    /// addressed to its logical unit. `lun` is validated against [`USBDrive::max_lun`] when
+    /// the first command is submitted.
+    pub fn lun(&mut self, lun: u8) -> ScsiLun<'_> {
+        ScsiLun { drive: self, lun }
+    }
+
+    /// Submit a command block wrapper addressed to logical unit `lun`, returning any Data-In
+    /// bytes received.
+    ///
+    /// `lun` is checked against [`USBDrive::max_lun`], and the command block against the
+    /// direction and length it declares, before anything is sent. The CSW is then validated: its
+    /// signature, tag and residue must match the CBW, and a `Failed` status is returned as
+    /// [`CswError::Failed`]. Only the valid prefix of the Data-In stage, as given by the residue,
+    /// is returned.
+    ///
+    /// On a phase error, a [`USBDrive::reset_recovery`] is performed and the command is retried
+    /// once.
+    #[tracing::instrument(skip(self, command_block))]
     pub async fn submit_cbw(
         &mut self,
-        command_block: scsi::command::CommandBlock,
+        lun: u8,
+        command_block: scsi::command::CommandBlock<'_>,
     ) -> Result<Vec<u8>> {
+        ensure!(
+            lun <= self.max_lun,
+            "LUN {lun} is out of range, the device only supports up to LUN {}",
+            self.max_lun
+        );
         // The code here is written in an unusual way and contains an unnecessary heap allocation.
         // It's a limitation of the borrow checker, and should be resolved with the introduction
         // of Polonius.
@@ -152,110 +691,265 @@ impl USBDrive {
             // Because of async drop shenanigans, a whole bunch of log messages created by
             // unwinding appear in the logs before the error message is reported.
             // This makes it difficult to know when the error actually occured
-            let result = self.submit_cbw_manual(&command_block).await;
+            let result = self.submit_cbw_manual(lun, &command_block).await;
             if let Err(e) = result {
                 error!("submitting CBW failed");
                 bail!(e);
             }
             let (response_bytes, csw) = result.unwrap();
-            if csw.status == CommandStatus::Passed {
-                return Ok(response_bytes.to_vec());
-            } else if csw.status == CommandStatus::Failed {
-                bail!("command status reported as Failed");
+            match csw.status {
+                CommandStatus::Passed => return Ok(response_bytes.to_vec()),
+                CommandStatus::Failed => bail!(CswError::Failed),
+                CommandStatus::PhaseError => (),
             }
-            // Every other state should have returned before this point
-            ensure!(csw.status == CommandStatus::PhaseError);
         }
 
         warn!("phase error detected, beginning reset recovery");
         self.reset_recovery().await?;
         info!("reset succeeded, retrying command");
-        let (response_bytes, status) = self.submit_cbw_manual(&command_block).await?;
-        ensure!(
-            status.status == CommandStatus::Passed,
-            "command failed after reset recovery performed"
-        );
-        Ok(response_bytes.to_vec())
+        let (response_bytes, status) = self.submit_cbw_manual(lun, &command_block).await?;
+        match status.status {
+            CommandStatus::Passed => Ok(response_bytes.to_vec()),
+            CommandStatus::Failed => {
+                Err(eyre!(CswError::Failed).wrap_err("command failed after reset recovery"))
+            }
+            CommandStatus::PhaseError => {
+                Err(eyre!(CswError::PhaseError).wrap_err("command failed after reset recovery"))
+            }
+        }
     }
 
     async fn submit_cbw_manual(
         &'_ mut self,
-        command_block: &scsi::command::CommandBlock,
-    ) -> Result<(&'_ [u8], &'_ CommandStatusWrapper)> {
+        lun: u8,
+        command_block: &scsi::command::CommandBlock<'_>,
+    ) -> Result<(&'_ [u8], CommandStatusWrapper)> {
+        // `bCBWCBLength`: "The only legal values are 1 through 16"
+        ensure!(
+            (1..=16).contains(&command_block.len()),
+            "command block must be 1 to 16 bytes long, was {}",
+            command_block.len()
+        );
         if command_block.direction == CBWDirection::NonDirectional {
             ensure!(
                 command_block.data_transfer_len == 0,
                 "CBW declared as non-directional has data to transfer"
             )
         }
-        let command = CommandBlockWrapper {
-            signature: cbw::CBW_SIGNATURE.to_le_bytes(),
-            command: command_block.get(),
-            data_transfer_length: command_block.data_transfer_len.to_le_bytes(),
-            direction: command_block.direction,
-            lun: 0,
-            command_block_length: command_block.size_of() as u8,
-            tag: self.tag_generator.tag().to_le_bytes(),
-        };
+        if command_block.direction == CBWDirection::DataOut {
+            let data_out_len = command_block.data_out.map_or(0, <[u8]>::len);
+            ensure!(
+                data_out_len == command_block.data_transfer_len as usize,
+                "CBW declared as Data-Out has {data_out_len} bytes of data, but a data transfer length of {}",
+                command_block.data_transfer_len
+            );
+        }
+        let command = CommandBlockWrapper::new(self.tag_generator.tag(), lun, command_block);
+        trace!(
+            tag = u32::from_le_bytes(command.tag),
+            lun,
+            opcode = ?scsi::OpCode::from_u8(command_block.get()[0]),
+            direction = ?command_block.direction,
+            data_transfer_length = command_block.data_transfer_len,
+            cdb = format_args!("{:02X?}", &command_block.get()[..command_block.len()]),
+            "submitting CBW"
+        );
         // As described by USB Mass Storage Class - Bulk Only Transport,
         // "The host shall send the CBW before the associated data-out, and
         // the device shall send data-in after the associated cbw and before the associated
         // csw
         // Submit the command
         {
-            self.bulk_write.write_all(command.as_slice()).await?;
-            self.bulk_write.flush_end_async().await?;
+            let writer = self.bulk_write.as_mut().wrap_err(WRITER_UNAVAILABLE)?;
+            writer
+                .write_all(&command.to_bytes())
+                .await
+                .map_err(transport_error)?;
+            writer.flush_end_async().await.map_err(transport_error)?;
             debug!("command submitted, pending response");
         }
+        let data_stage = data_stage(command_block);
+        // The Data-Out phase directly follows the CBW
+        if let (Some(CBWDirection::DataOut), Some(data)) = (data_stage, command_block.data_out) {
+            let writer = self.bulk_write.as_mut().wrap_err(WRITER_UNAVAILABLE)?;
+            writer.write_all(data).await.map_err(transport_error)?;
+            // Unlike the CBW, the data is not terminated with a short packet, the device
+            // already knows how much data to expect from the data transfer length.
+            match writer.flush().await {
+                Ok(()) => debug!("sent {} bytes of data", data.len()),
+                // The device may STALL the Bulk-Out pipe if it won't accept the rest of the
+                // data (section 6.7.3), the status is still sent afterwards.
+                Err(e) if is_stall(&e) => {
+                    warn!("Bulk-Out endpoint stalled during the data stage");
+                    self.clear_halt(self.bulk_out_address).await?;
+                }
+                Err(e) => return Err(transport_error(e)),
+            }
+        }
         let mut required_capacity = 0;
         // Ensure the response buffer can fit the response size
-        if command.direction == CBWDirection::DataIn {
+        if data_stage == Some(CBWDirection::DataIn) {
             required_capacity = u32::from_le_bytes(command.data_transfer_length) as usize;
-            if self.response_buf.len() < required_capacity + 13 {
-                self.response_buf.resize(required_capacity + 13, 0);
+            if self.response_buf.len() < required_capacity {
+                self.response_buf.resize(required_capacity, 0);
             }
         }
-        let (response_bytes, status_bytes) = self.response_buf.split_at_mut(required_capacity);
-        // Sometimes there's leftover space in the response buffer that we don't care about
-        let status_bytes = &mut status_bytes[..13];
-        let mut response_size = 0;
-        response_size += self.bulk_read.read(response_bytes).await?;
-        debug!("read {response_size} bytes into the response buffer so far",);
-        //    if response_size == (command_block.data_transfer_len as usize) {
-        //        break;
-        //    }
-        //}
-        //debug!("response buffer filled with {} bytes", response_bytes.len());
+        let response_bytes = &mut self.response_buf[..required_capacity];
+        let reader = self.bulk_read.as_mut().wrap_err(READER_UNAVAILABLE)?;
+        let data_in = read_data_in(reader, response_bytes)
+            .await
+            .map_err(transport_error)?;
+        debug!("read {} bytes into the response buffer", data_in.received);
+        // "The device shall [..] STALL the Bulk-In pipe" when it has less data
+        // to send than the host expects (section 6.7.2), the status is still sent afterwards.
+        if data_in.stalled {
+            warn!("Bulk-In endpoint stalled during the data stage");
+            self.recover_bulk_in_stall().await?;
+        }
+        let received = data_in.received;
         // The status is sent after the response
-        self.bulk_read.read_exact(status_bytes).await?;
+        let mut status_bytes = [0; CSW_SIZE];
+        let reader = self.bulk_read.as_mut().wrap_err(READER_UNAVAILABLE)?;
+        if let Err(e) = reader.read_exact(&mut status_bytes).await {
+            if !is_stall(&e) {
+                return Err(transport_error(e));
+            }
+            // Figure 2 of section 5.3.3, if the endpoint stalls while reading the CSW,
+            // clear the stall and try again
+            warn!("Bulk-In endpoint stalled while reading the CSW, retrying");
+            self.recover_bulk_in_stall().await?;
+            self.bulk_read
+                .as_mut()
+                .wrap_err(READER_UNAVAILABLE)?
+                .read_exact(&mut status_bytes)
+                .await
+                .map_err(transport_error)?;
+        }
         debug!("status buffer filled with {} bytes", status_bytes.len());
 
-        debug!("response recieved");
+        debug!("response received");
         // Validate the status
-        let status = CommandStatusWrapper::from_slice(status_bytes)?;
-        ensure!(
-            status.tag == u32::from_le_bytes(command.tag),
-            "invalid command tag"
+        let status = CommandStatusWrapper::from_bytes(&status_bytes)?;
+        trace!(
+            tag = { status.tag },
+            status = ?{ status.status },
+            residue = { status.data_residue },
+            data_in_length = received,
+            "received CSW"
         );
-        Ok((response_bytes, status))
+        let (expected, found) = (u32::from_le_bytes(command.tag), status.tag);
+        if expected != found {
+            bail!(CswError::TagMismatch { expected, found });
+        }
+        // Checked for every command, a Data-Out or non-data command has nothing received
+        let transfer_len = u32::from_le_bytes(command.data_transfer_length);
+        let response_len = valid_data_in_len(transfer_len, received, status.data_residue)?;
+        if response_len < required_capacity {
+            debug!(
+                "device returned {response_len} of {required_capacity} requested bytes (residue {})",
+                { status.data_residue }
+            );
+        }
+        Ok((&self.response_buf[..response_len], status))
+    }
+
+    /// Clears a halt (STALL) condition on the endpoint at `endpoint_addr` by sending
+    /// a `CLEAR_FEATURE(ENDPOINT_HALT)` request.
+    ///
+    /// See the USB 2.0 spec <https://eater.net/downloads/usb_20.pdf>, section 9.4.1.
+    #[tracing::instrument(skip(self))]
+    pub async fn clear_halt(&self, endpoint_addr: u8) -> eyre::Result<()> {
+        let clear_feature_halt: ControlOut = ControlOut {
+            control_type: ControlType::Standard,
+            recipient: Recipient::Endpoint,
+            // As defined in table 9-4, USB spec rev 2.0
+            request: 1,
+            // Table 9-6 defines 0 the value associated with an ENDPOINT_HALT
+            value: 0,
+            index: u16::from(endpoint_addr),
+            data: &[],
+        };
+        debug!("submitting `CLEAR_HALT` to endpoint 0x{endpoint_addr:02X}");
+        self.interface
+            .control_out(clear_feature_halt, Duration::from_millis(500))
+            .await
+            .map_err(control_transfer_error)?;
+        Ok(())
+    }
+
+    /// Clears a stall on the Bulk-In endpoint so that it can be read from again.
+    ///
+    /// Once a transfer fails, [`EndpointRead`] will keep returning the same error,
+    /// so the reader is rebuilt from the underlying endpoint with all pending transfers
+    /// discarded.
+    async fn recover_bulk_in_stall(&mut self) -> eyre::Result<()> {
+        self.discard_bulk_in().await?;
+        self.clear_halt(self.bulk_in_address).await
+    }
+
+    /// Rebuilds the Bulk-In reader, cancelling every pending transfer and discarding any
+    /// buffered data.
+    async fn discard_bulk_in(&mut self) -> eyre::Result<()> {
+        let mut endpoint = self
+            .bulk_read
+            .take()
+            .wrap_err(READER_UNAVAILABLE)?
+            .into_inner();
+        endpoint.cancel_all();
+        while endpoint.pending() > 0 {
+            // Every pending transfer either failed, or was cancelled
+            let _ = endpoint.next_complete().await;
+        }
+        self.bulk_read = Some(new_reader(endpoint, &self.config));
+        Ok(())
+    }
+
+    /// Rebuilds the Bulk-Out writer, cancelling every pending transfer and discarding any data
+    /// that was buffered, but not yet submitted.
+    async fn discard_bulk_out(&mut self) -> eyre::Result<()> {
+        let mut endpoint = self
+            .bulk_write
+            .take()
+            .wrap_err(WRITER_UNAVAILABLE)?
+            .into_inner();
+        endpoint.cancel_all();
+        while endpoint.pending() > 0 {
+            let _ = endpoint.next_complete().await;
+        }
+        self.bulk_write = Some(new_writer(endpoint, &self.config));
+        Ok(())
+    }
+
+    /// Recovers from a command that was abandoned part way through, e.g because it was
+    /// cancelled.
+    ///
+    /// Transfers still in flight are discarded, then the device is put back into a known state
+    /// with a reset recovery, as the host and the device no longer agree on which stage of the
+    /// command they're in.
+    pub async fn abort_recovery(&mut self) -> eyre::Result<()> {
+        self.discard_bulk_in().await?;
+        self.discard_bulk_out().await?;
+        self.reset_recovery().await
     }
 
     /// Submit a Bulk-Only Mass Storage Reset
     #[tracing::instrument(skip_all)]
-    pub async fn mass_storage_reset(&self) -> color_eyre::Result<()> {
+    pub async fn mass_storage_reset(&self) -> eyre::Result<()> {
         // USB Mass Storage Class - Bulk Only Transport: 3.1
-        let request: ControlIn = ControlIn {
+        // "bmRequestType: Class, Interface, host to device", with no data stage
+        let request: ControlOut = ControlOut {
             control_type: ControlType::Class,
             recipient: Recipient::Interface,
             request: 255,
             value: 0,
-            index: 0,
-            length: 0,
+            index: u16::from(self.interface.interface_number()),
+            data: &[],
         };
         debug!("requesting mass storage reset");
         self.interface
-            .control_in(request, Duration::from_millis(500))
-            .await?;
+            .control_out(request, Duration::from_millis(500))
+            .await
+            .map_err(control_transfer_error)?;
 
         Ok(())
     }
@@ -264,33 +958,193 @@ impl USBDrive {
     ///
     /// USB Mass Storage class - Bulk Only Transport 5.3.4
     #[tracing::instrument(skip_all)]
-    pub async fn reset_recovery(&mut self) -> color_eyre::Result<()> {
+    pub async fn reset_recovery(&mut self) -> eyre::Result<()> {
         // (a) a Bulk-Only Mass Storage Reset
         self.mass_storage_reset().await?;
         // (b) a *Clear Feature HALT* to the Bulk-In endpoint
-        // See the USB 2.0 spec <https://eater.net/downloads/usb_20.pdf>, section 9.4.1.
-        let mut clear_feature_halt: ControlOut = ControlOut {
-            control_type: ControlType::Standard,
-            recipient: Recipient::Endpoint,
-            // As defined in table 9-4, USB spec rev 2.0
-            request: 1,
-            // Table 9-6 defines 0 the value associated with an ENDPOINT_HALT
-            value: 0,
-            index: u16::from(self.bulk_in_address),
-            data: &[],
-        };
-        debug!("submitting `CLEAR_HALT` to the bulk-in interface");
-        self.interface
-            .control_out(clear_feature_halt, Duration::from_millis(500))
-            .await?;
+        self.clear_halt(self.bulk_in_address).await?;
         // (c) a *Clear Feature HALT* to the Bulk-Out endpoint
-        // Here we re-use the same struct w/ different address
-        debug!("submitting `CLEAR_HALT` to the bulk-out interface");
-        clear_feature_halt.index = u16::from(self.bulk_out_address);
-        self.interface
-            .control_out(clear_feature_halt, Duration::from_millis(500))
-            .await?;
+        self.clear_halt(self.bulk_out_address).await?;
         debug!("reset completed without errors");
         Ok(())
     }
 }
+
+impl UsbTransport for USBDrive {
+    async fn submit_cbw(&mut self, lun: u8, command_block: CommandBlock<'_>) -> Result<Vec<u8>> {
+        USBDrive::submit_cbw(self, lun, command_block).await
+    }
+
+    async fn abort_recovery(&mut self) -> Result<()> {
+        USBDrive::abort_recovery(self).await
+    }
+
+    fn max_lun(&self) -> u8 {
+        USBDrive::max_lun(self)
+    }
+}
+
+/// Lets a [`scsi::SCSIDevice`] borrow its transport, see [`ScsiLun`].
+impl<T: UsbTransport> UsbTransport for &mut T {
+    fn submit_cbw(
+        &mut self,
+        lun: u8,
+        command_block: CommandBlock<'_>,
+    ) -> impl Future<Output = Result<Vec<u8>>> {
+        (**self).submit_cbw(lun, command_block)
+    }
+
+    fn abort_recovery(&mut self) -> impl Future<Output = Result<()>> {
+        (**self).abort_recovery()
+    }
+
+    fn max_lun(&self) -> u8 {
+        (**self).max_lun()
+    }
+}
+
+/// A single logical unit of a multi-LUN device (e.g a card reader with a slot per LUN),
+/// borrowed from [`USBDrive::lun`].
+pub struct ScsiLun<'a, T: UsbTransport = USBDrive> {
+    drive: &'a mut T,
+    lun: u8,
+}
+
+impl<'a, T: UsbTransport> ScsiLun<'a, T> {
+    /// The logical unit number commands are addressed to.
+    pub fn number(&self) -> u8 {
+        self.lun
+    }
+
+    /// Submits `command_block` to this logical unit, returning the Data-In response, if any.
+    pub async fn submit_cbw(&mut self, command_block: CommandBlock<'_>) -> Result<Vec<u8>> {
+        self.drive.submit_cbw(self.lun, command_block).await
+    }
+
+    /// Initializes the logical unit as a SCSI device, see [`scsi::SCSIDevice::new`].
+    ///
+    /// The drive stays borrowed for as long as the device is in use, so to switch between
+    /// logical units, drop the device and open the other one.
+    pub async fn open(self) -> Result<scsi::SCSIDevice<&'a mut T>> {
+        scsi::SCSIDevice::new(self.drive, self.lun).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use nusb::transfer::TransferError;
+    use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
+
+    use crate::scsi::command::{self, CommandBlock};
+    use crate::scsi::response;
+    use crate::usb::cbw::{CBWDirection, CswError};
+    use crate::usb::mock::MockTransport;
+    use crate::usb::{
+        DataIn, ScsiLun, check_interface_protocol, data_stage, fill_data_in, valid_data_in_len,
+    };
+
+    #[test]
+    fn zero_length_commands_have_no_data_stage() {
+        assert_eq!(data_stage(&command::test_unit_ready()), None);
+        // A Data-In command asking for nothing
+        let empty_read = CommandBlock::raw(
+            &[0x28, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            CBWDirection::DataIn,
+            0,
+            response::raw,
+        )
+        .unwrap();
+        assert_eq!(data_stage(&empty_read), None);
+        assert_eq!(
+            data_stage(&command::read(0, 1, 512)),
+            Some(CBWDirection::DataIn)
+        );
+        assert_eq!(
+            data_stage(&command::write(0, 1, &[0; 512])),
+            Some(CBWDirection::DataOut)
+        );
+    }
+
+    #[test]
+    fn only_scsi_over_bulk_only_is_supported() {
+        assert!(check_interface_protocol(0x08, 0x06, 0x50).is_ok());
+        // A UFI floppy drive
+        let error = check_interface_protocol(0x08, 0x04, 0x00).unwrap_err();
+        assert!(error.to_string().contains("UFI"));
+        // A USB Attached SCSI drive
+        assert!(check_interface_protocol(0x08, 0x06, 0x62).is_err());
+        assert!(check_interface_protocol(0xFF, 0x06, 0x50).is_err());
+    }
+
+    #[tokio::test]
+    async fn lun_handles_address_their_lun() {
+        let mut transport = MockTransport::new()
+            .with_max_lun(1)
+            .expect(&[0, 0, 0, 0, 0, 0], &[])
+            .expect(&[0, 0, 0, 0, 0, 0], &[]);
+        for lun in [1, 0] {
+            let mut handle = ScsiLun {
+                drive: &mut transport,
+                lun,
+            };
+            handle.submit_cbw(command::test_unit_ready()).await.unwrap();
+        }
+        assert_eq!(transport.submitted[0].0, 1);
+        assert_eq!(transport.submitted[1].0, 0);
+        assert!(transport.is_finished());
+    }
+
+    /// A Bulk-In pipe the device has stalled.
+    struct StallingReader;
+
+    impl AsyncRead for StallingReader {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            _: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Err(std::io::Error::other(TransferError::Stall)))
+        }
+    }
+
+    #[tokio::test]
+    async fn stalled_data_in_only_counts_what_was_read() {
+        // The device sends 13 bytes of a 36 byte response, then stalls
+        let sent: &[u8] = &[0xAB; 13];
+        let mut buf = [0xEE; 36];
+        let data_in = fill_data_in(&mut sent.chain(StallingReader), &mut buf)
+            .await
+            .unwrap();
+        assert_eq!(
+            data_in,
+            DataIn {
+                received: 13,
+                stalled: true
+            }
+        );
+        // Even with a residue of 0, the stale bytes after the stall aren't returned
+        assert_eq!(valid_data_in_len(36, data_in.received, 0), Ok(13));
+    }
+
+    #[test]
+    fn short_data_in_is_truncated() {
+        // A device asked for 96 bytes of INQUIRY data, but only having the standard 36
+        assert_eq!(valid_data_in_len(96, 36, 60), Ok(36));
+        // A device that pads the response, but reports the residue correctly
+        assert_eq!(valid_data_in_len(96, 96, 60), Ok(36));
+        // A device that ends the data stage early, but reports no residue
+        assert_eq!(valid_data_in_len(96, 36, 0), Ok(36));
+        assert_eq!(valid_data_in_len(512, 512, 0), Ok(512));
+        // A residue larger than the expected length makes the whole CSW invalid
+        assert_eq!(
+            valid_data_in_len(36, 36, 100),
+            Err(CswError::InvalidResidue {
+                residue: 100,
+                transfer_len: 36
+            })
+        );
+    }
+}